@@ -14,20 +14,52 @@
  * limitations under the License.
  */
 
-use std::io::{prelude::*, BufReader, BufWriter};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::convert::TryFrom;
+use std::io::{prelude::*, BufReader, BufWriter, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use failure::format_err;
-use futures::{future, Future, Stream};
-use hyper::{service, Body, Method, Request, Response, Server, StatusCode};
+use futures::{future, stream, Async, Future, Stream};
+use hyper::body::Payload;
+use hyper::server::conn::AddrStream;
+use hyper::{service, Body, HeaderMap, Method, Request, Response, Server, StatusCode};
 use log::{debug, info, warn};
 use regex::Regex;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 use structopt::StructOpt;
-use uuid::Uuid;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Interval, Timeout};
+use tokio_rustls::rustls::{ServerConfig, ServerSession};
+use tokio_rustls::{TlsAcceptor, TlsStream};
 
+use transfer_rs::transfer_rs::access_log::AccessLogEntry;
+use transfer_rs::transfer_rs::checksum::{self, Checksums, Hasher};
+use transfer_rs::transfer_rs::cidr::Cidr;
+use transfer_rs::transfer_rs::content_type;
+use transfer_rs::transfer_rs::digest;
+use transfer_rs::transfer_rs::exif::strip_metadata;
+use transfer_rs::transfer_rs::filename::{insert_suffix, is_path_component, sanitize_path};
+use transfer_rs::transfer_rs::forwarded::Forwarded;
+use transfer_rs::transfer_rs::http_date::format_http_date;
+use transfer_rs::transfer_rs::id::{IdGenerator, IdScheme};
+use transfer_rs::transfer_rs::idempotency::{IdempotencyCache, IdempotencyKey};
+use transfer_rs::transfer_rs::logger;
+use transfer_rs::transfer_rs::metadata::{self, Metadata};
+use transfer_rs::transfer_rs::percent_encoding::{
+    form_urlencoded_decode, percent_decode, percent_encode,
+};
 use transfer_rs::transfer_rs::prelude::*;
+use transfer_rs::transfer_rs::range::{self, ByteRange};
+use transfer_rs::transfer_rs::semaphore::{KeyedPermit, KeyedSemaphore, Permit, Semaphore};
+use transfer_rs::transfer_rs::sha256::Sha256;
+use transfer_rs::transfer_rs::size::parse_size;
+use transfer_rs::transfer_rs::tar;
+use transfer_rs::transfer_rs::thumbnail;
+use transfer_rs::transfer_rs::tls;
 
 type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
 
@@ -35,8 +67,431 @@ type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send
 #[structopt(name = "transfer")]
 struct Opt {
     #[structopt(short, long)]
-    /// Port number
-    port: i32,
+    /// Port number, required unless running a subcommand
+    port: Option<i32>,
+
+    #[structopt(long, alias = "bind", default_value = "0.0.0.0")]
+    /// Address to listen on (e.g. 127.0.0.1 or ::1)
+    host: IpAddr,
+
+    #[structopt(short, long, default_value = "data", parse(from_os_str))]
+    /// Directory to store the uploaded files in
+    data_dir: PathBuf,
+
+    #[structopt(long, default_value = "/upload")]
+    /// Path accepting uploads
+    upload_path: String,
+
+    #[structopt(long)]
+    /// Accept uploads by POST to any path
+    upload_anywhere: bool,
+
+    #[structopt(long, default_value = "not-found")]
+    /// Response of "GET /". "not-found", "form" (HTML upload form), "list" (JSON list of the stored
+    /// files, "?offset=<n>&limit=<n>"), "redirect:<url>" or "text:<message>"
+    root_behavior: RootBehavior,
+
+    #[structopt(long)]
+    /// Reject multipart/form-data uploads
+    disable_multipart: bool,
+
+    #[structopt(long)]
+    /// Reject raw body uploads (e.g. application/octet-stream)
+    disable_octet_stream: bool,
+
+    #[structopt(long)]
+    /// Append the canonical extension from Content-Type to raw body uploads without one
+    append_extension: bool,
+
+    #[structopt(long, default_value = "generate")]
+    /// Handling of an empty filename. "generate" stores the file as "a", "reject" responds an error
+    empty_filename_policy: EmptyFilenamePolicy,
+
+    #[structopt(long)]
+    /// Reject raw body uploads without the X-TP-Filename header instead of naming them "a", and
+    /// multipart uploads that have a part without a filename
+    require_filename: bool,
+
+    #[structopt(long, default_value = "separate")]
+    /// Handling of parts sharing a filename within one multipart request.
+    /// "separate" stores each part under its own id directory, "rename" appends "-1", "-2", ... to the
+    /// later parts' filenames, and "reject" drops the later parts and reports an error for them
+    duplicate_filename_policy: DuplicateFilenamePolicy,
+
+    #[structopt(long)]
+    /// Maximum seconds allowed for receiving an upload body, responds 408 when exceeded
+    max_upload_time: Option<u64>,
+
+    #[structopt(long, default_value = "86400")]
+    /// Seconds to remember the response of an upload with the Idempotency-Key header
+    idempotency_ttl: u64,
+
+    #[structopt(long, default_value = "1024")]
+    /// Maximum number of remembered Idempotency-Key responses, the oldest one is forgotten first
+    idempotency_capacity: usize,
+
+    #[structopt(long)]
+    /// Honor the Forwarded and X-Forwarded-* headers from any peer
+    trust_proxy: bool,
+
+    #[structopt(long, number_of_values = 1)]
+    /// Honor the Forwarded and X-Forwarded-* headers from peers in this range (e.g. 10.0.0.0/8), can be
+    /// repeated
+    trusted_proxy: Vec<Cidr>,
+
+    #[structopt(long, possible_values = &["http", "https"])]
+    /// Scheme of the generated URLs unless a trusted proxy tells otherwise [default: http]. URLs are
+    /// always https with --tls-cert
+    default_scheme: Option<String>,
+
+    #[structopt(long)]
+    /// Fixed base of the generated URLs (e.g. https://example.com/files), ignoring the Host and the
+    /// forwarded headers
+    public_url: Option<String>,
+
+    #[structopt(long, default_value = "*")]
+    /// Value of Access-Control-Allow-Origin sent to browsers, i.e. the origin allowed to upload and
+    /// download from scripts
+    cors_origin: String,
+
+    #[structopt(long)]
+    /// Require "Authorization: Bearer <token>" or "X-TP-Token: <token>" for uploads, deletions,
+    /// the list of "--root-behavior list" and "/api/"
+    auth_token: Option<String>,
+
+    #[structopt(long)]
+    /// Require --auth-token for downloads as well
+    auth_downloads: bool,
+
+    #[structopt(long)]
+    /// Answer 410 instead of 404 for the files that were deleted or expired, keeping a small record
+    /// of each of them in the data directory
+    track_tombstones: bool,
+
+    #[structopt(long)]
+    /// Key of the HMAC signing the time-limited download URLs of "/api/<id>/sign", which are served
+    /// without --auth-downloads
+    url_sign_key: Option<String>,
+
+    #[structopt(long, parse(try_from_str = parse_dir_mode))]
+    /// Permission bits in octal (e.g. 750) applied to created directories regardless of the umask
+    dir_mode: Option<u32>,
+
+    #[structopt(long)]
+    /// Maximum number of multipart uploads parsed at the same time, more are rejected with 503
+    max_multipart_parses: Option<usize>,
+
+    #[structopt(long)]
+    /// Maximum number of downloads of each file at the same time, more are rejected with 503
+    max_file_concurrency: Option<usize>,
+
+    #[structopt(long, default_value = "5")]
+    /// Seconds in the Retry-After header of the responses to throttled requests
+    retry_after: u64,
+
+    #[structopt(long, parse(try_from_str = parse_size))]
+    /// Maximum size of an upload request in bytes (e.g. 500M), larger uploads are rejected with 413
+    max_upload_size: Option<u64>,
+
+    #[structopt(long, parse(try_from_str = parse_size))]
+    /// Maximum size of each multipart part in bytes (e.g. 10M), larger parts are dropped and
+    /// reported as an error
+    max_part_size: Option<u64>,
+
+    #[structopt(long)]
+    /// Store filenames containing slashes (e.g. photos/2019/img.jpg) in nested directories instead
+    /// of keeping only the last component
+    preserve_paths: bool,
+
+    #[structopt(long, default_value = "255", parse(try_from_str = parse_max_filename_bytes))]
+    /// Maximum length of a stored filename in bytes, at least 16. Longer names are truncated
+    max_filename_bytes: usize,
+
+    #[structopt(long, default_value = "uuid", possible_values = &["uuid", "short", "ulid"])]
+    /// Format of the upload id in URLs
+    id_scheme: IdScheme,
+
+    #[structopt(long)]
+    /// Store uploads under the key given by the X-TP-Key header instead of a generated id
+    allow_user_keys: bool,
+
+    #[structopt(long)]
+    /// Replace an existing file when an upload uses the same X-TP-Key and filename
+    allow_key_overwrite: bool,
+
+    #[structopt(long)]
+    /// Remove EXIF and other metadata from uploaded JPEG and PNG images
+    strip_exif: bool,
+
+    #[structopt(
+        long,
+        use_delimiter = true,
+        possible_values = &["md5", "sha1", "sha256"]
+    )]
+    /// Digests computed on upload, e.g. md5,sha1. They are returned by the upload and sent as
+    /// X-TP-MD5 and X-TP-SHA1 on download. SHA-256 is always computed
+    checksums: Vec<checksum::Algorithm>,
+
+    #[structopt(long, number_of_values = 1)]
+    /// Reject uploads whose filename ends with this extension (e.g. exe), can be repeated
+    deny_extension: Vec<String>,
+
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    /// Remove uploads this long (e.g. 24h, 7d) after they were stored, also the longest an upload
+    /// can ask for with X-TP-Max-Age, X-TP-Expires-In or ?expires=
+    max_age: Option<Duration>,
+
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    /// Shut the server down after no requests for this long (e.g. 30s, 10m), for one-shot transfers
+    shutdown_after_idle: Option<Duration>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// PEM certificate chain to serve HTTPS with, requires --tls-key
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// PEM private key (PKCS#8 or RSA) of --tls-cert
+    tls_key: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Write logs to this file in addition to stderr
+    log_file: Option<PathBuf>,
+
+    #[structopt(long, default_value = "text", possible_values = &["text", "combined", "json"])]
+    /// Format of the access log line of each request, "combined" writes Apache combined log lines
+    /// and "json" writes JSON lines of the access log and of the other logs. The access log lines of
+    /// both are written to stderr and --log-file regardless of RUST_LOG
+    log_format: LogFormat,
+
+    #[structopt(long)]
+    /// Move the log file to "<log-file>.1" when it exceeds this many bytes
+    log_rotate: Option<u64>,
+
+    #[structopt(long, hidden = true, parse(try_from_str = parse_size))]
+    /// Pace downloads to this many bytes per second (e.g. 64K). A testing aid for the resumption and
+    /// the progress of clients, not for production
+    throttle: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Upload and download a file through the server on an ephemeral loopback port with the other
+    /// options, and exit with 1 if it fails
+    Selftest,
+}
+
+impl Opt {
+    /// Returns whether the request has the token of `--auth-token`, or true if it isn't set.
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let expected = match self.auth_token {
+            Some(ref data) => data,
+            None => return true,
+        };
+        let bearer = headers
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|data| data.to_str().ok())
+            .and_then(|data| {
+                let mut parts = data.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                        Some(token.trim())
+                    }
+                    _ => None,
+                }
+            });
+        let token = bearer.or_else(|| {
+            headers
+                .get("x-tp-token")
+                .and_then(|data| data.to_str().ok())
+        });
+        match token {
+            Some(token) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+            None => false,
+        }
+    }
+
+    fn is_trusted_proxy(&self, remote_addr: IpAddr) -> bool {
+        self.trust_proxy
+            || self
+                .trusted_proxy
+                .iter()
+                .any(|cidr| cidr.contains(remote_addr))
+    }
+
+    fn is_denied_extension(&self, filename: &str) -> bool {
+        let ext = match Path::new(filename)
+            .extension()
+            .and_then(|data| data.to_str())
+        {
+            Some(data) => data.to_ascii_lowercase(),
+            None => return false,
+        };
+        self.deny_extension
+            .iter()
+            .any(|denied| denied.trim_start_matches('.').to_ascii_lowercase() == ext)
+    }
+}
+
+fn parse_dir_mode(value: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(value, 8)
+}
+
+/// A shorter `--max-filename-bytes` leaves no room for a name, an extension and a "-N" suffix.
+const MIN_FILENAME_BYTES: usize = 16;
+
+fn parse_max_filename_bytes(value: &str) -> Fallible<usize> {
+    let ret = value.parse::<usize>()?;
+    if ret < MIN_FILENAME_BYTES {
+        return Err(format_err!(
+            "must be at least {}: {}",
+            MIN_FILENAME_BYTES,
+            value
+        ));
+    }
+    Ok(ret)
+}
+
+const DEFAULT_FILENAME: &str = "a";
+
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How often the uploads older than `--max-age` are removed.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of the files listed by `--root-behavior list` without `?limit=`.
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// Number of the entries serialized into each piece of the streamed list.
+const LIST_CHUNK_ENTRIES: usize = 64;
+
+/// Images larger than this are stored as is even with `--strip-exif`.
+const MAX_STRIP_EXIF_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximum width and height of `?thumb=<w>x<h>`.
+const MAX_THUMBNAIL_SIDE: u32 = 1024;
+
+/// Images larger than this are not decoded for thumbnails.
+const MAX_THUMBNAIL_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Number of the TLS handshakes in progress at a time.
+const MAX_TLS_HANDSHAKES: usize = 64;
+
+/// Connections not completing the TLS handshake within this are dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The value of a multipart form field without a filename is returned in the response, not stored.
+const MAX_FIELD_SIZE: usize = 64 * 1024;
+
+/// A longer `X-Request-Id` of the client is replaced rather than copied to every log line.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Requests to the API endpoints with a larger body are rejected.
+const MAX_API_BODY_SIZE: u64 = 64 * 1024;
+
+/// Lifetime of the signed URL of "/api/<id>/sign" without `?ttl=`.
+const DEFAULT_SIGNED_URL_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Directory of the data directory keeping the caches and the metadata of the stored files. It is
+/// never served, so it is neither an upload id nor a key.
+const META_DIR: &str = ".transfer";
+
+/// First segment of the paths of the API endpoints, neither an upload id nor a key either.
+const API_DIR: &str = "api";
+
+#[derive(Clone, Debug, PartialEq)]
+enum RootBehavior {
+    NotFound,
+    Form,
+    List,
+    /// The `Location` of the redirection, checked on startup.
+    Redirect(hyper::header::HeaderValue),
+    Text(String),
+}
+
+impl std::str::FromStr for RootBehavior {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        if s == "not-found" {
+            Ok(RootBehavior::NotFound)
+        } else if s == "form" {
+            Ok(RootBehavior::Form)
+        } else if s == "list" {
+            Ok(RootBehavior::List)
+        } else if s.starts_with("redirect:") {
+            let url = &s["redirect:".len()..];
+            url.parse::<hyper::Uri>()
+                .map_err(|e| format_err!("invalid redirect url: {}: {}", url, e))?;
+            let location = hyper::header::HeaderValue::from_str(url)
+                .map_err(|e| format_err!("invalid redirect url: {}: {}", url, e))?;
+            Ok(RootBehavior::Redirect(location))
+        } else if s.starts_with("text:") {
+            Ok(RootBehavior::Text(s["text:".len()..].to_owned()))
+        } else {
+            Err(format_err!("unknown root behavior: {}", s))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    Combined,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format_err!("unknown log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EmptyFilenamePolicy {
+    Generate,
+    Reject,
+}
+
+impl std::str::FromStr for EmptyFilenamePolicy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s {
+            "generate" => Ok(EmptyFilenamePolicy::Generate),
+            "reject" => Ok(EmptyFilenamePolicy::Reject),
+            _ => Err(format_err!("unknown empty filename policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DuplicateFilenamePolicy {
+    Separate,
+    Rename,
+    Reject,
+}
+
+impl std::str::FromStr for DuplicateFilenamePolicy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s {
+            "separate" => Ok(DuplicateFilenamePolicy::Separate),
+            "rename" => Ok(DuplicateFilenamePolicy::Rename),
+            "reject" => Ok(DuplicateFilenamePolicy::Reject),
+            _ => Err(format_err!("unknown duplicate filename policy: {}", s)),
+        }
+    }
 }
 
 struct MultipartRegexps {
@@ -50,6 +505,7 @@ struct MultipartRegexps {
 #[derive(Serialize)]
 struct UploadResult {
     part: Vec<UploadResultPart>,
+    field: Vec<UploadResultField>,
     error: Option<String>,
 }
 
@@ -58,599 +514,6643 @@ struct UploadResultPart {
     name: String,
     file_name: String,
     url: String,
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha1: Option<String>,
     error: Option<String>,
 }
 
+/// A multipart form field sent without a filename.
+#[derive(Serialize)]
+struct UploadResultField {
+    name: String,
+    value: String,
+}
+
+impl UploadResult {
+    /// Lists the stored files followed by the rejected ones.
+    fn new(base_url: &str, processed: &[StoredFile], rejected: &[RejectedFile]) -> Self {
+        let part = processed
+            .iter()
+            .map(|data| UploadResultPart {
+                name: data.name.to_owned(),
+                file_name: data.original_filename.to_owned(),
+                url: format!("{}/{}", base_url, data.path()),
+                sha256: Some(data.checksums.sha256.to_owned()),
+                md5: data.checksums.md5.clone(),
+                sha1: data.checksums.sha1.clone(),
+                error: None,
+            })
+            .chain(rejected.iter().map(|data| UploadResultPart {
+                name: data.name.to_owned(),
+                file_name: data.original_filename.to_owned(),
+                url: "".to_owned(),
+                sha256: None,
+                md5: None,
+                sha1: None,
+                error: Some(data.error.to_owned()),
+            }))
+            .collect();
+        UploadResult {
+            part,
+            field: vec![],
+            error: None,
+        }
+    }
+
+    fn to_xml(&self) -> String {
+        let mut xml = r#"<?xml version="1.0" encoding="UTF-8"?><upload_result>"#.to_owned();
+        for part in &self.part {
+            xml.push_str("<part>");
+            push_xml_element(&mut xml, "name", &part.name);
+            push_xml_element(&mut xml, "file_name", &part.file_name);
+            push_xml_element(&mut xml, "url", &part.url);
+            if let Some(sha256) = &part.sha256 {
+                push_xml_element(&mut xml, "sha256", sha256);
+            }
+            if let Some(md5) = &part.md5 {
+                push_xml_element(&mut xml, "md5", md5);
+            }
+            if let Some(sha1) = &part.sha1 {
+                push_xml_element(&mut xml, "sha1", sha1);
+            }
+            if let Some(error) = &part.error {
+                push_xml_element(&mut xml, "error", error);
+            }
+            xml.push_str("</part>");
+        }
+        for field in &self.field {
+            xml.push_str("<field>");
+            push_xml_element(&mut xml, "name", &field.name);
+            push_xml_element(&mut xml, "value", &field.value);
+            xml.push_str("</field>");
+        }
+        if let Some(error) = &self.error {
+            push_xml_element(&mut xml, "error", error);
+        }
+        xml.push_str("</upload_result>");
+        xml
+    }
+}
+
+fn push_xml_element(xml: &mut String, name: &str, value: &str) {
+    xml.push_str(&format!("<{}>", name));
+    for c in value.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '"' => xml.push_str("&quot;"),
+            '\'' => xml.push_str("&apos;"),
+            _ => xml.push(c),
+        }
+    }
+    xml.push_str(&format!("</{}>", name));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResultFormat {
+    Json,
+    Xml,
+}
+
+impl ResultFormat {
+    /// Picks the first supported media range of the Accept header, JSON by default.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let accept = match headers
+            .get(hyper::header::ACCEPT)
+            .and_then(|data| data.to_str().ok())
+        {
+            Some(data) => data,
+            None => return ResultFormat::Json,
+        };
+        for media_range in accept.split(',') {
+            match content_type::essence(media_range).as_str() {
+                "application/json" => return ResultFormat::Json,
+                "application/xml" | "text/xml" => return ResultFormat::Xml,
+                _ => (),
+            }
+        }
+        ResultFormat::Json
+    }
+}
+
 fn main() -> Fallible<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    let opt = Opt::from_args();
+    if opt.port.is_none() && opt.command.is_none() {
+        structopt::clap::Error::with_description(
+            "The following required arguments were not provided:\n    --port <port>",
+            structopt::clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    logger::init(
+        opt.log_file.clone(),
+        opt.log_rotate,
+        opt.log_format == LogFormat::Json,
+    )?;
     info!("Hello");
 
-    let opt: Opt = Opt::from_args();
-    info!("port: {}", opt.port);
+    check_options(&opt)?;
+    if let Some(Command::Selftest) = opt.command {
+        return selftest(opt);
+    }
 
-    std::fs::create_dir_all("data")?;
+    let opt = Arc::new(opt);
+    info!("port: {}", opt.port.unwrap_or_default());
 
-    let multipart_regexps = Arc::new(create_multipart_regexps()?);
+    ensure_dir(&opt.data_dir, opt.dir_mode)?;
 
-    hyper::rt::run(
-        Server::bind(&format!("0.0.0.0:{}", opt.port).parse()?)
-            .serve(move || {
-                info!("new service");
-                let multipart_regexps = multipart_regexps.clone();
-                service::service_fn(move |req| {
-                    let multipart_regexps = multipart_regexps.clone();
-                    info!("uri: {:?}", req.uri());
-                    info!("version: {:?}", req.version());
-                    info!("headers: {:?}", req.headers());
-                    info!("method: {:?}", req.method());
-
-                    match *req.method() {
-                        Method::PUT
-                        | Method::DELETE
-                        | Method::HEAD
-                        | Method::OPTIONS
-                        | Method::CONNECT
-                        | Method::PATCH
-                        | Method::TRACE => return handler_not_implemented(),
-                        _ => (),
-                    }
+    let tls_config = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_server_config(cert, key)?),
+        (None, None) => None,
+        _ => {
+            return Err(format_err!(
+                "--tls-cert and --tls-key must be given together"
+            ))
+        }
+    };
 
-                    // TODO: sanitize path. e.g. http://host/../filename.jpg
-                    let get_path_regexp = Regex::new(&format!(r#"^/([^/]*)/([^/]*)$"#)).unwrap();
-                    if *req.method() == Method::GET && get_path_regexp.is_match(req.uri().path()) {
-                        return get_handler();
-                    }
+    let port = opt.port.unwrap_or_default();
+    let port = u16::try_from(port).map_err(|_| format_err!("invalid port: {}", port))?;
+    let addr = SocketAddr::new(opt.host, port);
+    info!("listen: {}", addr);
+    let shutdown_after_idle = opt.shutdown_after_idle;
+    let reaper_opt = opt.clone();
+    let download_semaphore = new_download_semaphore(&opt);
+    let last_activity = Arc::new(Mutex::new(Instant::now()));
+    let in_flight = Arc::new(AtomicUsize::new(0));
 
-                    match req.uri().path() {
-                        "/upload" => {
-                            if *req.method() == Method::POST {
-                                upload_handler(req, multipart_regexps)
-                            } else {
-                                handler_method_not_allowed()
-                            }
-                        }
-                        // path if path == "" => {}
-                        _ => handler_not_found(),
-                    }
+    // the signals are registered once the runtime polls the future.
+    let shutdown = {
+        let last_activity = last_activity.clone();
+        let in_flight = in_flight.clone();
+        future::lazy(move || {
+            let idle: Box<dyn Future<Item = (), Error = ()> + Send> = match shutdown_after_idle {
+                Some(idle) => Box::new(idle_timeout(last_activity, idle)),
+                None => Box::new(future::empty()),
+            };
+            shutdown_signal()
+                .select(idle)
+                .map(move |_| {
+                    info!(
+                        "stop accepting connections, draining {} requests",
+                        in_flight.load(Ordering::SeqCst)
+                    );
                 })
-            })
-            .map_err(|e| println!("server error: {}", e)),
-    );
+                .map_err(|_| ())
+        })
+    };
+    let (_, server) = bind_server(
+        opt,
+        addr,
+        tls_config,
+        download_semaphore.clone(),
+        last_activity,
+        in_flight,
+        shutdown,
+    )?;
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    // uploads may ask for a max-age without --max-age.
+    runtime.spawn(expire_uploads(reaper_opt, download_semaphore));
+    runtime
+        .block_on(server.map_err(|e| println!("server error: {}", e)))
+        .ok();
+    // the expiry of the uploads never finishes by itself.
+    runtime.shutdown_now().wait().ok();
 
     info!("Bye");
     Ok(())
 }
 
-fn get_handler() -> BoxFut {
-    Box::new(future::ok(
-        Response::builder()
-            .status(StatusCode::METHOD_NOT_ALLOWED)
-            .body(Body::from("TODO get handler"))
-            .unwrap(),
-    ))
+/// Size of the file uploaded by the selftest subcommand, more than one download chunk.
+const SELFTEST_FILE_SIZE: usize = DOWNLOAD_CHUNK_SIZE + 1;
+
+/// Runs a server on an ephemeral loopback port with the options and a temporary data directory,
+/// and checks that a file comes back unchanged after an upload and a download through HTTP.
+fn selftest(opt: Opt) -> Fallible<()> {
+    let data_dir = std::env::temp_dir().join(format!("transfer-selftest-{}", uuid::Uuid::new_v4()));
+    let opt = Arc::new(Opt {
+        host: IpAddr::from([127, 0, 0, 1]),
+        port: Some(0),
+        data_dir: data_dir.clone(),
+        // the URL of the upload result is requested as is.
+        public_url: None,
+        tls_cert: None,
+        tls_key: None,
+        ..opt
+    });
+    ensure_dir(&opt.data_dir, opt.dir_mode)?;
+    let (shutdown_sender, shutdown_receiver) = futures::sync::oneshot::channel::<()>();
+    let (addr, server) = bind_server(
+        opt.clone(),
+        SocketAddr::new(opt.host, 0),
+        None,
+        new_download_semaphore(&opt),
+        Arc::new(Mutex::new(Instant::now())),
+        Arc::new(AtomicUsize::new(0)),
+        shutdown_receiver.map_err(|_| ()),
+    )?;
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    runtime.spawn(server.map_err(|e| warn!("server error: {}", e)));
+    let ret = selftest_round_trip(&mut runtime, addr, &opt);
+    shutdown_sender.send(()).ok();
+    runtime.shutdown_on_idle().wait().ok();
+    std::fs::remove_dir_all(&data_dir).ok();
+    ret?;
+    println!("selftest passed");
+    Ok(())
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum ParseType {
-    LoadBoundary,
-    LoadContentDescription,
-    Body,
-    End,
+fn selftest_round_trip(
+    runtime: &mut tokio::runtime::Runtime,
+    addr: SocketAddr,
+    opt: &Opt,
+) -> Fallible<()> {
+    let client = hyper::Client::new();
+    let data = (0..SELFTEST_FILE_SIZE)
+        .map(|_| rand::random::<u8>())
+        .collect::<Vec<_>>();
+    let mut upload = Request::post(format!("http://{}{}", addr, opt.upload_path));
+    let body = if !opt.disable_octet_stream {
+        upload
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header("x-tp-filename", "selftest.bin");
+        data.clone()
+    } else {
+        let boundary = uuid::Uuid::new_v4().to_simple().to_string();
+        upload.header(
+            hyper::header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", boundary).as_str(),
+        );
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"selftest.bin\"\r\n\r\n",
+            boundary
+        )
+        .into_bytes();
+        body.extend_from_slice(&data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        body
+    };
+    if let Some(ref token) = opt.auth_token {
+        upload.header(
+            hyper::header::AUTHORIZATION,
+            format!("Bearer {}", token).as_str(),
+        );
+    }
+    let response = runtime.block_on(client.request(upload.body(Body::from(body))?))?;
+    let status = response.status();
+    let body = runtime.block_on(response.into_body().concat2())?;
+    if status != StatusCode::OK {
+        return Err(format_err!(
+            "upload failed: {}, {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    let upload_result = serde_json::from_slice::<serde_json::Value>(&body)?;
+    let url = upload_result["part"][0]["url"]
+        .as_str()
+        .ok_or_else(|| format_err!("no url in the upload result: {}", upload_result))?
+        .parse::<hyper::Uri>()?;
+    info!("selftest url: {}", url);
+
+    let mut download = Request::get(format!("http://{}{}", addr, url.path()));
+    if let Some(ref token) = opt.auth_token {
+        download.header(
+            hyper::header::AUTHORIZATION,
+            format!("Bearer {}", token).as_str(),
+        );
+    }
+    let response = runtime.block_on(client.request(download.body(Body::empty())?))?;
+    let status = response.status();
+    let body = runtime.block_on(response.into_body().concat2())?;
+    if status != StatusCode::OK {
+        return Err(format_err!("download failed: {}", status));
+    }
+    if body.as_ref() != data.as_slice() {
+        return Err(format_err!(
+            "downloaded {} bytes differ from the uploaded {} bytes",
+            body.len(),
+            data.len()
+        ));
+    }
+    Ok(())
 }
 
-#[derive(Debug, PartialEq)]
-enum CommandRet {
-    NextCommand,
-    Consumed,
+/// Rejects the options that are invalid together or malformed beyond what the parsers check.
+fn check_options(opt: &Opt) -> Fallible<()> {
+    if !opt.upload_path.starts_with('/') {
+        return Err(format_err!(
+            "--upload-path must start with '/': {}",
+            opt.upload_path
+        ));
+    }
+
+    if let Some(ref url) = opt.public_url {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format_err!(
+                "--public-url must start with http:// or https://: {}",
+                url
+            ));
+        }
+    }
+
+    if hyper::header::HeaderValue::from_str(&opt.cors_origin).is_err() {
+        return Err(format_err!("invalid --cors-origin: {}", opt.cors_origin));
+    }
+
+    if opt.throttle == Some(0) {
+        return Err(format_err!("--throttle must be greater than 0"));
+    }
+    Ok(())
 }
 
-struct ParseMultipartContext {
-    boundary: String,
-    command: ParseType,
-    name: Option<String>,
-    file_uuid: Option<Uuid>,
-    filename: Option<String>,
-    processed: Vec<String>,
-    file_writer: Option<BufWriter<std::fs::File>>,
-    buffer: Vec<u8>,
-    regexps: Arc<MultipartRegexps>,
-    body_skip_crlf: bool,
-    file_root: PathBuf,
+type BoxServer = Box<dyn Future<Item = (), Error = hyper::Error> + Send>;
+
+/// Returns the semaphore of the downloads of each stored file, shared by the server and the removal
+/// of the expired uploads.
+fn new_download_semaphore(opt: &Opt) -> Arc<KeyedSemaphore<PathBuf>> {
+    Arc::new(KeyedSemaphore::new(
+        opt.max_file_concurrency.unwrap_or_else(usize::max_value),
+    ))
 }
 
-impl ParseMultipartContext {
-    fn new(boundary: String, regexps: Arc<MultipartRegexps>, file_root: PathBuf) -> Self {
-        Self {
-            boundary,
-            command: ParseType::LoadBoundary,
-            name: Default::default(),
-            file_uuid: Default::default(),
-            filename: Default::default(),
-            processed: Default::default(),
-            file_writer: Default::default(),
-            buffer: Default::default(),
-            regexps,
-            body_skip_crlf: Default::default(),
-            file_root,
+/// Binds `addr` and returns its local address and the server that handles the requests until
+/// `shutdown` completes.
+fn bind_server<F>(
+    opt: Arc<Opt>,
+    addr: SocketAddr,
+    tls_config: Option<Arc<ServerConfig>>,
+    download_semaphore: Arc<KeyedSemaphore<PathBuf>>,
+    last_activity: Arc<Mutex<Instant>>,
+    in_flight: Arc<AtomicUsize>,
+    shutdown: F,
+) -> Fallible<(SocketAddr, BoxServer)>
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    // bound first for the URLs of the requests without a Host header.
+    let listener = std::net::TcpListener::bind(&addr)
+        .map_err(|e| format_err!("failed to bind {}: {}", addr, e))?;
+    let local_addr = listener.local_addr()?;
+    let multipart_regexps = Arc::new(create_multipart_regexps()?);
+    let id_generator: Arc<dyn IdGenerator + Send + Sync> = Arc::from(opt.id_scheme.generator());
+    let idempotency_cache = Arc::new(IdempotencyCache::new(
+        Duration::from_secs(opt.idempotency_ttl),
+        opt.idempotency_capacity,
+    ));
+    let multipart_semaphore = Arc::new(Semaphore::new(
+        opt.max_multipart_parses.unwrap_or_else(usize::max_value),
+    ));
+
+    let new_service = {
+        let last_activity = last_activity.clone();
+        let in_flight = in_flight.clone();
+        move |remote_addr: SocketAddr| {
+            info!("new service: {}", remote_addr);
+            let multipart_regexps = multipart_regexps.clone();
+            let id_generator = id_generator.clone();
+            let idempotency_cache = idempotency_cache.clone();
+            let multipart_semaphore = multipart_semaphore.clone();
+            let download_semaphore = download_semaphore.clone();
+            let last_activity = last_activity.clone();
+            let in_flight = in_flight.clone();
+            let opt = opt.clone();
+            service::service_fn(move |req| {
+                let multipart_regexps = multipart_regexps.clone();
+                let id_generator = id_generator.clone();
+                let idempotency_cache = idempotency_cache.clone();
+                let multipart_semaphore = multipart_semaphore.clone();
+                let download_semaphore = download_semaphore.clone();
+                let last_activity = last_activity.clone();
+                let in_flight = InFlight::new(in_flight.clone());
+                let opt = opt.clone();
+                let request_id = request_id(req.headers());
+                let _scope = logger::RequestIdScope::enter(&request_id);
+                *last_activity.lock().unwrap() = Instant::now();
+                let client = client_ip(req.headers(), remote_addr.ip(), &opt);
+                info!("client: {}", client);
+                info!("uri: {:?}", req.uri());
+                info!("version: {:?}", req.version());
+                info!("headers: {:?}", req.headers());
+                info!("method: {:?}", req.method());
+
+                let access_log = AccessLogEntry {
+                    request_id: request_id.clone(),
+                    client,
+                    time: SystemTime::now(),
+                    method: req.method().to_string(),
+                    uri: req.uri().to_string(),
+                    version: format!("{:?}", req.version()),
+                    status: 0,
+                    bytes: 0,
+                    referer: header_string(req.headers(), hyper::header::REFERER),
+                    user_agent: header_string(req.headers(), hyper::header::USER_AGENT),
+                };
+                let log_format = opt.log_format;
+                let cors_origin = opt.cors_origin.clone();
+                let response_request_id = request_id.clone();
+                Box::new(WithRequestId {
+                    inner: route(
+                        req,
+                        remote_addr,
+                        local_addr,
+                        client,
+                        multipart_regexps,
+                        id_generator,
+                        idempotency_cache,
+                        multipart_semaphore,
+                        download_semaphore,
+                        opt,
+                    )
+                    .map(move |mut response| {
+                        drop(in_flight);
+                        add_cors_headers(response.headers_mut(), &cors_origin);
+                        response.headers_mut().insert(
+                            "x-request-id",
+                            hyper::header::HeaderValue::from_str(&response_request_id).unwrap(),
+                        );
+                        *last_activity.lock().unwrap() = Instant::now();
+                        let access_log = AccessLogEntry {
+                            status: response.status().as_u16(),
+                            ..access_log
+                        };
+                        with_access_log(response, log_format, access_log)
+                    }),
+                    request_id,
+                }) as BoxFut
+            })
+        }
+    };
+
+    match tls_config {
+        Some(config) => {
+            let listener = TcpListener::from_std(listener, &tokio::reactor::Handle::default())?;
+            let server = Server::builder(tls_incoming(listener, config))
+                .serve(service::make_service_fn(
+                    move |socket: &TlsStream<TcpStream, ServerSession>| {
+                        let remote_addr = socket
+                            .get_ref()
+                            .0
+                            .peer_addr()
+                            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+                        new_service(remote_addr)
+                    },
+                ))
+                .with_graceful_shutdown(shutdown);
+            Ok((local_addr, Box::new(server)))
+        }
+        None => {
+            let server = Server::from_tcp(listener)?.serve(service::make_service_fn(
+                move |socket: &AddrStream| new_service(socket.remote_addr()),
+            ));
+            Ok((
+                local_addr,
+                Box::new(server.with_graceful_shutdown(shutdown)),
+            ))
         }
     }
 }
 
-trait ParseMultipartCommand {
-    fn execute(
-        &self,
-        context: &mut ParseMultipartContext,
-        reader: &mut BufReader<&[u8]>,
-    ) -> Fallible<CommandRet>;
+/// Removes the expired uploads periodically, see `is_expired`.
+fn expire_uploads(
+    opt: Arc<Opt>,
+    download_semaphore: Arc<KeyedSemaphore<PathBuf>>,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(EXPIRE_INTERVAL)
+        .for_each(move |_| {
+            remove_expired_uploads(&opt, &download_semaphore);
+            Ok(())
+        })
+        .map_err(|e| warn!("timer error: {:?}", e))
 }
 
-impl ParseMultipartCommand for ParseType {
-    fn execute(
-        &self,
-        context: &mut ParseMultipartContext,
-        reader: &mut BufReader<&[u8]>,
-    ) -> Fallible<CommandRet> {
-        match &self {
-            ParseType::LoadBoundary => {
-                let line = {
-                    let mut line = String::new();
-                    match reader.read_line(&mut line) {
-                        Ok(0) => return Ok(CommandRet::Consumed),
-                        Ok(_) => {
-                            if line.ends_with("\r\n") {
-                                let mut ret_line = Vec::new();
-                                ret_line.append(&mut context.buffer);
-                                ret_line.extend(line.into_bytes());
-                                ret_line
-                            } else {
-                                context.buffer.extend(line.into_bytes());
-                                return Ok(CommandRet::NextCommand);
-                            }
-                        }
-                        Err(e) => {
-                            return Err(format_err!("failed to read line: {:?}", e));
-                        }
-                    }
-                };
+/// Removes the expired uploads except for the files being downloaded, which are removed after the
+/// downloads.
+fn remove_expired_uploads(opt: &Opt, download_semaphore: &KeyedSemaphore<PathBuf>) {
+    let data_dir = &opt.data_dir;
+    let entries = match std::fs::read_dir(data_dir) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to read {:?}: {:?}", data_dir, e);
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() && entry.file_name() != META_DIR {
+            remove_expired_files(opt, download_semaphore, &path);
+        }
+    }
+}
 
-                match String::from_utf8(line) {
-                    Ok(s) => {
-                        info!("boundary: '{}'", context.boundary);
-                        info!("s len: {}, val: '{}'", s.len(), s);
-                        if s == format!("--{}\r\n", context.boundary) {
-                            info!("boundary consumed");
-                            context.command = ParseType::LoadContentDescription;
-                            return Ok(CommandRet::NextCommand);
-                        } else {
-                            // TODO:
-                            return Err(format_err!("failed to consume a boundary"));
-                        }
-                    }
-                    Err(e) => {
-                        // TODO:
-                        return Err(format_err!("failed to parse boundary: {:?}", e));
-                    }
-                }
+/// Removes the expired files under `dir`, the directory of an upload or one of its nested
+/// directories.
+fn remove_expired_files(opt: &Opt, download_semaphore: &KeyedSemaphore<PathBuf>, dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(data) => data.filter_map(Result::ok).collect::<Vec<_>>(),
+        Err(e) => {
+            warn!("failed to read {:?}: {:?}", dir, e);
+            return;
+        }
+    };
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_expired_files(opt, download_semaphore, &path);
+            continue;
+        }
+        // removed with its file, or still being written.
+        if !path.is_file() || is_partial_name(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if !is_expired(&opt.data_dir, &path, opt.max_age) {
+            continue;
+        }
+        let removed = download_semaphore.unless_acquired(&path, || {
+            if opt.track_tombstones {
+                write_tombstone(&opt.data_dir, &path);
             }
-            ParseType::LoadContentDescription => {
-                let line = {
-                    let mut line = String::new();
-                    match reader.read_line(&mut line) {
-                        Ok(0) => {
-                            info!("empty");
-                            return Ok(CommandRet::Consumed);
-                        }
-                        Ok(_) => {
-                            if line == "\r\n" && context.buffer.is_empty() {
-                                context.command = ParseType::Body;
-                                return Ok(CommandRet::NextCommand);
-                            } else if line.ends_with("\r\n") {
-                                let mut ret_val = Vec::new();
-                                ret_val.append(&mut context.buffer);
-                                ret_val.extend(line.into_bytes());
-                                ret_val
-                            } else {
-                                context.buffer.extend(line.into_bytes());
-                                return Ok(CommandRet::NextCommand);
-                            }
-                        }
-                        Err(e) => {
-                            return Err(format_err!("failed to read line: {:?}", e));
-                        }
-                    }
-                };
+            remove_stored_file(&opt.data_dir, &path)
+        });
+        match removed {
+            Some(Ok(_)) => info!("expired: {:?}", path),
+            Some(Err(e)) => warn!("failed to remove {:?}: {:?}", path, e),
+            None => info!("keep {:?} until the downloads finish", path),
+        }
+    }
+}
 
-                match String::from_utf8(line) {
-                    Ok(s) => {
-                        let reg_formdata = &context.regexps.form_data;
-                        let reg_mime = &context.regexps.mime;
-                        if reg_formdata.is_match(&s) {
-                            info!("ContentDescription: '{}'", s);
-                            match context.regexps.content_disposition_name.captures(&s) {
-                                Some(name) => match name.get(1) {
-                                    Some(name) => context.name = Some(name.as_str().to_owned()),
-                                    None => return Err(format_err!("unexpected")),
-                                },
-                                None => (),
-                            }
-                            match context.regexps.content_disposition_filename.captures(&s) {
-                                Some(filename) => match filename.get(1) {
-                                    Some(filename) => {
-                                        let mut uuid = Some(Uuid::new_v4());
-                                        let mut filename = Some(filename.as_str().to_owned());
-                                        std::mem::swap(&mut context.file_uuid, &mut uuid);
-                                        std::mem::swap(&mut context.filename, &mut filename);
-                                        if uuid.is_some() && filename.is_some() {
-                                            context.processed.push(format!(
-                                                "{}/{}",
-                                                uuid.unwrap(),
-                                                filename.unwrap()
-                                            ));
-                                        }
-                                    }
-                                    None => return Err(format_err!("unexpected")),
-                                },
-                                None => (),
-                            }
-                            info!("name: {:?}, filename: {:?}", context.name, context.filename);
-                            return Ok(CommandRet::NextCommand);
-                        } else if let Some(data) = reg_mime.captures(&s) {
-                            match data.get(1) {
-                                Some(data) => {
-                                    info!("ContentDescription mime: '{}'", data.as_str());
-                                    return Ok(CommandRet::NextCommand);
-                                }
-                                None => {
-                                    // TODO:
-                                    return Err(format_err!("unexpected"));
-                                }
-                            }
-                        } else {
-                            info!("ContentDescription (ignored): '{}'", s);
-                            return Ok(CommandRet::NextCommand);
-                        }
-                    }
-                    Err(e) => {
-                        // TODO:
-                        return Err(format_err!("failed to parse boundary: {:?}", e));
-                    }
-                }
-            }
-            ParseType::Body => {
-                let mut line = {
-                    let mut line = Vec::new();
-                    match reader.read_until(b'\n', &mut line) {
-                        Ok(0) => {
-                            return Ok(CommandRet::Consumed);
-                        }
-                        Ok(_) => {
-                            if line.ends_with(b"\r\n") {
-                                if &line == b"\r\n" {
-                                    info!("newline");
-                                }
-                                let mut ret_line = Vec::new();
-                                ret_line.append(&mut context.buffer);
-                                ret_line.extend(line);
-                                ret_line
-                            } else {
-                                info!("body: next_buf.extend, chunk.len: {}", line.len());
-                                context.buffer.extend(line);
-                                return Ok(CommandRet::NextCommand);
-                            }
-                        }
-                        Err(e) => {
-                            return Err(format_err!("failed to read line: {:?}", e));
-                        }
-                    }
-                };
-                if line == format!("--{}\r\n", context.boundary).as_bytes() {
-                    info!("match separator");
-                    let mut writer = None;
-                    std::mem::swap(&mut writer, &mut context.file_writer);
-                    if let Some(mut writer) = writer {
-                        writer.flush().ok();
-                    }
-                    context.command = ParseType::LoadContentDescription;
-                    context.body_skip_crlf = false;
-                    Ok(CommandRet::NextCommand)
-                } else if line == format!("--{}--\r\n", context.boundary).as_bytes() {
-                    info!("match end");
-                    let mut writer = None;
-                    std::mem::swap(&mut writer, &mut context.file_writer);
-                    if let Some(mut writer) = writer {
-                        writer.flush().ok();
-                    }
-                    context.command = ParseType::End;
-                    Ok(CommandRet::NextCommand)
-                } else {
-                    info!("body.len: '{}'", line.len());
-                    if context.body_skip_crlf {
-                        line.insert(0, b'\r');
-                        line.insert(1, b'\n');
-                    }
-                    if line.ends_with(b"\r\n") {
-                        line.truncate(line.len() - 2);
-                        context.body_skip_crlf = true;
-                    }
-                    context.body_skip_crlf = true;
-                    let writer = match context.file_writer {
-                        Some(ref mut writer) => writer,
-                        None => {
-                            let filename = context.filename.as_ref().unwrap();
-                            let filepath = context
-                                .file_root
-                                .join(context.file_uuid.unwrap().to_string())
-                                .join(filename);
-                            let create_dir_ret =
-                                std::fs::create_dir_all(filepath.parent().unwrap());
-                            if let Err(e) = create_dir_ret {
-                                return Err(format_err!("failed to create directory: {:?}", e));
-                            }
-                            context.file_writer = match std::fs::File::create(filepath) {
-                                Ok(file) => Some(BufWriter::new(file)),
-                                Err(e) => return Err(format_err!("failed to open file: {:?}", e)),
-                            };
-                            context.file_writer.as_mut().unwrap()
-                        }
-                    };
-                    match writer.write_all(&line) {
-                        Ok(_) => Ok(CommandRet::NextCommand),
-                        Err(e) => return Err(format_err!("failed to write file: {:?}", e)),
-                    }
-                }
+/// Returns whether the stored file `filepath` is past its expiry, see `Metadata::expires`.
+///
+/// A file without metadata, e.g. one stored by an older version, expires `max_age` after its last
+/// modification.
+fn is_expired(data_dir: &Path, filepath: &Path, max_age: Option<Duration>) -> bool {
+    let expires = match read_metadata(data_dir, filepath) {
+        Some(data) => data.expires(max_age),
+        None => std::fs::metadata(filepath)
+            .and_then(|data| data.modified())
+            .ok()
+            .and_then(|modified| max_age.map(|max_age| modified + max_age)),
+    };
+    expires.map_or(false, |expires| expires <= SystemTime::now())
+}
+
+/// Accepts the connections and completes their TLS handshakes, `MAX_TLS_HANDSHAKES` at a time.
+///
+/// A connection failing the handshake is dropped without stopping the server.
+fn tls_incoming(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+) -> impl Stream<Item = TlsStream<TcpStream, ServerSession>, Error = std::io::Error> {
+    let acceptor = TlsAcceptor::from(config);
+    listener
+        .incoming()
+        .then(|ret| match ret {
+            Ok(data) => Ok::<_, std::io::Error>(Some(data)),
+            Err(e) => {
+                warn!("failed to accept: {:?}", e);
+                Ok(None)
             }
-            ParseType::End => {
-                if context.file_uuid.is_some() && context.filename.is_some() {
-                    context.processed.push(format!(
-                        "{}/{}",
-                        context.file_uuid.as_ref().unwrap(),
-                        context.filename.as_ref().unwrap()
-                    ));
+        })
+        .filter_map(|data| data)
+        .map(move |socket| {
+            Timeout::new(acceptor.accept(socket), TLS_HANDSHAKE_TIMEOUT).then(|ret| match ret {
+                Ok(data) => Ok::<_, std::io::Error>(Some(data)),
+                Err(e) => {
+                    warn!("TLS handshake failed: {:?}", e);
+                    Ok(None)
                 }
-                Ok(CommandRet::Consumed)
-            }
-        }
+            })
+        })
+        .buffer_unordered(MAX_TLS_HANDSHAKES)
+        .filter_map(|data| data)
+}
+
+/// Counts the requests being handled until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter)
     }
 }
 
-fn upload_handler(req: Request<Body>, multipart_regexps: Arc<MultipartRegexps>) -> BoxFut {
-    if let Some(content_type) = req.headers().get(hyper::header::CONTENT_TYPE) {
-        if let Ok(content_type) = content_type.to_str() {
-            if content_type.contains("multipart/form-data") {
-                // curl -F myfile=@$HOME/path/to/file
-                return upload_handler_multipart(req, multipart_regexps);
-            } else if content_type == "application/x-www-form-urlencoded" {
-                info!("TODO: {}", content_type);
-                // curl --data-urlencode name@file --data-urlencode name@file
-                // name=<encoded>&name=<encoded>
-                // curl --data-urlencode @file --data-urlencode @file
-                // <encoded>&<encoded>
-                return Box::new(future::ok(
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::from("TODO"))
-                        .unwrap(),
-                ));
-            }
-        }
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
+}
 
-    // curl -H "Content-Type: application/octet-stream" --data-binary @$HOME/path/to/file
-    // curl -H "Content-Type: image/png" --data-binary @$HOME/path/to/file
-    // curl -H "Content-Type: foobar/baz" --data-binary @$HOME/path/to/file
-    upload_handler_file(req)
+/// Polls the inner future with the log records tagged with `request_id`.
+struct WithRequestId<F> {
+    inner: F,
+    request_id: String,
 }
 
-fn upload_handler_file(req: Request<Body>) -> BoxFut {
-    let file_root = "data";
-    let (head, body) = req.into_parts();
-    let filename = match head.headers.get("x-tp-filename") {
-        Some(filename) => match filename.to_str() {
-            Ok(filename) => filename.to_owned(),
-            _ => "a".to_owned(),
-        },
-        None => "a".to_owned(),
+impl<F: Future> Future for WithRequestId<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let _scope = logger::RequestIdScope::enter(&self.request_id);
+        self.inner.poll()
+    }
+}
+
+/// Resolves on the first SIGINT, or SIGTERM on unix.
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream().map(|_| "SIGINT");
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+
+        ctrl_c.select(Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM"))
     };
-    let host = head
-        .headers
-        .get(hyper::header::HOST)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned();
-    let body = body.concat2();
-    Box::new(body.map(move |data| {
-        let host = host;
-        let file_id = Uuid::new_v4();
-        let filepath = PathBuf::new()
-            .join(file_root)
-            .join(format!("{}", file_id))
-            .join(&filename);
-        match std::fs::create_dir_all(filepath.parent().unwrap()) {
-            Ok(_) => (),
-            Err(e) => {
-                warn!("failed to create directory: {:?}", e);
-                return Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from("failed to create directory"))
-                    .unwrap();
-            }
-        }
-        match std::fs::write(&filepath, data) {
-            Ok(_) => {
-                info!("wrote");
-                let upload_result = UploadResult {
-                    part: vec![UploadResultPart {
-                        name: "name".to_owned(),
-                        file_name: filepath.file_name().unwrap().to_str().unwrap().to_owned(),
-                        url: format!("http://{}/{}/{}", host, file_id, filename),
-                        error: None,
-                    }],
-                    error: None,
-                };
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(serde_json::to_string(&upload_result).unwrap()))
-                    .unwrap()
-            }
-            Err(e) => {
-                info!("err: {:?}", e);
-                Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::empty())
-                    .unwrap()
-            }
-        }
-    }))
+    #[cfg(not(unix))]
+    let signals = ctrl_c;
+    signals
+        .into_future()
+        .map(|(signal, _)| info!("received {}", signal.unwrap_or_default()))
+        .or_else(|(e, _)| {
+            // keeps serving rather than shutting down at once.
+            warn!("failed to wait for the signals: {:?}", e);
+            future::empty()
+        })
 }
 
-fn upload_handler_multipart(
+/// Resolves once no request has been received or completed for `idle`.
+fn idle_timeout(
+    last_activity: Arc<Mutex<Instant>>,
+    idle: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(Duration::from_secs(1))
+        .take_while(move |_| Ok(last_activity.lock().unwrap().elapsed() < idle))
+        .for_each(|_| Ok(()))
+        .map(move |_| info!("shutdown after idle for {:?}", idle))
+        .map_err(|e| warn!("timer error: {:?}", e))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn route(
     req: Request<Body>,
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    client: IpAddr,
     multipart_regexps: Arc<MultipartRegexps>,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    idempotency_cache: Arc<IdempotencyCache>,
+    multipart_semaphore: Arc<Semaphore>,
+    download_semaphore: Arc<KeyedSemaphore<PathBuf>>,
+    opt: Arc<Opt>,
 ) -> BoxFut {
-    let file_root = "data";
-    let reg = &multipart_regexps.boundary;
+    if *req.method() == Method::OPTIONS && req.uri().path() == "*" {
+        return options_asterisk_handler();
+    }
 
-    let content_type = match req.headers().get(hyper::header::CONTENT_TYPE) {
-        Some(data) => match data.to_str() {
-            Ok(data) => data,
-            Err(e) => panic!("TODO"),
-        },
-        None => unreachable!(),
-    };
+    if *req.method() == Method::OPTIONS {
+        return cors_preflight_handler(req.headers());
+    }
 
-    let boundary = match reg.captures(content_type) {
-        Some(cap) => match cap.get(1) {
-            Some(boundary) => boundary.as_str().to_owned(),
-            None => {
-                return Box::new(future::ok(
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::from("failed to parse boundary"))
-                        .unwrap(),
-                ));
+    match *req.method() {
+        Method::PUT | Method::CONNECT | Method::PATCH | Method::TRACE => {
+            return handler_not_implemented()
+        }
+        _ => (),
+    }
+
+    let is_upload_path = req.uri().path() == opt.upload_path;
+    if !is_upload_path && req.uri().path().starts_with(&format!("/{}/", API_DIR)) {
+        let base_url = public_base_url(req.headers(), remote_addr.ip(), local_addr, &opt);
+        return api_handler(req, base_url, opt);
+    }
+    if *req.method() == Method::POST && (is_upload_path || opt.upload_anywhere) {
+        if !opt.is_authorized(req.headers()) {
+            warn!("unauthorized upload");
+            return handler_unauthorized();
+        }
+        let idempotency_key = req
+            .headers()
+            .get("idempotency-key")
+            .and_then(|data| data.to_str().ok())
+            .map(|data| IdempotencyKey {
+                client,
+                method: req.method().clone(),
+                path: req.uri().path().to_owned(),
+                key: data.to_owned(),
+            });
+        if let Some(key) = &idempotency_key {
+            if let Some(response) = idempotency_cache.get(key) {
+                info!("replay the response of idempotency key: {}", key.key);
+                return Box::new(future::ok(response));
             }
-        },
-        None => {
-            return Box::new(future::ok(
-                Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from("failed to capture"))
-                    .unwrap(),
-            ));
         }
-    };
-    let host = req
-        .headers()
-        .get(hyper::header::HOST)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned();
-    Box::new(
-        req.into_body()
-            .fold(
-                ParseMultipartContext::new(
-                    boundary,
-                    multipart_regexps.clone(),
-                    PathBuf::new().join(file_root),
-                ),
-                move |mut context, data| {
-                    debug!("chunk size: {}", data.len());
-                    let mut buf = Vec::new();
-                    std::mem::swap(&mut context.buffer, &mut buf);
-                    buf.extend(data);
-                    let mut reader = BufReader::new(buf.as_slice());
+        let base_url = public_base_url(req.headers(), remote_addr.ip(), local_addr, &opt);
+        let max_upload_time = opt.max_upload_time;
+        let fut = with_max_upload_time(
+            upload_handler(
+                req,
+                base_url,
+                multipart_regexps,
+                id_generator,
+                multipart_semaphore,
+                opt,
+            ),
+            max_upload_time,
+        );
+        return match idempotency_key {
+            Some(key) => with_idempotency_key(fut, key, idempotency_cache),
+            None => fut,
+        };
+    }
 
-                    if context.command == ParseType::End {
-                        warn!("parsetype is end but received chunk");
-                        return future::ok::<_, hyper::Error>(context);
-                    }
+    // the filename may be nested in directories with --preserve-paths.
+    let get_path_regexp = Regex::new(r#"^/([^/]+)/(.+)$"#).unwrap();
+    if let Some(captures) = get_path_regexp.captures(req.uri().path()) {
+        if is_meta_dir(&captures[1]) {
+            info!("not found: {}", req.uri().path());
+            return handler_not_found();
+        }
+    }
+    if (*req.method() == Method::GET || *req.method() == Method::HEAD) && !is_upload_path {
+        if let Some(captures) = get_path_regexp.captures(req.uri().path()) {
+            // a signed url is checked by get_handler instead.
+            let is_signed = query_param(req.uri().query(), "signature").is_some();
+            if opt.auth_downloads && !is_signed && !opt.is_authorized(req.headers()) {
+                warn!("unauthorized download");
+                return handler_unauthorized();
+            }
+            let fut = get_handler(
+                req.headers(),
+                req.uri().query(),
+                &opt,
+                &download_semaphore,
+                &captures[1],
+                &captures[2],
+            );
+            return match *req.method() {
+                Method::HEAD => without_body(fut),
+                _ => fut,
+            };
+        }
+    }
+    if *req.method() == Method::DELETE && !is_upload_path {
+        if let Some(captures) = get_path_regexp.captures(req.uri().path()) {
+            if !opt.is_authorized(req.headers()) {
+                warn!("unauthorized delete");
+                return handler_unauthorized();
+            }
+            return delete_handler(&opt, &captures[1], &captures[2]);
+        }
+    }
 
-                    loop {
-                        match &context.command.clone().execute(&mut context, &mut reader) {
-                            Ok(CommandRet::NextCommand) => (),
-                            Ok(CommandRet::Consumed) => break,
-                            Err(e) => {
-                                // TODO:
-                                warn!("{:?}", e);
-                            }
-                        }
-                    }
-                    return future::ok::<_, hyper::Error>(context);
-                },
-            )
-            .map(move |context| {
-                if context.command == ParseType::End {
-                    info!("success end");
-                } else {
-                    warn!(
-                        "all data received but unexpected state: {:?}",
-                        context.command
-                    );
-                }
-                let upload_result = UploadResult {
-                    part: context
-                        .processed
-                        .iter()
-                        .map(|data| UploadResultPart {
-                            name: "name".to_owned(),
-                            file_name: "file_name".to_owned(),
-                            url: format!("http://{}/{}", host, data),
-                            error: None,
-                        })
-                        .collect(),
-                    error: None,
-                };
-                let body = serde_json::to_string(&upload_result).unwrap();
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::from(body))
-                    .unwrap()
-            }),
-    )
+    match req.uri().path() {
+        path if path == opt.upload_path => handler_method_not_allowed(),
+        "/" if *req.method() == Method::GET => {
+            if opt.root_behavior == RootBehavior::List && !opt.is_authorized(req.headers()) {
+                warn!("unauthorized list");
+                return handler_unauthorized();
+            }
+            root_handler(req.uri().query(), &opt)
+        }
+        "/healthz" if *req.method() == Method::GET => healthz_handler(),
+        "/healthz" if *req.method() == Method::HEAD => without_body(healthz_handler()),
+        // path if path == "" => {}
+        _ => handler_not_found(),
+    }
 }
 
-fn handler_not_implemented() -> BoxFut {
-    Box::new(future::ok(
-        Response::builder()
-            .status(StatusCode::NOT_IMPLEMENTED)
-            .body(Body::empty())
-            .unwrap(),
-    ))
+/// Writes the access log line of `response` once its body is sent, or dropped by a disconnected
+/// client, with the bytes of the body sent so far.
+fn with_access_log(
+    response: Response<Body>,
+    log_format: LogFormat,
+    entry: AccessLogEntry,
+) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    match body.content_length() {
+        Some(0) => {
+            write_access_log(log_format, entry);
+            return Response::from_parts(parts, body);
+        }
+        Some(len) if !parts.headers.contains_key(hyper::header::CONTENT_LENGTH) => {
+            // the wrapped body has no length, keep it from being sent chunked.
+            parts
+                .headers
+                .insert(hyper::header::CONTENT_LENGTH, len.into());
+        }
+        _ => (),
+    }
+    let body = CountingBody::new(body, move |bytes| {
+        write_access_log(log_format, AccessLogEntry { bytes, ..entry })
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
 }
 
-fn handler_method_not_allowed() -> BoxFut {
-    Box::new(future::ok(
-        Response::builder()
-            .status(StatusCode::METHOD_NOT_ALLOWED)
-            .body(Body::empty())
-            .unwrap(),
-    ))
+fn write_access_log(log_format: LogFormat, entry: AccessLogEntry) {
+    // the body may end after the request future, outside of `WithRequestId`.
+    let _scope = logger::RequestIdScope::enter(&entry.request_id);
+    match log_format {
+        LogFormat::Text => info!("{}", entry.to_text()),
+        // the logger writes these as is for the log analyzers.
+        LogFormat::Combined => info!(target: logger::ACCESS_LOG_TARGET, "{}", entry.to_combined()),
+        LogFormat::Json => info!(target: logger::ACCESS_LOG_TARGET, "{}", entry.to_json()),
+    }
 }
 
-fn handler_not_found() -> BoxFut {
+/// Counts the bytes of the body, calling `on_end` with the count once the body ends or is dropped.
+struct CountingBody<F: FnOnce(u64)> {
+    inner: Body,
+    bytes: u64,
+    on_end: Option<F>,
+}
+
+impl<F: FnOnce(u64)> CountingBody<F> {
+    fn new(inner: Body, on_end: F) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            on_end: Some(on_end),
+        }
+    }
+
+    fn end(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(self.bytes);
+        }
+    }
+}
+
+impl<F: FnOnce(u64)> Stream for CountingBody<F> {
+    type Item = hyper::Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+        let ret = self.inner.poll();
+        match ret {
+            Ok(Async::Ready(Some(ref chunk))) => self.bytes += chunk.len() as u64,
+            Ok(Async::Ready(None)) => self.end(),
+            _ => (),
+        }
+        ret
+    }
+}
+
+impl<F: FnOnce(u64)> Drop for CountingBody<F> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// Reuses the `X-Request-Id` of the client if it is safe to log, otherwise generates a new one.
+fn request_id(headers: &HeaderMap) -> String {
+    match header_string(
+        headers,
+        hyper::header::HeaderName::from_static("x-request-id"),
+    ) {
+        Some(ref data)
+            if !data.is_empty()
+                && data.len() <= MAX_REQUEST_ID_LENGTH
+                && data.bytes().all(|c| c.is_ascii_graphic()) =>
+        {
+            data.to_owned()
+        }
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: hyper::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|data| data.to_str().ok())
+        .map(|data| data.to_owned())
+}
+
+/// Liveness check for the load balancers, answered without touching the data directory.
+fn healthz_handler() -> BoxFut {
     Box::new(future::ok(
         Response::builder()
-            .status(StatusCode::NOT_FOUND)
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from("ok"))
+            .unwrap(),
+    ))
+}
+
+/// Answers the CORS preflight requests of the browsers, see `--cors-origin`.
+fn cors_preflight_handler(headers: &HeaderMap) -> BoxFut {
+    let mut response = Response::builder();
+    response
+        .status(StatusCode::NO_CONTENT)
+        .header(
+            hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
+            "GET, HEAD, POST, DELETE",
+        )
+        .header(hyper::header::ACCESS_CONTROL_MAX_AGE, "86400");
+    // allows whatever the client asks for, e.g. X-TP-Filename and Idempotency-Key.
+    if let Some(data) = headers.get(hyper::header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        response.header(hyper::header::ACCESS_CONTROL_ALLOW_HEADERS, data.clone());
+    }
+    Box::new(future::ok(response.body(Body::empty()).unwrap()))
+}
+
+fn add_cors_headers(headers: &mut HeaderMap, origin: &str) {
+    // checked on startup.
+    let value = hyper::header::HeaderValue::from_str(origin).unwrap();
+    headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+}
+
+/// Answers `OPTIONS *` with the methods the server supports.
+fn options_asterisk_handler() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(hyper::header::ALLOW, "GET, HEAD, POST, DELETE, OPTIONS")
             .body(Body::empty())
             .unwrap(),
     ))
 }
 
-fn create_multipart_regexps() -> Fallible<MultipartRegexps> {
-    let boundary = Regex::new("boundary=([^;]*)")?;
-    let form_data = Regex::new("^Content-Disposition: form-data(;|$)")?;
-    let mime = Regex::new("Content-Type: (.*)$")?;
-    let content_disposition_name = Regex::new(r#"^Content-Disposition:.* name="([^"]*)"(;|\r\n)"#)?;
-    let content_disposition_filename =
-        Regex::new(r#"^Content-Disposition:.* filename="([^"]*)"(;|\r\n)"#)?;
-    Ok(MultipartRegexps {
-        boundary,
-        form_data,
-        mime,
-        content_disposition_name,
-        content_disposition_filename,
+fn root_handler(query: Option<&str>, opt: &Opt) -> BoxFut {
+    let response = match &opt.root_behavior {
+        RootBehavior::NotFound => return handler_not_found(),
+        RootBehavior::List => return list_handler(query, opt),
+        RootBehavior::Form => Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(format!(
+                r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>transfer</title></head>
+<body>
+<form action="{}" method="post" enctype="multipart/form-data">
+<input type="file" name="file" multiple>
+<input type="submit" value="Upload">
+</form>
+</body>
+</html>
+"#,
+                opt.upload_path
+                    .replace('&', "&amp;")
+                    .replace('"', "&quot;")
+                    .replace('<', "&lt;")
+            ))),
+        RootBehavior::Redirect(location) => Response::builder()
+            .status(StatusCode::FOUND)
+            .header(hyper::header::LOCATION, location.clone())
+            .body(Body::empty()),
+        RootBehavior::Text(message) => Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(message.to_owned())),
+    };
+    Box::new(future::ok(response.unwrap()))
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    id: String,
+    file_name: String,
+    size: u64,
+    /// Seconds since the epoch of the last modification.
+    created: u64,
+}
+
+/// Lists the stored files ordered by the id and the filename.
+fn list_handler(query: Option<&str>, opt: &Opt) -> BoxFut {
+    let mut offset = 0;
+    let mut limit = DEFAULT_LIST_LIMIT;
+    let params = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|data| !data.is_empty());
+    for param in params {
+        let (key, value) = match param.find('=') {
+            Some(index) => (&param[..index], &param[index + 1..]),
+            None => (param, ""),
+        };
+        let target = match key {
+            "offset" => &mut offset,
+            "limit" => &mut limit,
+            _ => continue,
+        };
+        *target = match value.parse() {
+            Ok(data) => data,
+            Err(_) => return handler_bad_request("invalid offset or limit"),
+        };
+    }
+
+    let mut entries = Vec::new();
+    let dirs = match std::fs::read_dir(&opt.data_dir) {
+        Ok(data) => data,
+        // nothing is uploaded yet.
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return list_response(entries),
+        Err(e) => {
+            warn!("failed to read {:?}: {:?}", opt.data_dir, e);
+            return handler_internal_server_error();
+        }
+    };
+    for dir in dirs.filter_map(Result::ok) {
+        let path = dir.path();
+        if !path.is_dir() || dir.file_name() == META_DIR {
+            continue;
+        }
+        let id = match dir.file_name().into_string() {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        collect_list_entries(opt, &id, &path, "", &mut entries);
+    }
+    entries.sort_by(|a, b| (&a.id, &a.file_name).cmp(&(&b.id, &b.file_name)));
+    let entries = entries
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .collect::<Vec<_>>();
+    list_response(entries)
+}
+
+/// Adds the files under `dir` including the ones in the subdirectories of `--preserve-paths`, except
+/// for the expired ones that may not be removed yet.
+fn collect_list_entries(
+    opt: &Opt,
+    id: &str,
+    dir: &Path,
+    prefix: &str,
+    entries: &mut Vec<ListEntry>,
+) {
+    let files = match std::fs::read_dir(dir) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to read {:?}: {:?}", dir, e);
+            return;
+        }
+    };
+    for file in files.filter_map(Result::ok) {
+        let name = match file.file_name().into_string() {
+            Ok(data) => format!("{}{}", prefix, data),
+            Err(_) => continue,
+        };
+        let metadata = match file.metadata() {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            collect_list_entries(opt, id, &file.path(), &format!("{}/", name), entries);
+            continue;
+        }
+        if is_partial_name(&file.file_name().to_string_lossy())
+            || is_expired(&opt.data_dir, &file.path(), opt.max_age)
+        {
+            continue;
+        }
+        let created = metadata
+            .modified()
+            .ok()
+            .and_then(|data| data.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |data| data.as_secs());
+        entries.push(ListEntry {
+            id: id.to_owned(),
+            file_name: name,
+            size: metadata.len(),
+            created,
+        });
+    }
+}
+
+/// Streams `entries` as a JSON array, serializing `LIST_CHUNK_ENTRIES` of them into each piece as
+/// the body is sent instead of the whole document at once.
+fn list_response(entries: Vec<ListEntry>) -> BoxFut {
+    let mut entries = entries.into_iter();
+    let mut written = 0;
+    let mut done = false;
+    let pieces = std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let mut piece = String::new();
+        if written == 0 {
+            piece.push('[');
+        }
+        for entry in entries.by_ref().take(LIST_CHUNK_ENTRIES) {
+            if written != 0 {
+                piece.push(',');
+            }
+            written += 1;
+            piece.push_str(&serde_json::to_string(&entry).unwrap());
+        }
+        if entries.len() == 0 {
+            piece.push(']');
+            done = true;
+        }
+        Some(piece)
+    });
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::wrap_stream(stream::iter_ok::<_, std::io::Error>(
+                pieces,
+            )))
+            .unwrap(),
+    ))
+}
+
+/// Serves the endpoints under `/api/`, all of them behind `--auth-token`.
+fn api_handler(req: Request<Body>, base_url: String, opt: Arc<Opt>) -> BoxFut {
+    if !opt.is_authorized(req.headers()) {
+        warn!("unauthorized api request");
+        return handler_unauthorized();
+    }
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/api/export.tar") => return export_handler(&opt),
+        (&Method::HEAD, "/api/export.tar") => return without_body(export_handler(&opt)),
+        _ => (),
+    }
+    let upload_path_regexp = Regex::new(r#"^/api/([^/]+)/([a-z]+)$"#).unwrap();
+    let captures = match upload_path_regexp.captures(req.uri().path()) {
+        Some(data) => data,
+        None => return handler_not_found(),
+    };
+    let file_id = match percent_decode(&captures[1]) {
+        Some(ref data) if is_path_component(data) && data != META_DIR => data.to_owned(),
+        _ => return handler_not_found(),
+    };
+    match (req.method(), &captures[2]) {
+        (&Method::POST, "rename") => rename_handler(req.into_body(), base_url, opt, file_id),
+        (&Method::POST, "sign") => sign_handler(req.uri().query(), &base_url, &opt, &file_id),
+        (_, "rename") | (_, "sign") => handler_method_not_allowed(),
+        _ => handler_not_found(),
+    }
+}
+
+#[derive(Deserialize)]
+struct RenameRequest {
+    filename: String,
+}
+
+#[derive(Serialize)]
+struct UrlResult {
+    url: String,
+}
+
+/// Renames the file of the upload `file_id` to the filename of the JSON body, within the
+/// directory of the upload, and returns its new URL. The old URL is no longer served.
+fn rename_handler(body: Body, base_url: String, opt: Arc<Opt>, file_id: String) -> BoxFut {
+    let upload_dir = opt.data_dir.join(&file_id);
+    let body = concat_body(body, Some(MAX_API_BODY_SIZE));
+    Box::new(body.and_then(move |data| -> BoxFut {
+        let request = match data {
+            Some(data) => serde_json::from_slice::<RenameRequest>(&data),
+            None => return Box::new(future::ok(payload_too_large_response())),
+        };
+        let filename = match request {
+            Ok(data) => data.filename,
+            Err(e) => {
+                warn!("invalid rename request: {:?}", e);
+                return handler_bad_request("invalid request");
+            }
+        };
+        let filename = match sanitize_path(&filename, opt.max_filename_bytes, opt.preserve_paths) {
+            Some(data) => data,
+            None => {
+                warn!("invalid filename: {}", filename);
+                return handler_bad_request("invalid filename");
+            }
+        };
+        if opt.is_denied_extension(&filename) {
+            warn!("denied extension: {}", filename);
+            return handler_unsupported_media_type("the file extension is not allowed");
+        }
+        let from = match upload_file(&upload_dir) {
+            Ok(data) => upload_dir.join(data),
+            Err(response) => return response,
+        };
+        let to = upload_dir.join(&filename);
+        let url = format!("{}/{}", base_url, download_path(&file_id, &filename));
+        if to == from {
+            return url_result_response(url);
+        }
+        if to.exists() {
+            warn!("already exists: {:?}", to);
+            return handler_conflict("the filename already exists");
+        }
+        if let Err(e) = rename_stored_file(&opt, &from, &to) {
+            warn!("failed to rename {:?}: {:?}", from, e);
+            return handler_internal_server_error();
+        }
+        info!("renamed: {:?} -> {:?}", from, to);
+        url_result_response(url)
+    }))
+}
+
+/// Returns the filename of the only file of the upload in `upload_dir`, or the response of 404 if
+/// there is none or 409 if there are several.
+fn upload_file(upload_dir: &Path) -> Result<String, BoxFut> {
+    let mut files = Vec::new();
+    if upload_dir.is_dir() {
+        collect_file_paths(upload_dir, "", &mut files);
+    }
+    if 1 < files.len() {
+        warn!("several files in {:?}", upload_dir);
+        return Err(handler_conflict("the upload has several files"));
+    }
+    files.pop().ok_or_else(|| {
+        info!("not found: {:?}", upload_dir);
+        handler_not_found()
+    })
+}
+
+/// Moves the stored file `from` with its sidecar and metadata to `to`. The caches of `from` are
+/// dropped rather than moved.
+fn rename_stored_file(opt: &Opt, from: &Path, to: &Path) -> Fallible<()> {
+    let data_dir = &opt.data_dir;
+    ensure_dir(to.parent().unwrap(), opt.dir_mode)?;
+    std::fs::rename(from, to)?;
+    let sidecar = gzip_sidecar_path(from);
+    if sidecar.is_file() {
+        std::fs::rename(&sidecar, gzip_sidecar_path(to))?;
+    }
+    let metadata = read_metadata(data_dir, from);
+    remove_meta_files(data_dir, from);
+    if let Some(metadata) = metadata {
+        write_metadata(data_dir, to, &metadata);
+    }
+    if opt.track_tombstones {
+        write_tombstone(data_dir, from);
+    }
+    remove_empty_dirs(from, data_dir);
+    Ok(())
+}
+
+fn url_result_response(url: String) -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&UrlResult { url }).unwrap(),
+            ))
+            .unwrap(),
+    ))
+}
+
+/// Returns the download URL of the file of the upload `file_id` signed with `--url-sign-key`,
+/// valid for `?ttl=` without other auth.
+fn sign_handler(query: Option<&str>, base_url: &str, opt: &Opt, file_id: &str) -> BoxFut {
+    let key = match opt.url_sign_key {
+        Some(ref data) => data,
+        None => {
+            warn!("signing urls without --url-sign-key");
+            return handler_not_found();
+        }
+    };
+    let ttl = match query_param(query, "ttl") {
+        Some(data) => match humantime::parse_duration(data.trim()) {
+            Ok(data) => data,
+            Err(_) => return handler_bad_request("invalid ttl"),
+        },
+        None => DEFAULT_SIGNED_URL_TTL,
+    };
+    let upload_dir = opt.data_dir.join(file_id);
+    let filename = match upload_file(&upload_dir) {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+    let expires = (SystemTime::now() + ttl)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let signature = url_signature(key, &format!("{}/{}", file_id, filename), expires);
+    info!("signed: {}/{} until {}", file_id, filename, expires);
+    url_result_response(format!(
+        "{}/{}?expires={}&signature={}",
+        base_url,
+        download_path(file_id, &filename),
+        expires,
+        signature
+    ))
+}
+
+/// Returns the signature of the download URL of `data/<path>` expiring at `expires` in seconds
+/// since the epoch.
+fn url_signature(key: &str, path: &str, expires: u64) -> String {
+    Sha256::hmac(key.as_bytes(), format!("{}\n{}", path, expires).as_bytes())
+}
+
+/// Returns the response of 403 if the download URL of `filepath` is signed but the signature is
+/// invalid or expired.
+fn invalid_signed_url_handler(opt: &Opt, filepath: &Path, query: Option<&str>) -> Option<BoxFut> {
+    match verify_signed_url(opt, filepath, query) {
+        Ok(_) => None,
+        Err(e) => {
+            warn!("{}: {:?}", e, filepath);
+            Some(handler_forbidden(e))
+        }
+    }
+}
+
+/// Checks the `?expires=` and the `?signature=` of the download URL of `filepath` if it is signed.
+fn verify_signed_url(opt: &Opt, filepath: &Path, query: Option<&str>) -> Result<(), &'static str> {
+    let signature = match query_param(query, "signature") {
+        Some(data) => data,
+        None => return Ok(()),
+    };
+    let key = opt
+        .url_sign_key
+        .as_ref()
+        .ok_or("signed urls are disabled")?;
+    let expires = query_param(query, "expires")
+        .and_then(|data| data.parse::<u64>().ok())
+        .ok_or("invalid expires")?;
+    let path = filepath
+        .strip_prefix(&opt.data_dir)
+        .unwrap()
+        .components()
+        .map(|data| data.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if !constant_time_eq(
+        url_signature(key, &path, expires).as_bytes(),
+        signature.as_bytes(),
+    ) {
+        return Err("invalid signature");
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    if expires <= now {
+        return Err("expired signature");
+    }
+    Ok(())
+}
+
+/// Streams a tar archive of the data directory, the stored files with `META_DIR`, that restores
+/// them when extracted into an empty data directory.
+fn export_handler(opt: &Opt) -> BoxFut {
+    let mut files = Vec::new();
+    collect_file_paths(&opt.data_dir, "", &mut files);
+    files.sort();
+    info!("export {} files", files.len());
+    let archive = ExportArchive {
+        data_dir: opt.data_dir.clone(),
+        files: files.into_iter(),
+        current: None,
+        finished: false,
+    };
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/x-tar")
+            .header(
+                hyper::header::CONTENT_DISPOSITION,
+                r#"attachment; filename="export.tar""#,
+            )
+            .body(Body::wrap_stream(stream::iter_result(archive)))
+            .unwrap(),
+    ))
+}
+
+/// Adds the paths of the files under `dir` joined to `prefix`, except for the ones being written.
+fn collect_file_paths(dir: &Path, prefix: &str, files: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to read {:?}: {:?}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let name = match entry.file_name().into_string() {
+            Ok(data) => data,
+            Err(name) => {
+                warn!("skip the export of {:?}", name);
+                continue;
+            }
+        };
+        let path = format!("{}{}", prefix, name);
+        if entry.path().is_dir() {
+            collect_file_paths(&entry.path(), &format!("{}/", path), files);
+        } else if !is_partial_name(&name) {
+            files.push(path);
+        }
+    }
+}
+
+/// The pieces of the tar archive of `export_handler`, read from the files as it is sent.
+struct ExportArchive {
+    data_dir: PathBuf,
+    /// The paths relative to `data_dir` of the files not sent yet.
+    files: std::vec::IntoIter<String>,
+    /// The file being sent, its size in the header and the bytes sent so far.
+    current: Option<(std::fs::File, u64, u64)>,
+    finished: bool,
+}
+
+impl Iterator for ExportArchive {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((mut file, size, sent)) = self.current.take() {
+            let mut buf = vec![0; std::cmp::min(DOWNLOAD_CHUNK_SIZE as u64, size - sent) as usize];
+            let len = match file.read(&mut buf) {
+                // truncated meanwhile, the rest is filled with zeros to keep the archive readable.
+                Ok(0) => buf.len(),
+                Ok(len) => len,
+                Err(e) => {
+                    warn!("failed to read: {:?}", e);
+                    return Some(Err(e));
+                }
+            };
+            buf.truncate(len);
+            let sent = sent + len as u64;
+            if sent == size {
+                buf.resize(buf.len() + tar::padding(size), 0);
+            } else {
+                self.current = Some((file, size, sent));
+            }
+            return Some(Ok(buf));
+        }
+        for path in self.files.by_ref() {
+            let filepath = self.data_dir.join(&path);
+            let opened = std::fs::File::open(&filepath)
+                .and_then(|file| file.metadata().map(|metadata| (file, metadata)));
+            let (file, metadata) = match opened {
+                Ok(data) => data,
+                // e.g. removed meanwhile.
+                Err(e) => {
+                    warn!("failed to open {:?}: {:?}", filepath, e);
+                    continue;
+                }
+            };
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|data| data.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0, |data| data.as_secs());
+            if 0 < metadata.len() {
+                self.current = Some((file, metadata.len(), 0));
+            }
+            return Some(Ok(tar::file_header(&path, metadata.len(), mtime)));
+        }
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+        Some(Ok(tar::END.to_vec()))
+    }
+}
+
+/// Drops the body of the response for HEAD requests, keeping the headers of the GET response.
+fn without_body(fut: BoxFut) -> BoxFut {
+    Box::new(fut.map(|response| {
+        let (parts, _) = response.into_parts();
+        Response::from_parts(parts, Body::empty())
+    }))
+}
+
+/// Streams `reader` in `DOWNLOAD_CHUNK_SIZE` pieces instead of reading it into memory at once.
+///
+/// With `throttle` the pieces are paced to that many bytes per second. `permit` is held until the
+/// body is sent or dropped.
+fn file_body<R: Read + Send + 'static>(
+    reader: R,
+    permit: KeyedPermit<PathBuf>,
+    throttle: Option<u64>,
+) -> Body {
+    let chunk_size = match throttle {
+        Some(rate) => std::cmp::min(DOWNLOAD_CHUNK_SIZE as u64, rate) as usize,
+        None => DOWNLOAD_CHUNK_SIZE,
+    };
+    let chunks = stream::unfold(Some((reader, permit)), move |state| {
+        let (mut reader, permit) = state?;
+        let mut buf = vec![0; chunk_size];
+        match reader.read(&mut buf) {
+            Ok(0) => None,
+            Ok(len) => {
+                buf.truncate(len);
+                Some(future::ok((
+                    hyper::Chunk::from(buf),
+                    Some((reader, permit)),
+                )))
+            }
+            Err(e) => {
+                warn!("failed to read: {:?}", e);
+                Some(future::err(e))
+            }
+        }
+    });
+    match throttle {
+        Some(rate) => Body::wrap_stream(throttle_stream(chunks, rate)),
+        None => Body::wrap_stream(chunks),
+    }
+}
+
+/// Delays each chunk until the bytes sent so far would have taken `rate` bytes per second.
+fn throttle_stream<S>(
+    chunks: S,
+    rate: u64,
+) -> impl Stream<Item = hyper::Chunk, Error = std::io::Error>
+where
+    S: Stream<Item = hyper::Chunk, Error = std::io::Error>,
+{
+    let mut start = None;
+    let mut sent = 0;
+    chunks.and_then(move |chunk| {
+        let start = *start.get_or_insert_with(Instant::now);
+        sent += chunk.len() as u64;
+        let deadline = start + Duration::from_secs_f64(sent as f64 / rate as f64);
+        Delay::new(deadline)
+            .map(|_| chunk)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    })
+}
+
+/// Returns the path of the download URL of `data/<file_id>/<filename>`, "<id>/<filename>" with each
+/// segment percent-encoded. The reverse of `stored_file_path`.
+fn download_path(file_id: &str, filename: &str) -> String {
+    std::iter::once(file_id)
+        .chain(filename.split('/'))
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Resolves the percent-encoded path segments of a file URL to `data/<file_id>/<filename>`, where
+/// `filename` may be nested in directories, e.g. "photos/2019/img.jpg".
+///
+/// Returns `None` if a decoded segment could point outside of its directory.
+fn stored_file_path(file_root: &Path, file_id: &str, filename: &str) -> Option<PathBuf> {
+    let mut ret = file_root.to_path_buf();
+    for segment in std::iter::once(file_id).chain(filename.split('/')) {
+        match percent_decode(segment) {
+            Some(ref data) if is_path_component(data) => ret.push(data),
+            _ => {
+                warn!("invalid path: {}/{}", file_id, filename);
+                return None;
+            }
+        }
+    }
+    Some(ret)
+}
+
+/// Returns whether the percent-encoded path segment of a file URL names `META_DIR`.
+fn is_meta_dir(file_id: &str) -> bool {
+    percent_decode(file_id).map_or(false, |data| data == META_DIR)
+}
+
+/// Removes `data/<file_id>/<filename>`, and the directories of the upload once they're empty.
+fn delete_handler(opt: &Opt, file_id: &str, filename: &str) -> BoxFut {
+    let file_root = &opt.data_dir;
+    let filepath = match stored_file_path(file_root, file_id, filename) {
+        Some(data) => data,
+        None => return handler_bad_request("invalid path"),
+    };
+    if !filepath.is_file() {
+        info!("not found: {:?}", filepath);
+        return handler_not_found();
+    }
+    if opt.track_tombstones {
+        write_tombstone(file_root, &filepath);
+    }
+    if let Err(e) = remove_stored_file(file_root, &filepath) {
+        warn!("failed to remove {:?}: {:?}", filepath, e);
+        return handler_internal_server_error();
+    }
+    info!("deleted: {:?}", filepath);
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+/// Returns the response of 404, or 410 with --track-tombstones, if `filepath` doesn't exist or
+/// expired.
+fn unavailable_file_handler(opt: &Opt, filepath: &Path) -> Option<BoxFut> {
+    if !filepath.is_file() {
+        if opt.track_tombstones && tombstone_path(&opt.data_dir, filepath).is_file() {
+            info!("gone: {:?}", filepath);
+            return Some(handler_gone());
+        }
+        info!("not found: {:?}", filepath);
+        return Some(handler_not_found());
+    }
+    // may not be removed yet.
+    if !is_expired(&opt.data_dir, filepath, opt.max_age) {
+        return None;
+    }
+    info!("expired: {:?}", filepath);
+    Some(if opt.track_tombstones {
+        handler_gone()
+    } else {
+        handler_not_found()
     })
 }
+
+/// Removes the stored file `filepath` with its sidecar, caches and metadata, and the directories
+/// left empty.
+fn remove_stored_file(data_dir: &Path, filepath: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(filepath)?;
+    std::fs::remove_file(gzip_sidecar_path(filepath)).ok();
+    remove_meta_files(data_dir, filepath);
+    remove_empty_dirs(filepath, data_dir);
+    Ok(())
+}
+
+/// Removes the metadata, the cached digests and the thumbnails of `filepath`, and the directories
+/// keeping them, `META_DIR` included, once they're empty.
+fn remove_meta_files(data_dir: &Path, filepath: &Path) {
+    let filename = filepath.file_name().unwrap().to_string_lossy().into_owned();
+    std::fs::remove_file(metadata_path(data_dir, filepath)).ok();
+    std::fs::remove_file(digest_cache_path(data_dir, filepath)).ok();
+    std::fs::remove_file(digest_cache_path(data_dir, &gzip_sidecar_path(filepath))).ok();
+    let cache_dir = cache_dir(data_dir, filepath);
+    if let Ok(files) = std::fs::read_dir(&cache_dir) {
+        for file in files.filter_map(Result::ok) {
+            if thumbnail::is_cache_of(&file.file_name().to_string_lossy(), &filename) {
+                std::fs::remove_file(file.path()).ok();
+            }
+        }
+    }
+    remove_empty_dirs(&cache_dir.join(&filename), data_dir);
+}
+
+/// Serves `data/<file_id>/<filename>` from the percent-encoded path segments of the URL, or its
+/// thumbnail with `?thumb=<w>x<h>`.
+fn get_handler(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    opt: &Opt,
+    download_semaphore: &Arc<KeyedSemaphore<PathBuf>>,
+    file_id: &str,
+    filename: &str,
+) -> BoxFut {
+    let filepath = match stored_file_path(&opt.data_dir, file_id, filename) {
+        Some(data) => data,
+        None => return handler_bad_request("invalid path"),
+    };
+    let filename = filepath.file_name().unwrap().to_string_lossy().into_owned();
+    let response = invalid_signed_url_handler(opt, &filepath, query)
+        .or_else(|| unavailable_file_handler(opt, &filepath));
+    if let Some(response) = response {
+        return response;
+    }
+    if let Some(dimensions) = query_param(query, "thumb") {
+        return thumbnail_handler(&opt.data_dir, &filepath, &filename, &dimensions);
+    }
+    // released when the body is sent or dropped by a disconnected client.
+    let permit = match KeyedSemaphore::try_acquire(download_semaphore, filepath.clone()) {
+        Some(data) => data,
+        None => {
+            warn!("too many downloads of {:?} in progress", filepath);
+            return handler_throttled(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many downloads in progress",
+                opt.retry_after,
+            );
+        }
+    };
+    let sidecar_path = gzip_sidecar_path(&filepath);
+    let has_sidecar = sidecar_path.is_file();
+    let serve_sidecar = has_sidecar && accepts_gzip(headers);
+    let served_path = if serve_sidecar {
+        &sidecar_path
+    } else {
+        &filepath
+    };
+    let file = std::fs::File::open(served_path);
+    let (mut file, metadata) = match file.and_then(|file| file.metadata().map(|data| (file, data)))
+    {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to open {:?}: {:?}", served_path, e);
+            return handler_internal_server_error();
+        }
+    };
+    let len = metadata.len();
+    let byte_range = match headers
+        .get(hyper::header::RANGE)
+        .and_then(|data| data.to_str().ok())
+    {
+        // the ranges of the original can't be taken from the compressed content.
+        Some(data) if !serve_sidecar => range::resolve(data, len),
+        _ => ByteRange::Full,
+    };
+    let mut builder = Response::builder();
+    if has_sidecar {
+        builder.header(hyper::header::VARY, "accept-encoding");
+    }
+    if serve_sidecar {
+        info!("serve the precompressed {:?}", sidecar_path);
+        builder.header(hyper::header::CONTENT_ENCODING, "gzip");
+        builder.header(hyper::header::ACCEPT_RANGES, "none");
+    } else {
+        builder.header(hyper::header::ACCEPT_RANGES, "bytes");
+    }
+    if let Ok(modified) = metadata.modified() {
+        builder.header(
+            hyper::header::LAST_MODIFIED,
+            format_http_date(modified).as_str(),
+        );
+    }
+    if byte_range != ByteRange::Unsatisfiable {
+        // the digest of the uploaded file as returned by the upload, whichever encoding is sent.
+        if let Some(sha256) = file_digest(&opt.data_dir, &filepath) {
+            builder.header("x-tp-sha256", sha256.as_str());
+        }
+        if let Some(metadata) = read_metadata(&opt.data_dir, &filepath) {
+            if let Some(md5) = &metadata.md5 {
+                builder.header("x-tp-md5", md5.as_str());
+            }
+            if let Some(sha1) = &metadata.sha1 {
+                builder.header("x-tp-sha1", sha1.as_str());
+            }
+        }
+        let want_digest = headers
+            .get("want-digest")
+            .and_then(|data| data.to_str().ok());
+        if digest::wants_sha256(want_digest) {
+            // the digest of the sent representation, i.e. of the compressed sidecar if served.
+            if let Some(value) =
+                file_digest(&opt.data_dir, served_path).and_then(|data| digest::header_value(&data))
+            {
+                builder.header("digest", value.as_str());
+            }
+        }
+        let content_type = content_type::for_filename(&filename);
+        builder
+            .header(hyper::header::CONTENT_TYPE, content_type)
+            // the content-type is guessed, don't let browsers guess it differently.
+            .header(hyper::header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+        if content_type::is_active(content_type) {
+            // keeps an uploaded page from running scripts on this origin, e.g. reading the
+            // other files, even if a browser opens it.
+            builder
+                .header(
+                    hyper::header::CONTENT_DISPOSITION,
+                    format!("attachment; filename*=UTF-8''{}", percent_encode(&filename)).as_str(),
+                )
+                .header(hyper::header::CONTENT_SECURITY_POLICY, "sandbox");
+        }
+    }
+    let response = match byte_range {
+        ByteRange::Full => builder
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_LENGTH, len.to_string().as_str())
+            .body(file_body(file, permit, opt.throttle)),
+        ByteRange::Partial { start, end } => {
+            if let Err(e) = file.seek(SeekFrom::Start(start)) {
+                warn!("failed to seek {:?}: {:?}", filepath, e);
+                return handler_internal_server_error();
+            }
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    hyper::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len).as_str(),
+                )
+                .header(
+                    hyper::header::CONTENT_LENGTH,
+                    (end - start + 1).to_string().as_str(),
+                )
+                .body(file_body(file.take(end - start + 1), permit, opt.throttle))
+        }
+        ByteRange::Unsatisfiable => {
+            info!(
+                "range not satisfiable: {:?}",
+                headers.get(hyper::header::RANGE)
+            );
+            builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(
+                    hyper::header::CONTENT_RANGE,
+                    format!("bytes */{}", len).as_str(),
+                )
+                .body(Body::empty())
+        }
+    };
+    Box::new(future::ok(response.unwrap()))
+}
+
+/// Returns the directory keeping the caches and the metadata of `data/<file_id>/<filename>` outside
+/// of the served files, i.e. `data/.transfer/<file_id>` and the directories of `filename` if it is
+/// nested.
+fn cache_dir(data_dir: &Path, filepath: &Path) -> PathBuf {
+    let dir = filepath.parent().unwrap();
+    // the stored files are always under the data directory.
+    data_dir
+        .join(META_DIR)
+        .join(dir.strip_prefix(data_dir).unwrap())
+}
+
+/// Returns the path caching the SHA-256 digest of `filepath`, see `file_digest`.
+fn digest_cache_path(data_dir: &Path, filepath: &Path) -> PathBuf {
+    let filename = filepath.file_name().unwrap().to_string_lossy();
+    cache_dir(data_dir, filepath).join(digest::cache_name(&filename))
+}
+
+/// Writes `data` to `cache_path` in `META_DIR`, creating its directories.
+fn write_cache(cache_path: &Path, data: &[u8]) {
+    if ensure_dir(cache_path.parent().unwrap(), None).is_err() {
+        return;
+    }
+    if let Err(e) = write_file(cache_path, data) {
+        warn!("failed to cache {:?}: {:?}", cache_path, e);
+    }
+}
+
+/// Caches `sha256`, the lowercase hex SHA-256 digest of the file just stored at `filepath`.
+fn cache_digest(data_dir: &Path, filepath: &Path, sha256: &str) {
+    write_cache(&digest_cache_path(data_dir, filepath), sha256.as_bytes());
+}
+
+/// Records the digests and the metadata of the file just stored at `filepath`.
+fn record_stored_file(
+    data_dir: &Path,
+    filepath: &Path,
+    metadata: &Metadata,
+    checksums: &Checksums,
+) {
+    cache_digest(data_dir, filepath, &checksums.sha256);
+    let mut metadata = metadata.clone();
+    metadata.set_checksums(checksums);
+    write_metadata(data_dir, filepath, &metadata);
+}
+
+/// Returns the path keeping the `Metadata` of `filepath`.
+fn metadata_path(data_dir: &Path, filepath: &Path) -> PathBuf {
+    let filename = filepath.file_name().unwrap().to_string_lossy();
+    cache_dir(data_dir, filepath).join(metadata::file_name(&filename))
+}
+
+/// Records `metadata` of the file just stored at `filepath`, replacing the one of an overwritten
+/// file, and forgets that a file of the same path was deleted.
+fn write_metadata(data_dir: &Path, filepath: &Path, metadata: &Metadata) {
+    write_cache(
+        &metadata_path(data_dir, filepath),
+        metadata.to_json().as_bytes(),
+    );
+    std::fs::remove_file(tombstone_path(data_dir, filepath)).ok();
+}
+
+/// Returns the path recording that `filepath` was deleted or expired with --track-tombstones.
+///
+/// It is kept after the other files of `META_DIR` of `filepath` are removed.
+fn tombstone_path(data_dir: &Path, filepath: &Path) -> PathBuf {
+    let filename = filepath.file_name().unwrap().to_string_lossy();
+    cache_dir(data_dir, filepath).join(metadata::tombstone_name(&filename))
+}
+
+fn write_tombstone(data_dir: &Path, filepath: &Path) {
+    write_cache(&tombstone_path(data_dir, filepath), b"");
+}
+
+fn read_metadata(data_dir: &Path, filepath: &Path) -> Option<Metadata> {
+    std::fs::read(metadata_path(data_dir, filepath))
+        .ok()
+        .and_then(|data| Metadata::from_json(&data))
+}
+
+/// Returns the metadata the upload request records for each of its files, with the max-age of the
+/// X-TP-Max-Age header and the expiry of the X-TP-Expires-In header or the `expires` query
+/// parameter (e.g. 30m, 2h, 7d), both clamped to `--max-age`.
+fn upload_metadata(
+    headers: &HeaderMap,
+    query: Option<&str>,
+    opt: &Opt,
+) -> Result<Metadata, &'static str> {
+    let parse_duration = |value: &str| humantime::parse_duration(value.trim()).ok();
+    let mut ret = Metadata::new(SystemTime::now());
+    if let Some(value) = headers.get("x-tp-max-age") {
+        match value.to_str().ok().and_then(parse_duration) {
+            Some(max_age) => ret.set_max_age(max_age, opt.max_age),
+            None => {
+                warn!("invalid max-age: {:?}", value);
+                return Err("invalid max-age");
+            }
+        }
+    }
+    let expires_in = match headers.get("x-tp-expires-in") {
+        Some(value) => Some(value.to_str().unwrap_or_default().to_owned()),
+        None => query_param(query, "expires"),
+    };
+    if let Some(value) = expires_in {
+        match parse_duration(&value) {
+            Some(expires_in) => ret.set_expires_in(expires_in, opt.max_age),
+            None => {
+                warn!("invalid expires-in: {:?}", value);
+                return Err("invalid expires-in");
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Returns the SHA-256 digest of `filepath` as a lowercase hex string.
+///
+/// The digest is cached in `META_DIR` until the file is replaced or deleted, so only files that
+/// weren't uploaded through this server, e.g. the .gz sidecars, are read to compute it.
+fn file_digest(data_dir: &Path, filepath: &Path) -> Option<String> {
+    let cache_path = digest_cache_path(data_dir, filepath);
+    // the cache is stale if the file was overwritten with --allow-key-overwrite.
+    let cached = match (
+        std::fs::metadata(filepath).and_then(|data| data.modified()),
+        cache_path.metadata().and_then(|data| data.modified()),
+    ) {
+        (Ok(source), Ok(cache)) => source <= cache,
+        _ => false,
+    };
+    if cached {
+        match std::fs::read_to_string(&cache_path) {
+            Ok(ref data) if data.len() == 64 && data.bytes().all(|c| c.is_ascii_hexdigit()) => {
+                return Some(data.to_owned());
+            }
+            _ => info!("invalid digest cache: {:?}", cache_path),
+        }
+    }
+    let mut file = match std::fs::File::open(filepath) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to open {:?}: {:?}", filepath, e);
+            return None;
+        }
+    };
+    let mut sha256 = Sha256::new();
+    let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => sha256.update(&buf[..len]),
+            Err(e) => {
+                warn!("failed to read {:?}: {:?}", filepath, e);
+                return None;
+            }
+        }
+    }
+    let ret = sha256.finish();
+    cache_digest(data_dir, filepath, &ret);
+    Some(ret)
+}
+
+/// Returns the path of the precompressed variant of `filepath`, i.e. `<filepath>.gz`.
+fn gzip_sidecar_path(filepath: &Path) -> PathBuf {
+    let mut ret = filepath.as_os_str().to_owned();
+    ret.push(".gz");
+    PathBuf::from(ret)
+}
+
+/// Returns whether the Accept-Encoding header accepts gzip with a non-zero quality.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let accept_encoding = match headers
+        .get(hyper::header::ACCEPT_ENCODING)
+        .and_then(|data| data.to_str().ok())
+    {
+        Some(data) => data,
+        None => return false,
+    };
+    let mut wildcard = false;
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let accepted = params
+            .filter_map(|param| {
+                let param = param.trim();
+                if param.starts_with("q=") || param.starts_with("Q=") {
+                    param[2..].trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .map_or(true, |q| 0.0 < q);
+        match name.as_str() {
+            "gzip" | "x-gzip" => return accepted,
+            "*" => wildcard = accepted,
+            _ => (),
+        }
+    }
+    wildcard
+}
+
+/// Returns the decoded value of `name` in the query string.
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|param| match param.find('=') {
+            Some(index) if &param[..index] == name => Some(&param[index + 1..]),
+            _ => None,
+        })
+        .next()
+        .and_then(|value| form_urlencoded_decode(value.as_bytes()))
+        .and_then(|value| String::from_utf8(value).ok())
+}
+
+/// Serves the thumbnail of the image at `filepath` fitting in `dimensions`, "<w>x<h>".
+///
+/// The thumbnail is cached in `META_DIR` until the image is replaced or deleted.
+fn thumbnail_handler(data_dir: &Path, filepath: &Path, filename: &str, dimensions: &str) -> BoxFut {
+    let (width, height) = match thumbnail::parse_dimensions(dimensions, MAX_THUMBNAIL_SIDE) {
+        Some(data) => data,
+        None => return handler_bad_request("invalid thumbnail size"),
+    };
+    if !thumbnail::is_supported(content_type::for_filename(filename)) {
+        return handler_unsupported_media_type("thumbnails are only available for images");
+    }
+    let metadata = match std::fs::metadata(filepath) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to open {:?}: {:?}", filepath, e);
+            return handler_internal_server_error();
+        }
+    };
+    let cache_path =
+        cache_dir(data_dir, filepath).join(thumbnail::cache_name(filename, width, height));
+    // the cache is stale if the image was overwritten with --allow-key-overwrite.
+    let cached = match (
+        metadata.modified(),
+        cache_path.metadata().and_then(|data| data.modified()),
+    ) {
+        (Ok(source), Ok(cache)) => source <= cache,
+        _ => false,
+    };
+    let data = if cached {
+        std::fs::read(&cache_path).ok()
+    } else {
+        None
+    };
+    let data = match data {
+        Some(data) => data,
+        None => {
+            if MAX_THUMBNAIL_SOURCE_SIZE < metadata.len() {
+                return handler_unsupported_media_type("too large image for a thumbnail");
+            }
+            let source = match std::fs::read(filepath) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("failed to read {:?}: {:?}", filepath, e);
+                    return handler_internal_server_error();
+                }
+            };
+            let data = match thumbnail::generate(&source, width, height) {
+                Some(data) => data,
+                None => {
+                    info!("failed to generate a thumbnail: {:?}", filepath);
+                    return handler_unsupported_media_type("unsupported image");
+                }
+            };
+            // served anyway if it fails, the next request will try again.
+            write_cache(&cache_path, &data);
+            data
+        }
+    };
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, thumbnail::content_type(&data))
+            .header(hyper::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+            .header(
+                hyper::header::CONTENT_LENGTH,
+                data.len().to_string().as_str(),
+            )
+            .body(Body::from(data))
+            .unwrap(),
+    ))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ParseType {
+    LoadBoundary,
+    LoadContentDescription,
+    Body,
+    End,
+}
+
+#[derive(Debug, PartialEq)]
+enum CommandRet {
+    NextCommand,
+    Consumed,
+}
+
+/// A file stored by an upload request.
+struct StoredFile {
+    /// The form field name of the part.
+    name: String,
+    /// The filename sent by the client.
+    original_filename: String,
+    file_id: String,
+    /// The filename the file is stored as.
+    filename: String,
+    /// The digests of the stored content.
+    checksums: Checksums,
+}
+
+impl StoredFile {
+    /// Returns "<id>/<filename>" for the download URL.
+    fn path(&self) -> String {
+        download_path(&self.file_id, &self.filename)
+    }
+}
+
+/// A file rejected by an upload request.
+struct RejectedFile {
+    /// The form field name of the part.
+    name: String,
+    /// The filename sent by the client.
+    original_filename: String,
+    error: String,
+}
+
+/// Decides the filenames of the files of an upload request and remembers the rejected ones.
+struct UploadFilenames {
+    filenames: Vec<String>,
+    rejected: Vec<RejectedFile>,
+    user_key: Option<String>,
+    file_root: PathBuf,
+    opt: Arc<Opt>,
+}
+
+impl UploadFilenames {
+    fn new(user_key: Option<String>, file_root: PathBuf, opt: Arc<Opt>) -> Self {
+        Self {
+            filenames: Default::default(),
+            rejected: Default::default(),
+            user_key,
+            file_root,
+            opt,
+        }
+    }
+
+    /// Returns the id to store the next file under.
+    fn file_id(&self, id_generator: &dyn IdGenerator) -> String {
+        self.user_key
+            .clone()
+            .unwrap_or_else(|| id_generator.generate())
+    }
+
+    /// Remembers the file of the field `name` sent as `original_filename` as rejected.
+    fn reject(&mut self, name: &str, original_filename: &str, error: &str) {
+        self.rejected.push(RejectedFile {
+            name: name.to_owned(),
+            original_filename: original_filename.to_owned(),
+            error: error.to_owned(),
+        });
+    }
+
+    /// Applies the empty filename policy, the sanitization, the denied extensions and the duplicate
+    /// filename policy and returns the filename to store the file of the field `name` as, or
+    /// `None` if the file is rejected.
+    fn accept(&mut self, name: &str, original_filename: &str) -> Option<String> {
+        let filename = if !original_filename.is_empty() {
+            original_filename
+        } else {
+            match self.opt.empty_filename_policy {
+                EmptyFilenamePolicy::Generate => DEFAULT_FILENAME,
+                EmptyFilenamePolicy::Reject => {
+                    warn!("empty filename");
+                    self.reject(name, original_filename, "empty filename");
+                    return None;
+                }
+            }
+        };
+        let filename: &str = &match sanitize_path(
+            filename,
+            self.opt.max_filename_bytes,
+            self.opt.preserve_paths,
+        ) {
+            Some(data) => data,
+            None => {
+                warn!("invalid filename: {}", filename);
+                self.reject(name, original_filename, "invalid filename");
+                return None;
+            }
+        };
+        if self.opt.is_denied_extension(filename) {
+            warn!("denied extension: {}", filename);
+            self.reject(name, original_filename, "denied extension");
+            return None;
+        }
+        let filename = if !self.filenames.iter().any(|data| data == filename) {
+            filename.to_owned()
+        } else {
+            match self.opt.duplicate_filename_policy {
+                DuplicateFilenamePolicy::Separate => filename.to_owned(),
+                DuplicateFilenamePolicy::Rename => (1..)
+                    .map(|index| {
+                        insert_suffix(
+                            filename,
+                            &format!("-{}", index),
+                            self.opt.max_filename_bytes,
+                        )
+                    })
+                    .find(|candidate| !self.filenames.contains(candidate))
+                    .unwrap(),
+                DuplicateFilenamePolicy::Reject => {
+                    warn!("duplicate filename: {}", filename);
+                    self.reject(name, original_filename, "duplicate filename");
+                    return None;
+                }
+            }
+        };
+        if let Some(ref user_key) = self.user_key {
+            if !self.opt.allow_key_overwrite
+                && self.file_root.join(user_key).join(&filename).exists()
+            {
+                warn!("key already exists: {}/{}", user_key, filename);
+                self.reject(name, original_filename, "key already exists");
+                return None;
+            }
+        }
+        self.filenames.push(filename.clone());
+        Some(filename)
+    }
+}
+
+struct ParseMultipartContext {
+    boundary: String,
+    command: ParseType,
+    name: Option<String>,
+    file_id: Option<String>,
+    filename: Option<String>,
+    processed: Vec<StoredFile>,
+    field_value: Option<Vec<u8>>,
+    fields: Vec<UploadResultField>,
+    names: UploadFilenames,
+    error: Option<(StatusCode, String)>,
+    file_writer: Option<BufWriter<std::fs::File>>,
+    partial_path: Option<PathBuf>,
+    hasher: Hasher,
+    buffer: Vec<u8>,
+    regexps: Arc<MultipartRegexps>,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    body_skip_crlf: bool,
+    part_size: u64,
+    received: u64,
+    /// Recorded for each stored file.
+    metadata: Metadata,
+    file_root: PathBuf,
+    opt: Arc<Opt>,
+}
+
+impl ParseMultipartContext {
+    fn new(
+        boundary: String,
+        regexps: Arc<MultipartRegexps>,
+        id_generator: Arc<dyn IdGenerator + Send + Sync>,
+        user_key: Option<String>,
+        metadata: Metadata,
+        file_root: PathBuf,
+        opt: Arc<Opt>,
+    ) -> Self {
+        Self {
+            boundary,
+            command: ParseType::LoadBoundary,
+            name: Default::default(),
+            file_id: Default::default(),
+            filename: Default::default(),
+            processed: Default::default(),
+            field_value: Default::default(),
+            fields: Default::default(),
+            names: UploadFilenames::new(user_key, file_root.clone(), opt.clone()),
+            error: Default::default(),
+            file_writer: Default::default(),
+            partial_path: Default::default(),
+            hasher: Hasher::new(&opt.checksums),
+            buffer: Default::default(),
+            regexps,
+            id_generator,
+            body_skip_crlf: Default::default(),
+            part_size: Default::default(),
+            received: Default::default(),
+            metadata,
+            file_root,
+            opt,
+        }
+    }
+
+    /// Closes the file of the current part, or keeps the value of the current form field.
+    fn finish_part(&mut self) {
+        if let Some(value) = self.field_value.take() {
+            self.fields.push(UploadResultField {
+                name: self.name.clone().unwrap_or_default(),
+                value: String::from_utf8_lossy(&value).into_owned(),
+            });
+            return;
+        }
+        let hasher = std::mem::replace(&mut self.hasher, Hasher::new(&self.opt.checksums));
+        let mut checksums = hasher.finish();
+        let mut writer = None;
+        std::mem::swap(&mut writer, &mut self.file_writer);
+        let partial = self.partial_path.take();
+        if let (Some(mut writer), Some(partial)) = (writer, partial) {
+            if let Err(e) = writer.flush() {
+                warn!("failed to write file: {:?}", e);
+                self.partial_path = Some(partial);
+                self.abort(StatusCode::INTERNAL_SERVER_ERROR, "failed to write file");
+                return;
+            }
+            drop(writer);
+            if self.opt.strip_exif {
+                if let Some(data) = strip_file_metadata(&partial) {
+                    checksums = Hasher::digest(&self.opt.checksums, &data);
+                }
+            }
+            if let (Some(file_id), Some(filename)) = (&self.file_id, &self.filename) {
+                let filepath = self.file_root.join(file_id).join(filename);
+                if let Err(e) = std::fs::rename(&partial, &filepath) {
+                    warn!("failed to rename {:?}: {:?}", partial, e);
+                    self.partial_path = Some(partial);
+                    self.abort(StatusCode::INTERNAL_SERVER_ERROR, "failed to write file");
+                    return;
+                }
+                record_stored_file(&self.file_root, &filepath, &self.metadata, &checksums);
+            }
+        }
+        if self.file_id.is_some() {
+            // the current part is always the last stored one.
+            if let Some(part) = self.processed.last_mut() {
+                part.checksums = checksums;
+            }
+        }
+    }
+
+    /// Removes the partial file of the current part.
+    fn discard_partial(&mut self) {
+        self.file_writer = None;
+        if let Some(partial) = self.partial_path.take() {
+            if let Err(e) = std::fs::remove_file(&partial) {
+                warn!("failed to remove {:?}: {:?}", partial, e);
+            }
+        }
+    }
+
+    /// Fails the whole request with `status` and `error` and removes the files stored so far.
+    fn abort(&mut self, status: StatusCode, error: &str) {
+        self.discard_partial();
+        self.field_value = None;
+        if let (Some(file_id), Some(filename)) = (self.file_id.take(), self.filename.as_ref()) {
+            // the current part is not renamed to its path yet, which may be a file of the same
+            // key uploaded before.
+            self.processed.pop();
+            remove_empty_dirs(
+                &self.file_root.join(file_id).join(filename),
+                &self.file_root,
+            );
+        }
+        self.filename = None;
+        let mut stored = Vec::new();
+        std::mem::swap(&mut stored, &mut self.processed);
+        for data in stored {
+            let filepath = self.file_root.join(&data.file_id).join(&data.filename);
+            if filepath.exists() {
+                if let Err(e) = std::fs::remove_file(&filepath) {
+                    warn!("failed to remove {:?}: {:?}", filepath, e);
+                }
+            }
+            remove_meta_files(&self.file_root, &filepath);
+            remove_empty_dirs(&filepath, &self.file_root);
+        }
+        self.error = Some((status, error.to_owned()));
+    }
+
+    /// Discards the current part including the data written so far and reports `error` for it.
+    fn reject_part(&mut self, error: &str) {
+        self.discard_partial();
+        let file_id = self.file_id.take();
+        let filename = self.filename.take();
+        if file_id.is_none() || filename.is_none() {
+            return;
+        }
+        // the current part is always the last stored one.
+        if let Some(part) = self.processed.pop() {
+            let filepath = self.file_root.join(&part.file_id).join(&part.filename);
+            remove_empty_dirs(&filepath, &self.file_root);
+            self.names
+                .reject(&part.name, &part.original_filename, error);
+        }
+    }
+
+    /// Applies `--max-part-size` to the content buffered while a line of the current part waits
+    /// for its CRLF, and stops buffering the line once the part is rejected.
+    fn check_incomplete_line(&mut self) {
+        // a shorter line may still turn out to be a delimiter.
+        let delimiter_len = self.boundary.len() + 6;
+        if self.buffer.len() <= delimiter_len {
+            return;
+        }
+        if let (Some(max_part_size), true) = (self.opt.max_part_size, self.file_id.is_some()) {
+            let mut pending = self.buffer.len() as u64;
+            if self.body_skip_crlf {
+                pending += 2;
+            }
+            // the CR may begin the CRLF preceding a delimiter.
+            if self.buffer.ends_with(b"\r") {
+                pending -= 1;
+            }
+            if max_part_size < self.part_size + pending {
+                warn!("part exceeds {} bytes", max_part_size);
+                self.reject_part("part too large");
+            }
+        }
+        if self.file_id.is_none() && self.field_value.is_none() {
+            // the line is discarded, and longer than a delimiter whatever follows. a trailing CR
+            // is kept for the CRLF ending the line.
+            let cr = self.buffer.ends_with(b"\r");
+            self.buffer.truncate(delimiter_len + 1);
+            if cr {
+                self.buffer.push(b'\r');
+            }
+        }
+    }
+}
+
+/// Removes the files of an upload that is dropped before it completes, i.e. the client disconnected
+/// or `--max-upload-time` passed.
+impl Drop for ParseMultipartContext {
+    fn drop(&mut self) {
+        if self.command != ParseType::End && self.error.is_none() {
+            warn!("multipart upload was not completed");
+            self.abort(StatusCode::BAD_REQUEST, "upload not completed");
+        }
+    }
+}
+
+/// Reads a CRLF terminated line of the multipart body.
+///
+/// The line is decoded by the caller only after it is complete since a chunk may end in the middle
+/// of a line, between the CR and the LF or in the middle of a multibyte character. Returns `None`
+/// when the chunk is exhausted, keeping the incomplete line in `context.buffer` for the next chunk.
+fn read_line(
+    context: &mut ParseMultipartContext,
+    reader: &mut BufReader<&[u8]>,
+) -> Fallible<Option<Vec<u8>>> {
+    loop {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {
+                context.buffer.extend(line);
+                if context.buffer.ends_with(b"\r\n") {
+                    let mut ret_line = Vec::new();
+                    ret_line.append(&mut context.buffer);
+                    return Ok(Some(ret_line));
+                }
+            }
+            Err(e) => return Err(format_err!("failed to read line: {:?}", e)),
+        }
+    }
+}
+
+trait ParseMultipartCommand {
+    fn execute(
+        &self,
+        context: &mut ParseMultipartContext,
+        reader: &mut BufReader<&[u8]>,
+    ) -> Fallible<CommandRet>;
+}
+
+impl ParseMultipartCommand for ParseType {
+    #[allow(clippy::cognitive_complexity)]
+    fn execute(
+        &self,
+        context: &mut ParseMultipartContext,
+        reader: &mut BufReader<&[u8]>,
+    ) -> Fallible<CommandRet> {
+        match &self {
+            ParseType::LoadBoundary => {
+                let line = match read_line(context, reader)? {
+                    Some(line) => line,
+                    None => return Ok(CommandRet::Consumed),
+                };
+
+                match String::from_utf8(line) {
+                    Ok(s) => {
+                        info!("boundary: '{}'", context.boundary);
+                        info!("s len: {}, val: '{}'", s.len(), s);
+                        if s == format!("--{}\r\n", context.boundary) {
+                            info!("boundary consumed");
+                            context.command = ParseType::LoadContentDescription;
+                            Ok(CommandRet::NextCommand)
+                        } else {
+                            // the declared boundary doesn't match the body.
+                            warn!("failed to consume a boundary");
+                            context.error = Some((
+                                StatusCode::BAD_REQUEST,
+                                "first boundary not found".to_owned(),
+                            ));
+                            Ok(CommandRet::Consumed)
+                        }
+                    }
+                    Err(e) => {
+                        warn!("invalid header encoding: {:?}", e);
+                        context.abort(StatusCode::BAD_REQUEST, "invalid header encoding");
+                        Ok(CommandRet::Consumed)
+                    }
+                }
+            }
+            ParseType::LoadContentDescription => {
+                let line = match read_line(context, reader)? {
+                    Some(line) => line,
+                    None => {
+                        info!("empty");
+                        return Ok(CommandRet::Consumed);
+                    }
+                };
+                if line == b"\r\n" {
+                    context.command = ParseType::Body;
+                    return Ok(CommandRet::NextCommand);
+                }
+
+                match String::from_utf8(line) {
+                    Ok(s) => {
+                        let reg_formdata = &context.regexps.form_data;
+                        let reg_mime = &context.regexps.mime;
+                        if reg_formdata.is_match(&s) {
+                            info!("ContentDescription: '{}'", s);
+                            if let Some(name) =
+                                context.regexps.content_disposition_name.captures(&s)
+                            {
+                                match name.get(1) {
+                                    Some(name) => context.name = Some(name.as_str().to_owned()),
+                                    None => {
+                                        return Err(format_err!(
+                                            "malformed content-disposition header"
+                                        ))
+                                    }
+                                }
+                            }
+                            match context.regexps.content_disposition_filename.captures(&s) {
+                                Some(filename) => match filename.get(1) {
+                                    Some(original_filename) => {
+                                        let original_filename = original_filename.as_str();
+                                        let name = context.name.clone().unwrap_or_default();
+                                        if let Some(filename) =
+                                            context.names.accept(&name, original_filename)
+                                        {
+                                            let file_id = context
+                                                .names
+                                                .file_id(context.id_generator.as_ref());
+                                            context.file_id = Some(file_id.clone());
+                                            context.filename = Some(filename.clone());
+                                            context.processed.push(StoredFile {
+                                                name,
+                                                original_filename: original_filename.to_owned(),
+                                                file_id,
+                                                filename,
+                                                checksums: Default::default(),
+                                            });
+                                        }
+                                    }
+                                    None => {
+                                        return Err(format_err!(
+                                            "malformed content-disposition header"
+                                        ))
+                                    }
+                                },
+                                None if context.opt.require_filename => {
+                                    warn!("missing filename: {:?}", context.name);
+                                    context.abort(StatusCode::BAD_REQUEST, "missing filename");
+                                    return Ok(CommandRet::Consumed);
+                                }
+                                None => context.field_value = Some(vec![]),
+                            }
+                            info!("name: {:?}, filename: {:?}", context.name, context.filename);
+                            Ok(CommandRet::NextCommand)
+                        } else if let Some(data) = reg_mime.captures(&s) {
+                            match data.get(1) {
+                                Some(data) => {
+                                    info!("ContentDescription mime: '{}'", data.as_str());
+                                    Ok(CommandRet::NextCommand)
+                                }
+                                None => Err(format_err!("malformed content-type header")),
+                            }
+                        } else {
+                            info!("ContentDescription (ignored): '{}'", s);
+                            Ok(CommandRet::NextCommand)
+                        }
+                    }
+                    Err(e) => {
+                        warn!("invalid header encoding: {:?}", e);
+                        context.abort(StatusCode::BAD_REQUEST, "invalid header encoding");
+                        Ok(CommandRet::Consumed)
+                    }
+                }
+            }
+            ParseType::Body => {
+                let mut line = match read_line(context, reader)? {
+                    Some(line) => line,
+                    None => {
+                        context.check_incomplete_line();
+                        return Ok(CommandRet::Consumed);
+                    }
+                };
+                if line == format!("--{}\r\n", context.boundary).as_bytes() {
+                    info!("match separator");
+                    context.finish_part();
+                    if context.error.is_some() {
+                        return Ok(CommandRet::Consumed);
+                    }
+                    context.name = None;
+                    context.file_id = None;
+                    context.filename = None;
+                    context.command = ParseType::LoadContentDescription;
+                    context.body_skip_crlf = false;
+                    context.part_size = 0;
+                    Ok(CommandRet::NextCommand)
+                } else if line == format!("--{}--\r\n", context.boundary).as_bytes() {
+                    info!("match end");
+                    context.finish_part();
+                    if context.error.is_some() {
+                        return Ok(CommandRet::Consumed);
+                    }
+                    context.command = ParseType::End;
+                    Ok(CommandRet::NextCommand)
+                } else {
+                    info!("body.len: '{}'", line.len());
+                    // the CRLF preceding a delimiter belongs to the delimiter, so the CRLF of a
+                    // line is written only after the next line turns out to be the content.
+                    line.truncate(line.len() - 2);
+                    if context.body_skip_crlf {
+                        line.splice(0..0, b"\r\n".iter().cloned());
+                    }
+                    context.body_skip_crlf = true;
+                    if let Some(max_part_size) = context.opt.max_part_size {
+                        context.part_size += line.len() as u64;
+                        if max_part_size < context.part_size && context.file_id.is_some() {
+                            warn!("part exceeds {} bytes", max_part_size);
+                            context.reject_part("part too large");
+                        }
+                    }
+                    if let Some(ref mut value) = context.field_value {
+                        if MAX_FIELD_SIZE < value.len() + line.len() {
+                            warn!("form field exceeds {} bytes", MAX_FIELD_SIZE);
+                            context.abort(StatusCode::PAYLOAD_TOO_LARGE, "form field too large");
+                            return Ok(CommandRet::Consumed);
+                        }
+                        value.extend(line);
+                        return Ok(CommandRet::NextCommand);
+                    }
+                    let writer = match context.file_writer {
+                        Some(ref mut writer) => writer,
+                        None => {
+                            let (file_id, filename) =
+                                match (context.file_id.as_ref(), context.filename.as_ref()) {
+                                    (Some(file_id), Some(filename)) => (file_id, filename),
+                                    _ => {
+                                        debug!("discard body of the rejected part");
+                                        return Ok(CommandRet::NextCommand);
+                                    }
+                                };
+                            let filepath = context.file_root.join(file_id).join(filename);
+                            if ensure_dir(filepath.parent().unwrap(), context.opt.dir_mode).is_err()
+                            {
+                                context.abort(
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    "failed to create directory",
+                                );
+                                return Ok(CommandRet::Consumed);
+                            }
+                            let partial = partial_path(&filepath);
+                            context.file_writer = match std::fs::File::create(&partial) {
+                                Ok(file) => Some(BufWriter::new(file)),
+                                Err(e) => {
+                                    warn!("failed to open {:?}: {:?}", partial, e);
+                                    context.abort(
+                                        StatusCode::INTERNAL_SERVER_ERROR,
+                                        "failed to open file",
+                                    );
+                                    return Ok(CommandRet::Consumed);
+                                }
+                            };
+                            context.partial_path = Some(partial);
+                            context.file_writer.as_mut().unwrap()
+                        }
+                    };
+                    match writer.write_all(&line) {
+                        Ok(_) => {
+                            context.hasher.update(&line);
+                            Ok(CommandRet::NextCommand)
+                        }
+                        Err(e) => {
+                            warn!("failed to write file: {:?}", e);
+                            context
+                                .abort(StatusCode::INTERNAL_SERVER_ERROR, "failed to write file");
+                            Ok(CommandRet::Consumed)
+                        }
+                    }
+                }
+            }
+            ParseType::End => Ok(CommandRet::Consumed),
+        }
+    }
+}
+
+fn upload_handler(
+    req: Request<Body>,
+    base_url: String,
+    multipart_regexps: Arc<MultipartRegexps>,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    multipart_semaphore: Arc<Semaphore>,
+    opt: Arc<Opt>,
+) -> BoxFut {
+    if let Some(max_upload_size) = opt.max_upload_size {
+        let content_length = req
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|data| data.to_str().ok())
+            .and_then(|data| data.parse::<u64>().ok());
+        if let Some(content_length) = content_length {
+            if max_upload_size < content_length {
+                warn!("content-length exceeds {} bytes", max_upload_size);
+                return Box::new(future::ok(payload_too_large_response()));
+            }
+        }
+    }
+
+    if let Some(content_type) = req.headers().get(hyper::header::CONTENT_TYPE) {
+        if let Ok(content_type) = content_type.to_str() {
+            if content_type.contains("multipart/form-data") {
+                if opt.disable_multipart {
+                    return handler_unsupported_media_type(
+                        "multipart/form-data uploads are disabled, send the file as a raw body instead",
+                    );
+                }
+                let permit = match Semaphore::try_acquire(&multipart_semaphore) {
+                    Some(data) => data,
+                    None => {
+                        warn!("too many multipart uploads in progress");
+                        return handler_throttled(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "too many uploads in progress",
+                            opt.retry_after,
+                        );
+                    }
+                };
+                // curl -F myfile=@$HOME/path/to/file
+                return upload_handler_multipart(
+                    req,
+                    base_url,
+                    multipart_regexps,
+                    id_generator,
+                    permit,
+                    opt,
+                );
+            } else if content_type::essence(content_type) == "application/x-www-form-urlencoded" {
+                // curl --data-urlencode name@file --data-urlencode name@file
+                // name=<encoded>&name=<encoded>
+                // curl --data-urlencode @file --data-urlencode @file
+                // <encoded>&<encoded>
+                return upload_handler_urlencoded(req, base_url, id_generator, opt);
+            }
+        }
+    }
+
+    // curl -H "Content-Type: application/octet-stream" --data-binary @$HOME/path/to/file
+    // curl -H "Content-Type: image/png" --data-binary @$HOME/path/to/file
+    // curl -H "Content-Type: foobar/baz" --data-binary @$HOME/path/to/file
+    if opt.disable_octet_stream {
+        return handler_unsupported_media_type(
+            "raw body uploads are disabled, send the file as multipart/form-data instead",
+        );
+    }
+    upload_handler_file(req, base_url, id_generator, opt)
+}
+
+fn with_max_upload_time(fut: BoxFut, max_upload_time: Option<u64>) -> BoxFut {
+    let max_upload_time = match max_upload_time {
+        Some(data) => Duration::from_secs(data),
+        None => return fut,
+    };
+    Box::new(Timeout::new(fut, max_upload_time).or_else(move |e| {
+        if e.is_elapsed() {
+            warn!("upload was not completed within {:?}", max_upload_time);
+            return Ok(Response::builder()
+                .status(StatusCode::REQUEST_TIMEOUT)
+                .body(Body::from("upload timed out"))
+                .unwrap());
+        }
+        match e.into_inner() {
+            Some(e) => Err(e),
+            None => {
+                warn!("timer error");
+                Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap())
+            }
+        }
+    }))
+}
+
+/// Remembers the successful response for `key` to replay it to retried uploads.
+fn with_idempotency_key(fut: BoxFut, key: IdempotencyKey, cache: Arc<IdempotencyCache>) -> BoxFut {
+    Box::new(fut.and_then(move |response| {
+        let (parts, body) = response.into_parts();
+        body.concat2().map(move |body| {
+            cache.insert(key, parts.status, &parts.headers, &body);
+            Response::from_parts(parts, Body::from(body))
+        })
+    }))
+}
+
+/// Collects the body, or resolves to `None` once it exceeds `max_size` bytes.
+///
+/// The rest of an oversized body is read and discarded.
+fn concat_body(
+    body: Body,
+    max_size: Option<u64>,
+) -> impl Future<Item = Option<Vec<u8>>, Error = hyper::Error> {
+    body.fold(Some(Vec::new()), move |data, chunk| {
+        let data = data.and_then(|mut data| {
+            data.extend_from_slice(&chunk);
+            match max_size {
+                Some(max_size) if max_size < data.len() as u64 => {
+                    warn!("upload exceeds {} bytes", max_size);
+                    None
+                }
+                _ => Some(data),
+            }
+        });
+        future::ok::<_, hyper::Error>(data)
+    })
+}
+
+fn upload_handler_file(
+    req: Request<Body>,
+    base_url: String,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    opt: Arc<Opt>,
+) -> BoxFut {
+    let file_root = opt.data_dir.clone();
+    let (head, body) = req.into_parts();
+    let format = ResultFormat::from_headers(&head.headers);
+    let mut filename = match head
+        .headers
+        .get("x-tp-filename")
+        .and_then(|data| data.to_str().ok())
+    {
+        Some(filename) => filename.to_owned(),
+        None if opt.require_filename => {
+            warn!("missing filename");
+            return handler_bad_request("missing filename");
+        }
+        None => DEFAULT_FILENAME.to_owned(),
+    };
+    if filename.is_empty() {
+        match opt.empty_filename_policy {
+            EmptyFilenamePolicy::Generate => filename = DEFAULT_FILENAME.to_owned(),
+            EmptyFilenamePolicy::Reject => {
+                warn!("empty filename");
+                return handler_bad_request("empty filename");
+            }
+        }
+    }
+    if opt.append_extension && std::path::Path::new(&filename).extension().is_none() {
+        let ext = head
+            .headers
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|data| data.to_str().ok())
+            .and_then(content_type::extension_for);
+        if let Some(ext) = ext {
+            info!("append extension: {}", ext);
+            filename = format!("{}.{}", filename, ext);
+        }
+    }
+    let filename = match sanitize_path(&filename, opt.max_filename_bytes, opt.preserve_paths) {
+        Some(data) => data,
+        None => {
+            warn!("invalid filename: {}", filename);
+            return handler_bad_request("invalid filename");
+        }
+    };
+    if opt.is_denied_extension(&filename) {
+        warn!("denied extension: {}", filename);
+        return handler_unsupported_media_type("the file extension is not allowed");
+    }
+    let user_key = match user_key(&head.headers, &opt) {
+        Ok(data) => data,
+        Err(e) => return handler_bad_request(e),
+    };
+    let metadata = match upload_metadata(&head.headers, head.uri.query(), &opt) {
+        Ok(data) => data,
+        Err(e) => return handler_bad_request(e),
+    };
+    if let Some(ref user_key) = user_key {
+        let filepath = file_root.join(user_key).join(&filename);
+        if !opt.allow_key_overwrite && filepath.exists() {
+            warn!("key already exists: {}/{}", user_key, filename);
+            return handler_conflict("key already exists");
+        }
+    }
+    let dir_mode = opt.dir_mode;
+    let strip_exif = opt.strip_exif;
+    let algorithms = opt.checksums.clone();
+    let body = concat_body(body, opt.max_upload_size);
+    Box::new(body.map(move |data| {
+        let data = match data {
+            Some(data) => data,
+            None => return payload_too_large_response(),
+        };
+        let file_id = user_key.unwrap_or_else(|| id_generator.generate());
+        let filepath = file_root.join(&file_id).join(&filename);
+        if ensure_dir(filepath.parent().unwrap(), dir_mode).is_err() {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("failed to create directory"))
+                .unwrap();
+        }
+        let stripped = if strip_exif && data.len() <= MAX_STRIP_EXIF_SIZE {
+            strip_metadata(&data)
+        } else {
+            None
+        };
+        let data = match stripped {
+            Some(stripped) => {
+                info!("stripped image metadata");
+                stripped
+            }
+            None => data,
+        };
+        let checksums = Hasher::digest(&algorithms, &data);
+        match write_file(&filepath, &data) {
+            Ok(_) => {
+                info!("wrote");
+                record_stored_file(&file_root, &filepath, &metadata, &checksums);
+                let upload_result = UploadResult {
+                    part: vec![UploadResultPart {
+                        name: "name".to_owned(),
+                        file_name: filepath.file_name().unwrap().to_str().unwrap().to_owned(),
+                        url: format!("{}/{}", base_url, download_path(&file_id, &filename)),
+                        sha256: Some(checksums.sha256),
+                        md5: checksums.md5,
+                        sha1: checksums.sha1,
+                        error: None,
+                    }],
+                    field: vec![],
+                    error: None,
+                };
+                upload_result_response(StatusCode::OK, &upload_result, format)
+            }
+            Err(e) => {
+                info!("err: {:?}", e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        }
+    }))
+}
+
+/// Stores each field of the form as a file named after the field name, or "a-<n>" for the n-th
+/// field without a name.
+fn upload_handler_urlencoded(
+    req: Request<Body>,
+    base_url: String,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    opt: Arc<Opt>,
+) -> BoxFut {
+    let file_root = opt.data_dir.clone();
+    let user_key = match user_key(req.headers(), &opt) {
+        Ok(data) => data,
+        Err(e) => return handler_bad_request(e),
+    };
+    let metadata = match upload_metadata(req.headers(), req.uri().query(), &opt) {
+        Ok(data) => data,
+        Err(e) => return handler_bad_request(e),
+    };
+    let format = ResultFormat::from_headers(req.headers());
+    let body = concat_body(req.into_body(), opt.max_upload_size);
+    Box::new(body.map(move |data| {
+        let data = match data {
+            Some(data) => data,
+            None => return payload_too_large_response(),
+        };
+        let mut names = UploadFilenames::new(user_key, file_root.clone(), opt.clone());
+        let mut processed = Vec::new();
+        let fields = data
+            .split(|data| *data == b'&')
+            .filter(|data| !data.is_empty());
+        for (index, field) in fields.enumerate() {
+            let (name, value) = match field.iter().position(|data| *data == b'=') {
+                Some(position) => (
+                    form_urlencoded_decode(&field[..position])
+                        .and_then(|data| String::from_utf8(data).ok()),
+                    &field[position + 1..],
+                ),
+                None => (Some(format!("{}-{}", DEFAULT_FILENAME, index + 1)), field),
+            };
+            let name = match name {
+                Some(data) => data,
+                None => {
+                    warn!("invalid field name");
+                    names.reject("", "", "invalid field name");
+                    continue;
+                }
+            };
+            let value = match form_urlencoded_decode(value) {
+                Some(data) => data,
+                None => {
+                    warn!("invalid field value: {}", name);
+                    names.reject(&name, &name, "invalid field value");
+                    continue;
+                }
+            };
+            let filename = match names.accept(&name, &name) {
+                Some(data) => data,
+                None => continue,
+            };
+            let file_id = names.file_id(id_generator.as_ref());
+            let filepath = file_root.join(&file_id).join(&filename);
+            let value = if opt.strip_exif && value.len() <= MAX_STRIP_EXIF_SIZE {
+                strip_metadata(&value).unwrap_or(value)
+            } else {
+                value
+            };
+            let checksums = Hasher::digest(&opt.checksums, &value);
+            let ret = ensure_dir(filepath.parent().unwrap(), opt.dir_mode)
+                .and_then(|_| write_file(&filepath, &value).map_err(failure::Error::from));
+            if let Err(e) = ret {
+                warn!("failed to write {:?}: {:?}", filepath, e);
+                names.reject(&name, &name, "failed to write");
+                continue;
+            }
+            record_stored_file(&file_root, &filepath, &metadata, &checksums);
+            processed.push(StoredFile {
+                name: name.clone(),
+                original_filename: name,
+                file_id,
+                filename,
+                checksums,
+            });
+        }
+        let upload_result = UploadResult::new(&base_url, &processed, &names.rejected);
+        upload_result_response(StatusCode::OK, &upload_result, format)
+    }))
+}
+
+fn upload_handler_multipart(
+    req: Request<Body>,
+    base_url: String,
+    multipart_regexps: Arc<MultipartRegexps>,
+    id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    permit: Permit,
+    opt: Arc<Opt>,
+) -> BoxFut {
+    let file_root = opt.data_dir.clone();
+    let reg = &multipart_regexps.boundary;
+
+    let content_type = match req.headers().get(hyper::header::CONTENT_TYPE) {
+        Some(data) => match data.to_str() {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("invalid content-type: {:?}", e);
+                return handler_bad_request("invalid content-type");
+            }
+        },
+        None => {
+            warn!("missing content-type");
+            return handler_bad_request("missing content-type");
+        }
+    };
+
+    let boundary = match reg.captures(content_type).and_then(|cap| cap.get(1)) {
+        Some(boundary) if !boundary.as_str().is_empty() => boundary.as_str().to_owned(),
+        _ => {
+            warn!("missing multipart boundary: {}", content_type);
+            return handler_bad_request("missing multipart boundary");
+        }
+    };
+    let user_key = match user_key(req.headers(), &opt) {
+        Ok(data) => data,
+        Err(e) => return handler_bad_request(e),
+    };
+    let metadata = match upload_metadata(req.headers(), req.uri().query(), &opt) {
+        Ok(data) => data,
+        Err(e) => return handler_bad_request(e),
+    };
+    let format = ResultFormat::from_headers(req.headers());
+    Box::new(
+        req.into_body()
+            .fold(
+                ParseMultipartContext::new(
+                    boundary,
+                    multipart_regexps.clone(),
+                    id_generator,
+                    user_key,
+                    metadata,
+                    file_root,
+                    opt,
+                ),
+                move |mut context, data| {
+                    debug!("chunk size: {}", data.len());
+                    let data_len = data.len();
+                    let mut buf = Vec::new();
+                    std::mem::swap(&mut context.buffer, &mut buf);
+                    buf.extend(data);
+                    let mut reader = BufReader::new(buf.as_slice());
+
+                    if context.command == ParseType::End {
+                        warn!("parsetype is end but received chunk");
+                        return future::ok::<_, hyper::Error>(context);
+                    }
+
+                    context.received += data_len as u64;
+                    if let Some(max_upload_size) = context.opt.max_upload_size {
+                        if max_upload_size < context.received && context.error.is_none() {
+                            warn!("upload exceeds {} bytes", max_upload_size);
+                            context.abort(StatusCode::PAYLOAD_TOO_LARGE, "upload too large");
+                        }
+                    }
+
+                    if context.error.is_some() {
+                        debug!("discard chunk after error");
+                        return future::ok::<_, hyper::Error>(context);
+                    }
+
+                    loop {
+                        match &context.command.clone().execute(&mut context, &mut reader) {
+                            Ok(CommandRet::NextCommand) => (),
+                            Ok(CommandRet::Consumed) => break,
+                            Err(e) => {
+                                warn!("failed to parse multipart: {:?}", e);
+                                context.abort(StatusCode::BAD_REQUEST, &e.to_string());
+                                break;
+                            }
+                        }
+                    }
+                    future::ok::<_, hyper::Error>(context)
+                },
+            )
+            .map(move |mut context| {
+                drop(permit);
+                if context.error.is_none() && context.command != ParseType::End {
+                    if context.buffer == format!("--{}--", context.boundary).as_bytes() {
+                        // the CRLF after the close delimiter is optional.
+                        context.finish_part();
+                        context.command = ParseType::End;
+                    } else {
+                        warn!(
+                            "all data received but unexpected state: {:?}",
+                            context.command
+                        );
+                        context.abort(StatusCode::BAD_REQUEST, "incomplete multipart body");
+                    }
+                }
+                if let Some((status, ref error)) = context.error {
+                    let upload_result = UploadResult {
+                        part: vec![],
+                        field: vec![],
+                        error: Some(error.to_owned()),
+                    };
+                    return upload_result_response(status, &upload_result, format);
+                }
+                info!("success end");
+                let mut upload_result =
+                    UploadResult::new(&base_url, &context.processed, &context.names.rejected);
+                std::mem::swap(&mut upload_result.field, &mut context.fields);
+                upload_result_response(StatusCode::OK, &upload_result, format)
+            }),
+    )
+}
+
+/// Returns the scheme and authority that clients reach this server with.
+fn public_base_url(
+    headers: &HeaderMap,
+    remote_addr: IpAddr,
+    local_addr: SocketAddr,
+    opt: &Opt,
+) -> String {
+    if let Some(ref url) = opt.public_url {
+        return url.trim_end_matches('/').to_owned();
+    }
+    format!(
+        "{}://{}",
+        public_scheme(headers, remote_addr, opt),
+        public_host(headers, remote_addr, local_addr, opt)
+    )
+}
+
+/// Returns https when serving TLS, otherwise the scheme a trusted proxy tells or `--default-scheme`.
+fn public_scheme(headers: &HeaderMap, remote_addr: IpAddr, opt: &Opt) -> String {
+    // the client connection is HTTPS whatever a proxy in front of this server claims.
+    if opt.tls_cert.is_some() {
+        return "https".to_owned();
+    }
+    if opt.is_trusted_proxy(remote_addr) {
+        let proto = forwarded_header(headers, opt).proto.or_else(|| {
+            last_list_value(headers, "x-forwarded-proto").map(|data| data.to_ascii_lowercase())
+        });
+        if let Some(proto) = proto {
+            if proto == "http" || proto == "https" {
+                return proto;
+            }
+            warn!("ignore unknown forwarded proto: {}", proto);
+        }
+    }
+    match opt.default_scheme {
+        Some(ref scheme) => scheme.to_owned(),
+        None => "http".to_owned(),
+    }
+}
+
+fn public_host(
+    headers: &HeaderMap,
+    remote_addr: IpAddr,
+    local_addr: SocketAddr,
+    opt: &Opt,
+) -> String {
+    if opt.is_trusted_proxy(remote_addr) {
+        if let Some(host) = forwarded_header(headers, opt).host {
+            return host;
+        }
+        if let Some(host) = last_list_value(headers, "x-forwarded-host") {
+            return host;
+        }
+    }
+    match headers
+        .get(hyper::header::HOST)
+        .and_then(|data| data.to_str().ok())
+    {
+        Some(host) if !host.is_empty() => host.to_owned(),
+        _ => {
+            // HTTP/1.0 clients may omit the Host header.
+            warn!("missing host header, use the local address");
+            if local_addr.ip().is_unspecified() {
+                // the loopback one is the only known address when listening on all of them.
+                format!("localhost:{}", local_addr.port())
+            } else {
+                local_addr.to_string()
+            }
+        }
+    }
+}
+
+/// Returns the key given by the X-TP-Key header to store the upload under if `--allow-user-keys`
+/// is set.
+fn user_key(headers: &HeaderMap, opt: &Opt) -> Result<Option<String>, &'static str> {
+    let key = match headers.get("x-tp-key") {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    if !opt.allow_user_keys {
+        warn!("ignore x-tp-key since user keys are not allowed");
+        return Ok(None);
+    }
+    match key.to_str() {
+        Ok(key) if is_path_component(key) && key != META_DIR && key != API_DIR => {
+            Ok(Some(key.to_owned()))
+        }
+        _ => {
+            warn!("invalid key: {:?}", key);
+            Err("invalid key")
+        }
+    }
+}
+
+/// Returns the address of the client, i.e. the peer address unless the peer is a trusted proxy.
+fn client_ip(headers: &HeaderMap, remote_addr: IpAddr, opt: &Opt) -> IpAddr {
+    if !opt.is_trusted_proxy(remote_addr) {
+        return remote_addr;
+    }
+    let forwarded_for = forwarded_header(headers, opt)
+        .client_ip()
+        .or_else(|| x_forwarded_for(headers, opt));
+    forwarded_for.unwrap_or(remote_addr)
+}
+
+/// Returns the element of the Forwarded headers of a trusted peer that describes the client.
+///
+/// The client can send the header with forged elements that proxies append to, so the elements are
+/// walked from the closest proxy and the first one whose `for` isn't a trusted proxy is taken.
+fn forwarded_header(headers: &HeaderMap, opt: &Opt) -> Forwarded {
+    let mut elements = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|data| data.to_str().ok())
+        .flat_map(Forwarded::parse)
+        .collect::<Vec<_>>();
+    while let Some(element) = elements.pop() {
+        match element.client_ip() {
+            Some(addr) if opt.is_trusted_proxy(addr) && !elements.is_empty() => continue,
+            _ => return element,
+        }
+    }
+    Forwarded::default()
+}
+
+/// Returns the client address of the X-Forwarded-For headers of a trusted peer, walking the
+/// addresses from the closest proxy as `forwarded_header` does.
+fn x_forwarded_for(headers: &HeaderMap, opt: &Opt) -> Option<IpAddr> {
+    let addrs = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|data| data.to_str().ok())
+        .flat_map(|data| data.split(','))
+        .map(|data| data.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    let mut ret = None;
+    for addr in addrs.into_iter().rev() {
+        match addr {
+            Some(addr) => {
+                ret = Some(addr);
+                if !opt.is_trusted_proxy(addr) {
+                    break;
+                }
+            }
+            // the addresses before a malformed one can't be told apart from forged ones.
+            None => break,
+        }
+    }
+    ret
+}
+
+/// Returns the last value of a comma separated header, i.e. the one set by the closest proxy.
+fn last_list_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|data| data.to_str().ok())
+        .flat_map(|data| data.split(','))
+        .map(|data| data.trim())
+        .filter(|data| !data.is_empty())
+        .last()
+        .map(|data| data.to_owned())
+}
+
+fn upload_result_response(
+    status: StatusCode,
+    upload_result: &UploadResult,
+    format: ResultFormat,
+) -> Response<Body> {
+    let (content_type, body) = match format {
+        ResultFormat::Json => (
+            "application/json",
+            serde_json::to_string(upload_result).unwrap(),
+        ),
+        ResultFormat::Xml => ("application/xml", upload_result.to_xml()),
+    };
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Creates the directory and its parents, and applies the `--dir-mode` permission to the directory
+/// and the parents it created.
+fn ensure_dir(path: &Path, mode: Option<u32>) -> Fallible<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        if let Some(mode) = mode {
+            builder.mode(mode);
+        }
+        let created_parents = path
+            .ancestors()
+            .skip(1)
+            .take_while(|data| !data.as_os_str().is_empty() && !data.exists())
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        let ret = builder.create(path).and_then(|_| match mode {
+            // DirBuilder::mode is still masked by the umask.
+            Some(mode) => std::iter::once(path)
+                .chain(created_parents.iter().map(PathBuf::as_path))
+                .try_for_each(|data| {
+                    std::fs::set_permissions(data, std::fs::Permissions::from_mode(mode))
+                }),
+            None => Ok(()),
+        });
+        if let Err(e) = ret {
+            warn!("failed to create directory: {:?}, {:?}", path, e);
+            return Err(format_err!("failed to create directory: {:?}", e));
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if mode.is_some() {
+            warn!("--dir-mode is not supported on this platform");
+        }
+        if let Err(e) = builder.create(path) {
+            warn!("failed to create directory: {:?}, {:?}", path, e);
+            return Err(format_err!("failed to create directory: {:?}", e));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the empty directories of `filepath` up to `file_root`.
+///
+/// Other files may share the directories with X-TP-Key or --preserve-paths.
+fn remove_empty_dirs(filepath: &Path, file_root: &Path) {
+    for dir in filepath.ancestors().skip(1) {
+        if dir == file_root || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Returns a path next to `filepath` to write its content to until the content is complete.
+///
+/// The name is random and fixed-length, so it fits in NAME_MAX whatever `filepath` is, and keeps
+/// the partial file from being downloaded.
+fn partial_path(filepath: &Path) -> PathBuf {
+    filepath.with_file_name(format!(".{:016x}.partial", rand::random::<u64>()))
+}
+
+/// Returns whether `name` is a name returned by `partial_path`.
+fn is_partial_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    // ".<16 hex>.partial"
+    bytes.len() == 25
+        && bytes[0] == b'.'
+        && bytes[1..17].iter().all(|c| c.is_ascii_hexdigit())
+        && name.ends_with(".partial")
+}
+
+/// Writes `data` to `filepath` through a partial file so that `filepath` is never incomplete.
+fn write_file(filepath: &Path, data: &[u8]) -> std::io::Result<()> {
+    let partial = partial_path(filepath);
+    let ret = std::fs::write(&partial, data).and_then(|_| std::fs::rename(&partial, filepath));
+    if ret.is_err() {
+        std::fs::remove_file(&partial).ok();
+    }
+    ret
+}
+
+/// Rewrites the image without the metadata, see `--strip-exif`.
+fn strip_file_metadata(path: &Path) -> Option<Vec<u8>> {
+    match std::fs::metadata(path) {
+        Ok(data) if data.len() <= MAX_STRIP_EXIF_SIZE as u64 => (),
+        _ => return None,
+    }
+    let stripped = match std::fs::read(path) {
+        Ok(data) => strip_metadata(&data)?,
+        Err(e) => {
+            warn!("failed to read {:?}: {:?}", path, e);
+            return None;
+        }
+    };
+    match std::fs::write(path, &stripped) {
+        Ok(_) => {
+            info!("stripped image metadata: {:?}", path);
+            Some(stripped)
+        }
+        Err(e) => {
+            warn!("failed to write {:?}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+fn handler_not_implemented() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NOT_IMPLEMENTED)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+fn handler_method_not_allowed() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+fn handler_bad_request(message: &'static str) -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(message))
+            .unwrap(),
+    ))
+}
+
+fn handler_unauthorized() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+/// Compares without returning early so that the time taken doesn't tell how much of a secret
+/// matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn handler_forbidden(message: &'static str) -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(message))
+            .unwrap(),
+    ))
+}
+
+fn handler_conflict(message: &'static str) -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(Body::from(message))
+            .unwrap(),
+    ))
+}
+
+/// Responds to a request rejected by a limit with the seconds to wait before retrying.
+fn handler_throttled(status: StatusCode, message: &'static str, retry_after: u64) -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(status)
+            .header(hyper::header::RETRY_AFTER, retry_after.to_string().as_str())
+            .body(Body::from(message))
+            .unwrap(),
+    ))
+}
+
+fn handler_unsupported_media_type(message: &'static str) -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body(Body::from(message))
+            .unwrap(),
+    ))
+}
+
+fn handler_internal_server_error() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+fn payload_too_large_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Body::from("upload too large"))
+        .unwrap()
+}
+
+fn handler_gone() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::GONE)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+fn handler_not_found() -> BoxFut {
+    Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    ))
+}
+
+fn create_multipart_regexps() -> Fallible<MultipartRegexps> {
+    let boundary = Regex::new("boundary=([^;]*)")?;
+    let form_data = Regex::new("^Content-Disposition: form-data(;|$)")?;
+    let mime = Regex::new("Content-Type: (.*)$")?;
+    let content_disposition_name = Regex::new(r#"^Content-Disposition:.* name="([^"]*)"(;|\r\n)"#)?;
+    let content_disposition_filename =
+        Regex::new(r#"^Content-Disposition:.* filename="([^"]*)"(;|\r\n)"#)?;
+    Ok(MultipartRegexps {
+        boundary,
+        form_data,
+        mime,
+        content_disposition_name,
+        content_disposition_filename,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A data directory removed on drop.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("transfer-rs-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// Handles requests with the state `main` would share, storing the files in a temporary
+    /// directory.
+    struct TestServer {
+        dir: TestDir,
+        multipart_regexps: Arc<MultipartRegexps>,
+        id_generator: Arc<dyn IdGenerator + Send + Sync>,
+        idempotency_cache: Arc<IdempotencyCache>,
+        multipart_semaphore: Arc<Semaphore>,
+        download_semaphore: Arc<KeyedSemaphore<PathBuf>>,
+        opt: Arc<Opt>,
+        runtime: tokio::runtime::current_thread::Runtime,
+    }
+
+    const CLIENT_ADDR: ([u8; 4], u16) = ([192, 0, 2, 1], 50000);
+    const SERVER_ADDR: ([u8; 4], u16) = ([192, 0, 2, 80], 8080);
+
+    impl TestServer {
+        fn new(args: &[&str]) -> Self {
+            let dir = TestDir::new();
+            let data_dir = dir.0.to_str().unwrap().to_owned();
+            let opt = Opt::from_iter_safe(
+                ["transfer", "--port", "0", "--data-dir", &data_dir]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+            Self {
+                dir,
+                multipart_regexps: Arc::new(create_multipart_regexps().unwrap()),
+                id_generator: Arc::from(opt.id_scheme.generator()),
+                idempotency_cache: Arc::new(IdempotencyCache::new(
+                    Duration::from_secs(opt.idempotency_ttl),
+                    opt.idempotency_capacity,
+                )),
+                multipart_semaphore: Arc::new(Semaphore::new(
+                    opt.max_multipart_parses.unwrap_or_else(usize::max_value),
+                )),
+                download_semaphore: new_download_semaphore(&opt),
+                opt: Arc::new(opt),
+                runtime: tokio::runtime::current_thread::Runtime::new().unwrap(),
+            }
+        }
+
+        fn data_dir(&self) -> &Path {
+            &self.dir.0
+        }
+
+        fn send(&mut self, req: Request<Body>) -> Response<Vec<u8>> {
+            self.send_from(SocketAddr::from(CLIENT_ADDR), req)
+        }
+
+        fn send_from(&mut self, remote_addr: SocketAddr, req: Request<Body>) -> Response<Vec<u8>> {
+            let response = self.respond(remote_addr, req);
+            let (parts, body) = response.into_parts();
+            let body = self.runtime.block_on(body.concat2()).unwrap();
+            Response::from_parts(parts, body.to_vec())
+        }
+
+        /// Returns the response without reading its body.
+        fn respond(&mut self, remote_addr: SocketAddr, req: Request<Body>) -> Response<Body> {
+            let client = client_ip(req.headers(), remote_addr.ip(), &self.opt);
+            let fut = route(
+                req,
+                remote_addr,
+                SocketAddr::from(SERVER_ADDR),
+                client,
+                self.multipart_regexps.clone(),
+                self.id_generator.clone(),
+                self.idempotency_cache.clone(),
+                self.multipart_semaphore.clone(),
+                self.download_semaphore.clone(),
+                self.opt.clone(),
+            );
+            self.runtime.block_on(fut).unwrap()
+        }
+
+        /// Uploads `data` as a raw body named `filename` and returns the response.
+        fn upload(&mut self, filename: &str, data: &[u8]) -> Response<Vec<u8>> {
+            self.send(raw_upload(filename, data))
+        }
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path)
+            .header(hyper::header::HOST, "example.com")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn json(response: &Response<Vec<u8>>) -> serde_json::Value {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    /// Returns the path of the URL of the `index`th part of an upload response.
+    fn url_path(response: &Response<Vec<u8>>, index: usize) -> String {
+        let url = json(response)["part"][index]["url"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        url.trim_start_matches("http://example.com").to_owned()
+    }
+
+    fn raw_upload(filename: &str, data: &[u8]) -> Request<Body> {
+        Request::post("/upload")
+            .header(hyper::header::HOST, "example.com")
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header("x-tp-filename", filename)
+            .body(Body::from(data.to_vec()))
+            .unwrap()
+    }
+
+    const BOUNDARY: &str = "XyZ-boundary";
+
+    /// Builds a multipart/form-data body of (name, filename, content) parts.
+    fn multipart_body(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, filename, content) in parts {
+            body.extend(format!("--{}\r\n", BOUNDARY).bytes());
+            match filename {
+                Some(filename) => body.extend(
+                    format!(
+                        "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                        name, filename
+                    )
+                    .bytes(),
+                ),
+                None => body.extend(
+                    format!("Content-Disposition: form-data; name=\"{}\"\r\n", name).bytes(),
+                ),
+            }
+            body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend(format!("--{}--\r\n", BOUNDARY).bytes());
+        body
+    }
+
+    /// Builds a multipart upload request sending `chunks` as separate chunks of the body.
+    fn multipart_upload(chunks: Vec<Vec<u8>>) -> Request<Body> {
+        Request::post("/upload")
+            .header(hyper::header::HOST, "example.com")
+            .header(
+                hyper::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY).as_str(),
+            )
+            .body(Body::wrap_stream(stream::iter_ok::<_, std::io::Error>(
+                chunks,
+            )))
+            .unwrap()
+    }
+
+    #[test]
+    fn disable_multipart() {
+        let mut server = TestServer::new(&["--disable-multipart"]);
+        let body = multipart_body(&[("file", Some("a.txt"), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(String::from_utf8_lossy(response.body()).contains("raw body"));
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+
+        let response = server.upload("a.txt", b"hello");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn disable_octet_stream() {
+        let mut server = TestServer::new(&["--disable-octet-stream"]);
+        let response = server.upload("a.txt", b"hello");
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(String::from_utf8_lossy(response.body()).contains("multipart/form-data"));
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+
+        let body = multipart_body(&[("file", Some("a.txt"), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn disable_both_upload_styles() {
+        let mut server = TestServer::new(&["--disable-multipart", "--disable-octet-stream"]);
+        let response = server.upload("a.txt", b"hello");
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body = multipart_body(&[("file", Some("a.txt"), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn thumbnail() {
+        let mut server = TestServer::new(&[]);
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(64, 32)
+            .write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+        let response = server.upload("a.png", &png);
+        let path = url_path(&response, 0);
+
+        for _ in 0..2 {
+            // the second request is served from the cache.
+            let response = server.send(get(&format!("{}?thumb=16x16", path)));
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], "image/png");
+            let thumbnail = image::load_from_memory(response.body()).unwrap();
+            assert_eq!(image::GenericImageView::dimensions(&thumbnail), (16, 8));
+        }
+
+        for size in &["0x16", "16", "16x99999"] {
+            let response = server.send(get(&format!("{}?thumb={}", path, size)));
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", size);
+        }
+
+        let response = server.send(Request::delete(path.as_str()).body(Body::empty()).unwrap());
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn caches_are_not_served() {
+        let mut server = TestServer::new(&["--root-behavior", "list", "--allow-user-keys"]);
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(64, 32)
+            .write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+        let path = url_path(&server.upload("a.png", &png), 0);
+        let response = server.send(get(&format!("{}?thumb=16x16", path)));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // only the upload is in its directory.
+        let upload_dir = server.data_dir().join(path.split('/').nth(1).unwrap());
+        let names = upload_dir
+            .read_dir()
+            .unwrap()
+            .map(|data| data.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.png"]);
+        let entries = json(&server.send(get("/")));
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+
+        // the digest, the metadata and the thumbnail.
+        let caches = stored_files(&server.data_dir().join(META_DIR));
+        assert_eq!(caches.len(), 3);
+        for cache in &caches {
+            let relative = cache.strip_prefix(server.data_dir()).unwrap();
+            let relative = relative.to_str().unwrap();
+            for path in &[format!("/{}", relative), format!("/%2E{}", &relative[1..])] {
+                assert_eq!(server.send(get(path)).status(), StatusCode::NOT_FOUND);
+                assert_eq!(server.send(delete(path)).status(), StatusCode::NOT_FOUND);
+            }
+            assert!(cache.exists());
+        }
+
+        let mut req = raw_upload("a.txt", b"hello");
+        req.headers_mut()
+            .insert("x-tp-key", META_DIR.parse().unwrap());
+        assert_eq!(server.send(req).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn thumbnail_of_non_image() {
+        let mut server = TestServer::new(&[]);
+        let response = server.upload("a.txt", b"hello");
+        let path = url_path(&response, 0);
+        let response = server.send(get(&format!("{}?thumb=16x16", path)));
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = server.upload("b.png", b"not a png");
+        let path = url_path(&response, 0);
+        let response = server.send(get(&format!("{}?thumb=16x16", path)));
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn append_extension() {
+        let mut server = TestServer::new(&["--append-extension"]);
+        let upload = |filename: &str, content_type: &str| {
+            Request::post("/upload")
+                .header(hyper::header::HOST, "example.com")
+                .header(hyper::header::CONTENT_TYPE, content_type)
+                .header("x-tp-filename", filename)
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+
+        let response = server.send(upload("photo", "image/jpeg"));
+        assert!(url_path(&response, 0).ends_with("/photo.jpg"));
+        // the existing extension is kept.
+        let response = server.send(upload("photo.bin", "image/jpeg"));
+        assert!(url_path(&response, 0).ends_with("/photo.bin"));
+        // no canonical extension.
+        let response = server.send(upload("photo", "application/octet-stream"));
+        assert!(url_path(&response, 0).ends_with("/photo"));
+
+        let mut server = TestServer::new(&[]);
+        let response = server.send(upload("photo", "image/jpeg"));
+        assert!(url_path(&response, 0).ends_with("/photo"));
+    }
+
+    /// Uploads two parts named "a.txt" and a third named `filename` and returns the response.
+    fn upload_duplicates(server: &mut TestServer, filename: &str) -> Response<Vec<u8>> {
+        let body = multipart_body(&[
+            ("file", Some("a.txt"), b"first"),
+            ("file", Some("a.txt"), b"second"),
+            ("file", Some(filename), b"third"),
+        ]);
+        server.send(multipart_upload(vec![body]))
+    }
+
+    #[test]
+    fn duplicate_filename_policy_separate() {
+        let mut server = TestServer::new(&[]);
+        let response = upload_duplicates(&mut server, "b.txt");
+        let first = url_path(&response, 0);
+        let second = url_path(&response, 1);
+        assert!(first.ends_with("/a.txt"));
+        assert!(second.ends_with("/a.txt"));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn duplicate_filename_policy_rename() {
+        let mut server = TestServer::new(&["--duplicate-filename-policy", "rename"]);
+        let response = upload_duplicates(&mut server, "a-1.txt");
+        assert!(url_path(&response, 0).ends_with("/a.txt"));
+        assert!(url_path(&response, 1).ends_with("/a-1.txt"));
+        assert!(url_path(&response, 2).ends_with("/a-1-1.txt"));
+    }
+
+    #[test]
+    fn duplicate_filename_policy_rename_keeps_max_filename_bytes() {
+        let mut server = TestServer::new(&[
+            "--duplicate-filename-policy",
+            "rename",
+            "--max-filename-bytes",
+            "16",
+        ]);
+        let filename = "b".repeat(20);
+        let body = multipart_body(&[
+            ("file", Some(&filename), b"first"),
+            ("file", Some(&filename), b"second"),
+        ]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert!(url_path(&response, 0).ends_with(&format!("/{}", "b".repeat(16))));
+        assert!(url_path(&response, 1).ends_with(&format!("/{}-1", "b".repeat(14))));
+    }
+
+    #[test]
+    fn duplicate_filename_policy_rename_preserve_paths() {
+        let mut server =
+            TestServer::new(&["--duplicate-filename-policy", "rename", "--preserve-paths"]);
+        let body = multipart_body(&[
+            ("file", Some("v1.2/a"), b"first"),
+            ("file", Some("v1.2/a"), b"second"),
+        ]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert!(url_path(&response, 0).ends_with("/v1.2/a"));
+        assert!(url_path(&response, 1).ends_with("/v1.2/a-1"));
+    }
+
+    #[test]
+    fn duplicate_filename_policy_reject() {
+        let mut server = TestServer::new(&["--duplicate-filename-policy", "reject"]);
+        let response = upload_duplicates(&mut server, "b.txt");
+        let json = json(&response);
+        let parts = json["part"].as_array().unwrap();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0]["url"].as_str().unwrap().ends_with("/a.txt"));
+        assert!(parts[1]["url"].as_str().unwrap().ends_with("/b.txt"));
+        assert_eq!(parts[2]["file_name"], "a.txt");
+        assert_eq!(parts[2]["error"], "duplicate filename");
+        assert!(parts[1]["sha256"].is_string());
+        assert!(parts[2]["sha256"].is_null());
+    }
+
+    /// Returns the paths of the files under `dir` except for the caches in `META_DIR`.
+    fn stored_files(dir: &Path) -> Vec<PathBuf> {
+        let mut ret = Vec::new();
+        for entry in dir.read_dir().unwrap().filter_map(Result::ok) {
+            if entry.file_name() == META_DIR {
+                continue;
+            } else if entry.path().is_dir() {
+                ret.extend(stored_files(&entry.path()));
+            } else {
+                ret.push(entry.path());
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn max_upload_size_raw() {
+        let mut server = TestServer::new(&["--max-upload-size", "1k"]);
+        let response = server.upload("a.txt", &[b'a'; 1025]);
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(stored_files(server.data_dir()).is_empty());
+
+        let response = server.upload("a.txt", &[b'a'; 1024]);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(stored_files(server.data_dir()).len(), 1);
+    }
+
+    #[test]
+    fn max_upload_size_multipart() {
+        let mut server = TestServer::new(&["--max-upload-size", "1k"]);
+        let body = multipart_body(&[
+            ("a", Some("a b.txt"), &[b'a'; 600]),
+            ("b", Some("b.txt"), &[b'b'; 600]),
+        ]);
+        let chunks = body.chunks(100).map(|data| data.to_vec()).collect();
+        let response = server.send(multipart_upload(chunks));
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(server.data_dir().read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn max_upload_time_aborts_slow_body() {
+        let mut server = TestServer::new(&["--max-upload-time", "1"]);
+        let (sender, receiver) = futures::sync::mpsc::unbounded::<Vec<u8>>();
+        let mut body = multipart_body(&[("file", Some("a.txt"), b"hello")]);
+        // the rest of the body never arrives.
+        body.truncate(body.len() - 10);
+        sender.unbounded_send(body).unwrap();
+        let req = Request::post("/upload")
+            .header(hyper::header::HOST, "example.com")
+            .header(
+                hyper::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY).as_str(),
+            )
+            .body(Body::wrap_stream(receiver.map_err(|_| {
+                std::io::Error::from(std::io::ErrorKind::BrokenPipe)
+            })))
+            .unwrap();
+
+        let started = Instant::now();
+        let response = server.send(req);
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(Duration::from_secs(1) <= started.elapsed());
+        assert!(stored_files(server.data_dir()).is_empty());
+        drop(sender);
+    }
+
+    #[test]
+    fn max_upload_time_allows_fast_body() {
+        let mut server = TestServer::new(&["--max-upload-time", "1"]);
+        let response = server.upload("a.txt", b"hello");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn upload_result_xml() {
+        let mut server = TestServer::new(&[]);
+        let mut req = raw_upload("a&<b>.txt", b"hello");
+        req.headers_mut().insert(
+            hyper::header::ACCEPT,
+            "text/html, application/xml;q=0.9".parse().unwrap(),
+        );
+        let response = server.send(req);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "application/xml"
+        );
+        let body = String::from_utf8(response.body().clone()).unwrap();
+        assert!(body.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?><upload_result><part>"#));
+        assert!(body.contains("<name>name</name><file_name>a&amp;&lt;b&gt;.txt</file_name><url>"));
+        assert!(body.contains(&format!("<sha256>{}</sha256>", Sha256::digest(b"hello"))));
+        assert!(body.ends_with("</part></upload_result>"));
+    }
+
+    #[test]
+    fn result_format_from_accept() {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(hyper::header::ACCEPT, accept.parse().unwrap());
+            ResultFormat::from_headers(&headers)
+        };
+        assert_eq!(
+            ResultFormat::from_headers(&HeaderMap::new()),
+            ResultFormat::Json
+        );
+        assert_eq!(format("text/xml"), ResultFormat::Xml);
+        assert_eq!(
+            format("application/json, application/xml"),
+            ResultFormat::Json
+        );
+        assert_eq!(format("*/*"), ResultFormat::Json);
+    }
+
+    /// Uploads the files of `parts` with the multipart body split at `offsets` and asserts that each
+    /// file is stored as sent.
+    fn assert_chunked_upload(
+        server: &mut TestServer,
+        parts: &[(&str, Option<&str>, &[u8])],
+        offsets: &[usize],
+    ) {
+        let body = multipart_body(parts);
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for &offset in offsets.iter().chain(Some(&body.len())) {
+            chunks.push(body[start..offset].to_vec());
+            start = offset;
+        }
+        let response = server.send(multipart_upload(chunks));
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", offsets);
+        for (index, (_, _, content)) in parts.iter().enumerate() {
+            let response = server.send(get(&url_path(&response, index)));
+            assert_eq!(response.body().as_slice(), *content, "{:?}", offsets);
+        }
+    }
+
+    #[test]
+    fn multipart_split_at_every_offset() {
+        let mut server = TestServer::new(&[]);
+        let parts: &[(&str, Option<&str>, &[u8])] = &[
+            (
+                "file",
+                Some("a.bin"),
+                b"x\r\n--XyZ-boundar\r\n--XyZ-boundary-not\r\n--XyZ-boundary--x\r\ny",
+            ),
+            ("file", Some("b.txt"), b"second"),
+        ];
+        let len = multipart_body(parts).len();
+        for offset in 1..len {
+            assert_chunked_upload(&mut server, parts, &[offset]);
+        }
+    }
+
+    #[test]
+    fn multipart_split_crlf_at_end_of_part() {
+        let mut server = TestServer::new(&[]);
+        let parts: &[(&str, Option<&str>, &[u8])] = &[
+            ("file", Some("a.txt"), b"data\r\n"),
+            ("file", Some("b.txt"), b"data\r"),
+            ("file", Some("c.txt"), b"\r\n\r\n"),
+            ("file", Some("d.txt"), b""),
+        ];
+        let body = multipart_body(parts);
+        // every offset around the CRLFs at the ends of the parts.
+        let offsets = body
+            .windows(2)
+            .enumerate()
+            .filter(|(_, data)| data.contains(&b'\r') || data.contains(&b'\n'))
+            .map(|(index, _)| index + 1)
+            .collect::<Vec<_>>();
+        for &offset in &offsets {
+            assert_chunked_upload(&mut server, parts, &[offset]);
+        }
+        // the CRLFs arriving alone.
+        assert_chunked_upload(&mut server, parts, &offsets);
+    }
+
+    #[test]
+    fn multipart_binary_content_at_chunk_boundaries() {
+        let mut server = TestServer::new(&[]);
+        let mut content = (0..=255).collect::<Vec<u8>>();
+        content.extend_from_slice(b"\r\r\n\n\r\n\r\r\n\r");
+        let parts: &[(&str, Option<&str>, &[u8])] = &[
+            ("file", Some("a.bin"), &content),
+            ("file", Some("b.bin"), b"\n\r\r\n"),
+        ];
+        let len = multipart_body(parts).len();
+        // 2 chunks, and 3 chunks cutting a CR and its LF apart.
+        for offset in 1..len {
+            assert_chunked_upload(&mut server, parts, &[offset]);
+            if offset + 1 < len {
+                assert_chunked_upload(&mut server, parts, &[offset, offset + 1]);
+            }
+        }
+    }
+
+    #[test]
+    fn multipart_split_in_arbitrary_places() {
+        let mut server = TestServer::new(&[]);
+        let parts: &[(&str, Option<&str>, &[u8])] = &[
+            // almost a delimiter, the boundary itself must not appear in the content.
+            (
+                "file",
+                Some("a.bin"),
+                b"--XyZ-boundar\r\n--XyZ-boundaryy\r\n",
+            ),
+            (
+                "file",
+                Some("b.bin"),
+                b"\r\n--XyZ-boundary-\r\n-XyZ-boundary--",
+            ),
+            ("file", Some("c.bin"), b"last\r\n"),
+        ];
+        let len = multipart_body(parts).len();
+        // chunks of every size, e.g. a single byte each.
+        for size in 1..len {
+            let offsets = (1..)
+                .map(|index| index * size)
+                .take_while(|&offset| offset < len)
+                .collect::<Vec<_>>();
+            assert_chunked_upload(&mut server, parts, &offsets);
+        }
+        // uneven chunks cutting the delimiters in different places.
+        for seed in 1..8 {
+            let mut offsets = Vec::new();
+            let mut offset = 0;
+            loop {
+                offset += (offset * 7 + seed) % 11 + 1;
+                if len <= offset {
+                    break;
+                }
+                offsets.push(offset);
+            }
+            assert_chunked_upload(&mut server, parts, &offsets);
+        }
+    }
+
+    #[test]
+    fn throttle() {
+        let mut server = TestServer::new(&["--throttle", "1000"]);
+        let response = server.upload("a.bin", &[0; 1500]);
+        let path = url_path(&response, 0);
+
+        let started = Instant::now();
+        let response = server.send(get(&path));
+        assert_eq!(response.body().len(), 1500);
+        assert!(Duration::from_millis(1500) <= started.elapsed());
+    }
+
+    #[test]
+    fn public_url() {
+        let mut server = TestServer::new(&[
+            "--trust-proxy",
+            "--public-url",
+            "https://files.example.com/transfer/",
+        ]);
+        // the headers are not used.
+        let mut req = raw_upload("a.txt", b"hello");
+        req.headers_mut()
+            .insert("x-forwarded-host", "proxy.example.org".parse().unwrap());
+        let raw = server.send(req);
+        let body = multipart_body(&[("file", Some("b.txt"), b"hello")]);
+        let multipart = server.send(multipart_upload(vec![body]));
+        for (response, filename) in &[(raw, "a.txt"), (multipart, "b.txt")] {
+            let url = json(response)["part"][0]["url"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+            assert!(
+                url.starts_with("https://files.example.com/transfer/"),
+                "{}",
+                url
+            );
+            assert!(url.ends_with(&format!("/{}", filename)), "{}", url);
+        }
+    }
+
+    #[test]
+    fn url_without_host_header() {
+        let server = LiveServer::new(&[]);
+        let (_, body) = server.request(
+            b"POST /upload HTTP/1.0\r\nContent-Type: application/octet-stream\r\n\
+              X-TP-Filename: a.txt\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        let url = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["part"][0]["url"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        let prefix = format!("http://{}/", server.addr);
+        assert!(url.starts_with(&prefix), "{}", url);
+
+        let opt = Opt::from_iter_safe(&["transfer", "--port", "0"]).unwrap();
+        let client = IpAddr::from(CLIENT_ADDR.0);
+        let host = |addr: &str| public_host(&HeaderMap::new(), client, addr.parse().unwrap(), &opt);
+        assert_eq!(host("0.0.0.0:8080"), "localhost:8080");
+        assert_eq!(host("[::]:8080"), "localhost:8080");
+        assert_eq!(host("192.0.2.80:8080"), "192.0.2.80:8080");
+        assert_eq!(host("[2001:db8::80]:8080"), "[2001:db8::80]:8080");
+    }
+
+    #[test]
+    fn forwarded_header_drives_urls() {
+        let mut server = TestServer::new(&["--trust-proxy"]);
+        let mut req = raw_upload("a.txt", b"hello");
+        req.headers_mut().insert(
+            "forwarded",
+            r#"for=198.51.100.7;host="files.example.org";proto=https"#
+                .parse()
+                .unwrap(),
+        );
+        req.headers_mut()
+            .insert("x-forwarded-host", "legacy.example.org".parse().unwrap());
+        req.headers_mut()
+            .insert("x-forwarded-proto", "http".parse().unwrap());
+        let response = server.send(req);
+        let url = json(&response)["part"][0]["url"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        assert!(url.starts_with("https://files.example.org/"), "{}", url);
+
+        // the legacy headers are used without the Forwarded header.
+        let mut req = raw_upload("a.txt", b"hello");
+        req.headers_mut()
+            .insert("x-forwarded-host", "legacy.example.org".parse().unwrap());
+        let response = server.send(req);
+        let url = json(&response)["part"][0]["url"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        assert!(url.starts_with("http://legacy.example.org/"), "{}", url);
+    }
+
+    #[test]
+    fn forwarded_header_drives_client_ip() {
+        let opt = Opt::from_iter_safe(&["transfer", "--port", "0", "--trust-proxy"]).unwrap();
+        let proxy = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.0.2.1".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, proxy, &opt),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        headers.insert("forwarded", r#"for="[2001:db8::7]:4711""#.parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, proxy, &opt),
+            "2001:db8::7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dir_mode_ignores_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut server = TestServer::new(&["--dir-mode", "777", "--preserve-paths"]);
+        let response = server.upload("a/b/c.txt", b"hello");
+        let path = url_path(&response, 0);
+        let mut dir = server.data_dir().to_path_buf();
+        for component in path.trim_start_matches('/').split('/').take(3) {
+            dir.push(component);
+            let mode = dir.metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o777, "{:?}", dir);
+        }
+    }
+
+    #[test]
+    fn deny_extension() {
+        let mut server = TestServer::new(&["--deny-extension", "php", "--deny-extension", ".SH"]);
+        for filename in &["payload.php", "payload.PHP", "dir/run.sh"] {
+            let response = server.upload(filename, b"<?php");
+            assert_eq!(
+                response.status(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{}",
+                filename
+            );
+        }
+        assert!(stored_files(server.data_dir()).is_empty());
+
+        let body = multipart_body(&[
+            ("file", Some("payload.php"), b"<?php"),
+            ("file", Some("a.txt"), b"hello"),
+        ]);
+        let response = server.send(multipart_upload(vec![body]));
+        let json = json(&response);
+        assert!(json["part"][0]["url"].as_str().unwrap().ends_with("/a.txt"));
+        assert_eq!(json["part"][1]["error"], "denied extension");
+
+        let response = server.upload("payload.php.txt", b"<?php");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn selftest_round_trip() {
+        let opt = Opt::from_iter_safe(&["transfer", "--strip-exif", "selftest"]).unwrap();
+        assert!(opt.command.is_some());
+        selftest(opt).unwrap();
+
+        let opt = Opt::from_iter_safe(&[
+            "transfer",
+            "--disable-octet-stream",
+            "--auth-token",
+            "secret",
+            "--auth-downloads",
+            "selftest",
+        ])
+        .unwrap();
+        selftest(opt).unwrap();
+    }
+
+    #[test]
+    fn public_scheme_cases() {
+        let scheme = |args: &[&str], headers: &[(&str, &str)]| {
+            let opt = Opt::from_iter_safe(["transfer", "--port", "0"].iter().chain(args)).unwrap();
+            let mut header_map = HeaderMap::new();
+            for (name, value) in headers {
+                header_map.insert(
+                    hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    value.parse().unwrap(),
+                );
+            }
+            public_scheme(&header_map, "10.0.0.1".parse().unwrap(), &opt)
+        };
+        let tls = ["--tls-cert", "cert.pem", "--tls-key", "key.pem"];
+
+        // plain.
+        assert_eq!(scheme(&[], &[]), "http");
+        assert_eq!(scheme(&["--default-scheme", "https"], &[]), "https");
+        assert_eq!(scheme(&[], &[("x-forwarded-proto", "https")]), "http");
+
+        // TLS.
+        assert_eq!(scheme(&tls, &[]), "https");
+        let mut args = tls.to_vec();
+        args.extend_from_slice(&["--trust-proxy", "--default-scheme", "http"]);
+        assert_eq!(scheme(&args, &[("x-forwarded-proto", "http")]), "https");
+
+        // proxied.
+        let proxy = ["--trusted-proxy", "10.0.0.0/8"];
+        assert_eq!(scheme(&proxy, &[("x-forwarded-proto", "HTTPS")]), "https");
+        assert_eq!(scheme(&proxy, &[("forwarded", "proto=https")]), "https");
+        assert_eq!(scheme(&proxy, &[("x-forwarded-proto", "gopher")]), "http");
+        let untrusted = ["--trusted-proxy", "192.168.0.0/16"];
+        assert_eq!(
+            scheme(&untrusted, &[("x-forwarded-proto", "https")]),
+            "http"
+        );
+    }
+
+    /// gzip of "hello".
+    const GZIP_HELLO: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\xcb\x48\xcd\xc9\xc9\x07\x00\x86\xa6\x10\x36\x05\x00\x00\x00";
+
+    /// Returns the path of the .gz sidecar of the file at the URL path `path`.
+    fn sidecar_path(server: &TestServer, path: &str) -> PathBuf {
+        server
+            .data_dir()
+            .join(format!("{}.gz", path.trim_start_matches('/')))
+    }
+
+    #[test]
+    fn gzip_sidecar() {
+        let mut server = TestServer::new(&[]);
+        let response = server.upload("a.txt", b"hello");
+        let path = url_path(&response, 0);
+        let gzip = GZIP_HELLO;
+        let sidecar = sidecar_path(&server, &path);
+        let download = |server: &mut TestServer, accept_encoding: Option<&str>| {
+            let mut req = get(&path);
+            if let Some(accept_encoding) = accept_encoding {
+                req.headers_mut().insert(
+                    hyper::header::ACCEPT_ENCODING,
+                    accept_encoding.parse().unwrap(),
+                );
+            }
+            server.send(req)
+        };
+
+        // no sidecar yet.
+        let response = download(&mut server, Some("gzip"));
+        assert_eq!(response.body().as_slice(), b"hello");
+        assert!(response.headers().get(hyper::header::VARY).is_none());
+        assert_eq!(response.headers()[hyper::header::ACCEPT_RANGES], "bytes");
+
+        std::fs::write(&sidecar, &gzip[..]).unwrap();
+        for accept_encoding in &["gzip", "deflate, gzip;q=0.5", "x-gzip", "*"] {
+            let response = download(&mut server, Some(accept_encoding));
+            assert_eq!(response.body().as_slice(), &gzip[..], "{}", accept_encoding);
+            let headers = response.headers();
+            assert_eq!(headers[hyper::header::CONTENT_ENCODING], "gzip");
+            assert_eq!(headers[hyper::header::CONTENT_TYPE], "text/plain");
+            assert_eq!(headers[hyper::header::VARY], "accept-encoding");
+            assert_eq!(headers[hyper::header::ACCEPT_RANGES], "none");
+        }
+        for accept_encoding in &[None, Some("deflate"), Some("gzip;q=0"), Some("*, gzip;q=0")] {
+            let response = download(&mut server, *accept_encoding);
+            assert_eq!(
+                response.body().as_slice(),
+                b"hello",
+                "{:?}",
+                accept_encoding
+            );
+            let headers = response.headers();
+            assert!(headers.get(hyper::header::CONTENT_ENCODING).is_none());
+            assert_eq!(headers[hyper::header::VARY], "accept-encoding");
+            assert_eq!(headers[hyper::header::ACCEPT_RANGES], "bytes");
+        }
+
+        let response = server.send(Request::delete(path.as_str()).body(Body::empty()).unwrap());
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn gzip_sidecar_encoding() {
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        std::fs::write(sidecar_path(&server, &path), GZIP_HELLO).unwrap();
+        let request = |method: Method, accept_encoding: Option<&str>| {
+            let mut req = get(&path);
+            *req.method_mut() = method;
+            if let Some(accept_encoding) = accept_encoding {
+                req.headers_mut().insert(
+                    hyper::header::ACCEPT_ENCODING,
+                    accept_encoding.parse().unwrap(),
+                );
+            }
+            req
+        };
+        for method in &[Method::GET, Method::HEAD] {
+            // the compressed bytes are always labeled as such.
+            let response = server.send(request(method.clone(), Some("gzip")));
+            let headers = response.headers();
+            assert_eq!(headers[hyper::header::CONTENT_ENCODING], "gzip");
+            assert_eq!(
+                headers[hyper::header::CONTENT_LENGTH],
+                GZIP_HELLO.len().to_string().as_str()
+            );
+            // the digest of the uploaded content, not of the sidecar.
+            assert_eq!(headers["x-tp-sha256"], Sha256::digest(b"hello").as_str());
+
+            let response = server.send(request(method.clone(), None));
+            let headers = response.headers();
+            assert!(headers.get(hyper::header::CONTENT_ENCODING).is_none());
+            assert_eq!(headers[hyper::header::CONTENT_LENGTH], "5");
+            assert_eq!(headers["x-tp-sha256"], Sha256::digest(b"hello").as_str());
+            if *method == Method::GET {
+                assert_eq!(response.body().as_slice(), b"hello");
+            } else {
+                assert!(response.body().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn gzip_sidecar_ranges() {
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        let gzip = GZIP_HELLO;
+        std::fs::write(sidecar_path(&server, &path), gzip).unwrap();
+
+        // a range of the original isn't applied to the compressed content, which is sent whole.
+        let ranged = |server: &mut TestServer, accept_encoding: Option<&str>, range: &str| {
+            let mut req = get(&path);
+            if let Some(accept_encoding) = accept_encoding {
+                req.headers_mut().insert(
+                    hyper::header::ACCEPT_ENCODING,
+                    accept_encoding.parse().unwrap(),
+                );
+            }
+            req.headers_mut()
+                .insert(hyper::header::RANGE, range.parse().unwrap());
+            server.send(req)
+        };
+        for range in &["bytes=0-1", "bytes=1-", "bytes=-2", "bytes=100-"] {
+            let response = ranged(&mut server, Some("gzip"), range);
+            assert_eq!(response.status(), StatusCode::OK, "{}", range);
+            assert_eq!(response.body().as_slice(), &gzip[..], "{}", range);
+            let headers = response.headers();
+            assert_eq!(headers[hyper::header::CONTENT_ENCODING], "gzip");
+            assert_eq!(headers[hyper::header::ACCEPT_RANGES], "none");
+            assert_eq!(
+                headers[hyper::header::CONTENT_LENGTH],
+                gzip.len().to_string().as_str()
+            );
+            assert!(headers.get(hyper::header::CONTENT_RANGE).is_none());
+        }
+        // the clients without gzip get the ranges of the original.
+        let response = ranged(&mut server, None, "bytes=1-2");
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.body().as_slice(), b"el");
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_RANGE],
+            "bytes 1-2/5"
+        );
+        assert!(response
+            .headers()
+            .get(hyper::header::CONTENT_ENCODING)
+            .is_none());
+    }
+
+    fn post(path: &str, filename: &str, data: &[u8]) -> Request<Body> {
+        let mut req = raw_upload(filename, data);
+        *req.uri_mut() = path.parse().unwrap();
+        req
+    }
+
+    #[test]
+    fn custom_upload_path() {
+        let mut server = TestServer::new(&["--upload-path", "/files/new"]);
+        let response = server.send(post("/files/new", "a.txt", b"hello"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(get(&url_path(&response, 0)));
+        assert_eq!(response.body().as_slice(), b"hello");
+
+        // the download route doesn't swallow the upload path.
+        let response = server.send(get("/files/new"));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = server.send(post("/upload", "a.txt", b"hello"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn upload_anywhere() {
+        let mut server = TestServer::new(&["--upload-anywhere"]);
+        for path in &["/", "/upload", "/any/where", "/any/where/deeper"] {
+            let response = server.send(post(path, "a.txt", b"hello"));
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        }
+
+        let mut server = TestServer::new(&[]);
+        let response = server.send(post("/any/where", "a.txt", b"hello"));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn root_behavior() {
+        let mut server = TestServer::new(&[]);
+        assert_eq!(server.send(get("/")).status(), StatusCode::NOT_FOUND);
+
+        let mut server =
+            TestServer::new(&["--root-behavior", "form", "--upload-path", "/up\"<&load"]);
+        let response = server.send(get("/"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = String::from_utf8(response.body().clone()).unwrap();
+        assert!(
+            body.contains(r#"action="/up&quot;&lt;&amp;load""#),
+            "{}",
+            body
+        );
+
+        let mut server =
+            TestServer::new(&["--root-behavior", "redirect:https://example.org/a?b=c"]);
+        let response = server.send(get("/"));
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers()[hyper::header::LOCATION],
+            "https://example.org/a?b=c"
+        );
+
+        let mut server = TestServer::new(&["--root-behavior", "text:hello, world"]);
+        let response = server.send(get("/"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.body().as_slice(), b"hello, world");
+
+        for value in &[
+            "redirect:",
+            "redirect:https://exa mple.org/",
+            "redirect:/a\nb",
+            "other",
+        ] {
+            let opt = Opt::from_iter_safe(&["transfer", "--root-behavior", value]);
+            assert!(opt.is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn list_is_streamed() {
+        let mut server = TestServer::new(&["--root-behavior", "list"]);
+        let list = |server: &mut TestServer, query: &str| {
+            let response =
+                server.respond(SocketAddr::from(CLIENT_ADDR), get(&format!("/{}", query)));
+            assert_eq!(response.status(), StatusCode::OK);
+            let pieces = server
+                .runtime
+                .block_on(response.into_body().collect())
+                .unwrap();
+            let body = pieces
+                .iter()
+                .flat_map(|data| data.to_vec())
+                .collect::<Vec<_>>();
+            let entries = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            (pieces.len(), entries.as_array().unwrap().clone())
+        };
+        assert_eq!(list(&mut server, ""), (1, vec![]));
+
+        let count = LIST_CHUNK_ENTRIES * 4 + 1;
+        for index in 0..count {
+            let dir = server.data_dir().join(format!("{:04}", index));
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+        }
+        for &limit in &[1, LIST_CHUNK_ENTRIES, LIST_CHUNK_ENTRIES + 1, count] {
+            let (pieces, entries) = list(&mut server, &format!("?limit={}", limit));
+            assert_eq!(
+                pieces,
+                (limit + LIST_CHUNK_ENTRIES - 1) / LIST_CHUNK_ENTRIES
+            );
+            assert_eq!(entries.len(), limit);
+            for (index, entry) in entries.iter().enumerate() {
+                assert_eq!(entry["id"], format!("{:04}", index).as_str());
+                assert_eq!(entry["file_name"], "a.txt");
+                assert_eq!(entry["size"], 5);
+            }
+        }
+        let (_, entries) = list(
+            &mut server,
+            &format!("?offset={}&limit={}", count - 2, count),
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1]["id"], format!("{:04}", count - 1).as_str());
+    }
+
+    /// Returns the paths and the contents of the files of a tar archive.
+    fn untar(mut archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let field = |header: &[u8], start: usize, len: usize| {
+            let data = &header[start..start + len];
+            let end = data.iter().position(|&c| c == 0).unwrap_or(len);
+            String::from_utf8(data[..end].to_vec()).unwrap()
+        };
+        let mut files = Vec::new();
+        let mut pax_path = None;
+        while archive[..512].iter().any(|&c| c != 0) {
+            let (header, rest) = archive.split_at(512);
+            let size = u64::from_str_radix(field(header, 124, 12).trim(), 8).unwrap() as usize;
+            let data = rest[..size].to_vec();
+            archive = &rest[(size + 511) / 512 * 512..];
+            if header[156] == b'x' {
+                let record = String::from_utf8(data).unwrap();
+                let path = record.splitn(2, " path=").nth(1).unwrap();
+                pax_path = Some(path.trim_end_matches('\n').to_owned());
+                continue;
+            }
+            assert_eq!(header[156], b'0');
+            let path = match pax_path.take() {
+                Some(data) => data,
+                None => match field(header, 345, 155) {
+                    ref prefix if prefix.is_empty() => field(header, 0, 100),
+                    prefix => format!("{}/{}", prefix, field(header, 0, 100)),
+                },
+            };
+            files.push((path, data));
+        }
+        assert!(archive.iter().all(|&c| c == 0));
+        files
+    }
+
+    #[test]
+    fn export_tar() {
+        let mut server = TestServer::new(&["--auth-token", "secret"]);
+        let long_name = format!("{}.txt", "a".repeat(120));
+        let mut paths = Vec::new();
+        for (filename, data) in &[("a.txt", &b"hello"[..]), (&long_name, &[7; 1000][..])] {
+            let response = server.send(with_header(
+                raw_upload(filename, data),
+                "x-tp-token",
+                "secret",
+            ));
+            assert_eq!(response.status(), StatusCode::OK);
+            paths.push(url_path(&response, 0));
+        }
+
+        let response = server.send(get("/api/export.tar"));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = server.send(with_header(get("/api/export.tar"), "x-tp-token", "secret"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "application/x-tar"
+        );
+        let mut files = untar(response.body());
+        files.sort();
+        let mut expected = Vec::new();
+        let mut dirs = vec![server.data_dir().to_owned()];
+        while let Some(dir) = dirs.pop() {
+            for entry in dir.read_dir().unwrap().filter_map(Result::ok) {
+                if entry.path().is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let path = entry.path();
+                let name = path.strip_prefix(server.data_dir()).unwrap();
+                expected.push((
+                    name.to_str().unwrap().to_owned(),
+                    std::fs::read(&path).unwrap(),
+                ));
+            }
+        }
+        expected.sort();
+        assert_eq!(files, expected);
+        // the stored files and their metadata.
+        assert!(files.contains(&(paths[0][1..].to_owned(), b"hello".to_vec())));
+        assert!(files.contains(&(paths[1][1..].to_owned(), vec![7; 1000])));
+        let metadata = files
+            .iter()
+            .filter(|(path, _)| path.starts_with(META_DIR) && path.ends_with(".json"))
+            .count();
+        assert_eq!(metadata, 2);
+    }
+
+    fn post_json(path: &str, body: &str) -> Request<Body> {
+        Request::post(path)
+            .header(hyper::header::HOST, "example.com")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    }
+
+    #[test]
+    fn rename_upload() {
+        let mut server = TestServer::new(&["--auth-token", "secret"]);
+        let response = server.send(with_header(
+            raw_upload("a.txt", b"hello"),
+            "x-tp-token",
+            "secret",
+        ));
+        let path = url_path(&response, 0);
+        let id = path.split('/').nth(1).unwrap().to_owned();
+        let rename_path = format!("/api/{}/rename", id);
+
+        let response = server.send(post_json(&rename_path, r#"{"filename":"b.txt"}"#));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        for body in &["", "{}", r#"{"filename":""}"#, r#"{"filename":"../b.txt"}"#] {
+            let response = server.send(with_header(
+                post_json(&rename_path, body),
+                "x-tp-token",
+                "secret",
+            ));
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        let response = server.send(with_header(
+            post_json("/api/unknown/rename", r#"{"filename":"b.txt"}"#),
+            "x-tp-token",
+            "secret",
+        ));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // sanitized like the filename of an upload.
+        let response = server.send(with_header(
+            post_json(&rename_path, r#"{"filename":"dir/b.txt"}"#),
+            "x-tp-token",
+            "secret",
+        ));
+        assert_eq!(response.status(), StatusCode::OK);
+        let url = json(&response)["url"].as_str().unwrap().to_owned();
+        assert_eq!(url, format!("http://example.com/{}/b.txt", id));
+
+        let response = server.send(get(url.trim_start_matches("http://example.com")));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_slice(), b"hello");
+        assert!(response.headers().contains_key("x-tp-sha256"));
+        assert_eq!(server.send(get(&path)).status(), StatusCode::NOT_FOUND);
+        let stored = server.data_dir().join(&id).join("b.txt");
+        assert_eq!(stored_files(server.data_dir()), vec![stored.clone()]);
+        assert!(read_metadata(server.data_dir(), &stored).is_some());
+        assert!(read_metadata(
+            server.data_dir(),
+            &server.data_dir().join(&id).join("a.txt")
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn rename_conflicts() {
+        let mut server = TestServer::new(&["--allow-user-keys", "--track-tombstones"]);
+        let response = server.send(with_header(raw_upload("a.txt", b"hello"), "x-tp-key", "k"));
+        let path = url_path(&response, 0);
+        server.send(with_header(raw_upload("b.txt", b"hello"), "x-tp-key", "k"));
+        let response = server.send(post_json("/api/k/rename", r#"{"filename":"c.txt"}"#));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        server.send(delete("/k/b.txt"));
+        let response = server.send(post_json("/api/k/rename", r#"{"filename":"c.txt"}"#));
+        assert_eq!(response.status(), StatusCode::OK);
+        // the old URL is gone rather than never existed.
+        assert_eq!(server.send(get(&path)).status(), StatusCode::GONE);
+        assert_eq!(server.send(get("/k/c.txt")).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn signed_urls() {
+        let mut server = TestServer::new(&[
+            "--auth-token",
+            "secret",
+            "--auth-downloads",
+            "--url-sign-key",
+            "key",
+        ]);
+        let response = server.send(with_header(
+            raw_upload("a.txt", b"hello"),
+            "x-tp-token",
+            "secret",
+        ));
+        let path = url_path(&response, 0);
+        let sign_path = format!("/api/{}/sign", path.split('/').nth(1).unwrap());
+
+        let response = server.send(post_json(&sign_path, ""));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = server.send(with_header(
+            post_json(&format!("{}?ttl=soon", sign_path), ""),
+            "x-tp-token",
+            "secret",
+        ));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = server.send(with_header(
+            post_json(&format!("{}?ttl=1h", sign_path), ""),
+            "x-tp-token",
+            "secret",
+        ));
+        assert_eq!(response.status(), StatusCode::OK);
+        let url = json(&response)["url"].as_str().unwrap().to_owned();
+        let signed_path = url.trim_start_matches("http://example.com").to_owned();
+        assert!(signed_path.starts_with(&format!("{}?expires=", path)));
+
+        // served without the token.
+        assert_eq!(server.send(get(&path)).status(), StatusCode::UNAUTHORIZED);
+        let response = server.send(get(&signed_path));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_slice(), b"hello");
+    }
+
+    #[test]
+    fn signed_urls_rejected() {
+        let mut server = TestServer::new(&["--auth-downloads", "--url-sign-key", "key"]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        let signed = |path: &str, expires: u64| {
+            let signature = url_signature("key", &path[1..], expires);
+            format!("{}?expires={}&signature={}", path, expires, signature)
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(
+            server.send(get(&signed(&path, now + 60))).status(),
+            StatusCode::OK
+        );
+
+        // expired.
+        let response = server.send(get(&signed(&path, now - 1)));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.body().as_slice(), b"expired signature");
+
+        // tampered.
+        let url = signed(&path, now + 60);
+        for tampered in &[
+            url.replace(
+                &format!("expires={}", now + 60),
+                &format!("expires={}", now + 6000),
+            ),
+            format!("{}0", url),
+            url.replace("a.txt", "b.txt"),
+            url.replace("signature=", "signature=0"),
+        ] {
+            let response = server.send(get(tampered));
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", tampered);
+            assert_eq!(response.body().as_slice(), b"invalid signature");
+        }
+
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        let sign_path = format!("/api/{}/sign", path.split('/').nth(1).unwrap());
+        let response = server.send(post_json(&sign_path, ""));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // signed with a key the server doesn't have.
+        let response = server.send(get(&signed(&path, now + 60)));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn first_boundary_not_found() {
+        let mut server = TestServer::new(&[]);
+        let mut body = b"--another-boundary\r\n".to_vec();
+        body.extend_from_slice(&multipart_body(&[("file", Some("a.txt"), b"hello")]));
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json(&response)["error"], "first boundary not found");
+        assert!(stored_files(server.data_dir()).is_empty());
+    }
+
+    #[test]
+    fn missing_multipart_boundary() {
+        let mut server = TestServer::new(&[]);
+        for content_type in &["multipart/form-data", "multipart/form-data; boundary="] {
+            let mut req =
+                multipart_upload(vec![multipart_body(&[("file", Some("a.txt"), b"hello")])]);
+            req.headers_mut()
+                .insert(hyper::header::CONTENT_TYPE, content_type.parse().unwrap());
+            let response = server.send(req);
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                content_type
+            );
+            assert_eq!(response.body().as_slice(), b"missing multipart boundary");
+        }
+        assert!(stored_files(server.data_dir()).is_empty());
+    }
+
+    #[test]
+    fn invalid_header_encoding() {
+        let mut server = TestServer::new(&[]);
+        let body = multipart_body(&[("file", Some("a\u{e9}.txt"), b"hello")]);
+        // replaces the UTF-8 encoded 'é' with its Latin-1 byte.
+        let position = body.windows(2).position(|w| w == [0xc3, 0xa9]).unwrap();
+        let mut latin1 = body[..position].to_vec();
+        latin1.push(0xe9);
+        latin1.extend_from_slice(&body[position + 2..]);
+        let response = server.send(multipart_upload(vec![latin1]));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json(&response)["error"], "invalid header encoding");
+        assert!(stored_files(server.data_dir()).is_empty());
+    }
+
+    #[test]
+    fn empty_filename_policy_generate() {
+        let mut server = TestServer::new(&[]);
+        let body = multipart_body(&[("file", Some(""), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+        let path = url_path(&response, 0);
+        assert!(path.ends_with(&format!("/{}", DEFAULT_FILENAME)));
+        assert_eq!(server.send(get(&path)).body().as_slice(), b"hello");
+    }
+
+    #[test]
+    fn empty_filename_policy_reject() {
+        let mut server = TestServer::new(&["--empty-filename-policy", "reject"]);
+        let body = multipart_body(&[("file", Some(""), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(json(&response)["part"][0]["error"], "empty filename");
+        // no id directory is left behind.
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+
+        let response = server.upload("", b"hello");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn client_ip_skips_forged_addresses() {
+        let opt =
+            Opt::from_iter_safe(&["transfer", "--port", "0", "--trusted-proxy", "10.0.0.0/8"])
+                .unwrap();
+        let proxy = "10.0.0.1".parse().unwrap();
+        let forwarded_ip = |name: &str, values: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(
+                    hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    value.parse().unwrap(),
+                );
+            }
+            client_ip(&headers, proxy, &opt).to_string()
+        };
+
+        // the client sent the first address.
+        let xff = "x-forwarded-for";
+        assert_eq!(
+            forwarded_ip(xff, &["192.0.2.1, 198.51.100.9"]),
+            "198.51.100.9"
+        );
+        assert_eq!(
+            forwarded_ip(xff, &["192.0.2.1, 198.51.100.9, 10.0.0.2"]),
+            "198.51.100.9"
+        );
+        assert_eq!(
+            forwarded_ip(xff, &["192.0.2.1", "198.51.100.9"]),
+            "198.51.100.9"
+        );
+        // every address is a trusted proxy.
+        assert_eq!(forwarded_ip(xff, &["10.0.0.3, 10.0.0.2"]), "10.0.0.3");
+        assert_eq!(
+            forwarded_ip(xff, &["198.51.100.9, garbage, 10.0.0.2"]),
+            "10.0.0.2"
+        );
+
+        let forwarded = "forwarded";
+        assert_eq!(
+            forwarded_ip(
+                forwarded,
+                &["for=192.0.2.1, for=198.51.100.9, for=10.0.0.2"]
+            ),
+            "198.51.100.9"
+        );
+        assert_eq!(
+            forwarded_ip(forwarded, &["for=192.0.2.1", "for=198.51.100.9"]),
+            "198.51.100.9"
+        );
+
+        // the headers of an untrusted peer are ignored.
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.0.2.1".parse().unwrap());
+        let peer = "198.51.100.1".parse().unwrap();
+        assert_eq!(client_ip(&headers, peer, &opt), peer);
+    }
+
+    #[test]
+    fn forwarded_host_of_the_client_element() {
+        let opt =
+            Opt::from_iter_safe(&["transfer", "--port", "0", "--trusted-proxy", "10.0.0.0/8"])
+                .unwrap();
+        let proxy = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            "for=192.0.2.1;host=forged.example;proto=http, \
+             for=198.51.100.9;host=files.example.org;proto=https, for=10.0.0.2;host=internal"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            public_host(&headers, proxy, SocketAddr::from(SERVER_ADDR), &opt),
+            "files.example.org"
+        );
+        assert_eq!(public_scheme(&headers, proxy, &opt), "https");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-host",
+            "forged.example, files.example.org".parse().unwrap(),
+        );
+        assert_eq!(
+            public_host(&headers, proxy, SocketAddr::from(SERVER_ADDR), &opt),
+            "files.example.org"
+        );
+    }
+
+    #[test]
+    fn max_filename_bytes() {
+        for value in &["0", "15", "-1", "abc"] {
+            let ret = Opt::from_iter_safe(&["transfer", "--max-filename-bytes", value]);
+            assert!(ret.is_err(), "{}", value);
+        }
+
+        let mut server = TestServer::new(&["--max-filename-bytes", "16"]);
+        let filename = format!("{}.txt", "\u{3042}".repeat(10));
+        let body = multipart_body(&[("file", Some(&filename), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+        let stored = stored_files(server.data_dir());
+        assert_eq!(stored.len(), 1);
+        assert_eq!(
+            stored[0].file_name().unwrap().to_str().unwrap(),
+            format!("{}.txt", "\u{3042}".repeat(4))
+        );
+    }
+
+    #[test]
+    fn id_scheme_round_trip() {
+        for (scheme, len) in &[("uuid", 36), ("short", 16), ("ulid", 26)] {
+            let mut server = TestServer::new(&["--id-scheme", scheme]);
+            let response = server.upload("a.txt", b"hello");
+            let path = url_path(&response, 0);
+            let id = path.trim_start_matches('/').split('/').next().unwrap();
+            assert_eq!(id.len(), *len, "{}", path);
+
+            let response = server.send(get(&path));
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            assert_eq!(response.body().as_slice(), b"hello");
+        }
+    }
+
+    #[test]
+    fn max_part_size() {
+        let mut server = TestServer::new(&["--max-part-size", "1K"]);
+        let large = vec![b'b'; 1025];
+        let body = multipart_body(&[
+            ("file", Some("a.txt"), b"first"),
+            ("file", Some("b.txt"), &large),
+            ("file", Some("c.txt"), &[b'c'; 1024]),
+        ]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+        let ret = json(&response);
+        assert_eq!(ret["part"][0]["file_name"], "a.txt");
+        assert!(url_path(&response, 0).ends_with("/a.txt"));
+        assert_eq!(ret["part"][1]["file_name"], "c.txt");
+        assert!(url_path(&response, 1).ends_with("/c.txt"));
+        assert_eq!(ret["part"][2]["file_name"], "b.txt");
+        assert_eq!(ret["part"][2]["error"], "part too large");
+
+        let mut stored = stored_files(server.data_dir())
+            .iter()
+            .map(|data| data.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        stored.sort();
+        assert_eq!(stored, ["a.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn max_part_size_without_crlf() {
+        let large = vec![b'b'; 64 * 1024];
+        let body = multipart_body(&[
+            ("file", Some("a.txt"), &[b'a'; 1024]),
+            ("file", Some("b.txt"), &large),
+            ("file", Some("c.txt"), &[b'c'; 1024]),
+        ]);
+        for size in &[1, 7, 100, 1000] {
+            let mut server = TestServer::new(&["--max-part-size", "1K"]);
+            let chunks = body.chunks(*size).map(<[u8]>::to_vec).collect();
+            let response = server.send(multipart_upload(chunks));
+            assert_eq!(response.status(), StatusCode::OK, "{}", size);
+            let ret = json(&response);
+            assert_eq!(ret["part"][0]["file_name"], "a.txt", "{}", size);
+            assert_eq!(ret["part"][1]["file_name"], "c.txt", "{}", size);
+            assert_eq!(ret["part"][2]["file_name"], "b.txt", "{}", size);
+            assert_eq!(ret["part"][2]["error"], "part too large", "{}", size);
+            let response = server.send(get(&url_path(&response, 1)));
+            assert_eq!(response.body().as_slice(), &[b'c'; 1024][..]);
+            assert_eq!(stored_files(server.data_dir()).len(), 2);
+        }
+
+        // the rejected part isn't buffered while its line lasts.
+        let dir = TestDir::new();
+        let opt = Opt::from_iter_safe(&[
+            "transfer",
+            "--max-part-size",
+            "1K",
+            "--data-dir",
+            dir.0.to_str().unwrap(),
+        ])
+        .unwrap();
+        let mut context = ParseMultipartContext::new(
+            BOUNDARY.to_owned(),
+            Arc::new(create_multipart_regexps().unwrap()),
+            Arc::from(opt.id_scheme.generator()),
+            None,
+            Metadata::default(),
+            dir.0.clone(),
+            Arc::new(opt),
+        );
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"b.txt\"\r\n\r\n",
+            BOUNDARY
+        );
+        let chunks = std::iter::once(head.into_bytes()).chain(vec![vec![b'b'; 100]; 100]);
+        for chunk in chunks {
+            let mut buf = std::mem::replace(&mut context.buffer, Vec::new());
+            buf.extend(chunk);
+            let mut reader = BufReader::new(buf.as_slice());
+            while let Ok(CommandRet::NextCommand) =
+                context.command.clone().execute(&mut context, &mut reader)
+            {}
+        }
+        assert!(context.file_id.is_none());
+        assert!(context.buffer.len() <= BOUNDARY.len() + 7);
+    }
+
+    #[test]
+    fn idempotency_key() {
+        let mut server = TestServer::new(&[]);
+        let upload = |key: &str| {
+            let mut req = raw_upload("a.txt", b"hello");
+            req.headers_mut()
+                .insert("idempotency-key", key.parse().unwrap());
+            req
+        };
+
+        let first = server.send(upload("a"));
+        let retry = server.send(upload("a"));
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(retry.status(), first.status());
+        assert_eq!(retry.body(), first.body());
+        assert_eq!(stored_files(server.data_dir()).len(), 1);
+
+        // another key, or the same key from another client, is a new upload.
+        let other = server.send(upload("b"));
+        assert_ne!(other.body(), first.body());
+        let other_client = server.send_from(([192, 0, 2, 2], 50000).into(), upload("a"));
+        assert_ne!(other_client.body(), first.body());
+        assert_eq!(stored_files(server.data_dir()).len(), 3);
+    }
+
+    #[test]
+    fn max_file_concurrency() {
+        let mut server = TestServer::new(&["--max-file-concurrency", "2", "--retry-after", "3"]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        let other_path = url_path(&server.upload("b.txt", b"hello"), 0);
+        let client = SocketAddr::from(CLIENT_ADDR);
+
+        let first = server.respond(client, get(&path));
+        let second = server.respond(client, get(&path));
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        let response = server.send(get(&path));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "3");
+        assert_eq!(server.send(get(&other_path)).status(), StatusCode::OK);
+
+        // a client disconnecting drops the body.
+        drop(first);
+        let response = server.send(get(&path));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_slice(), b"hello");
+        // the completed download released its slot too.
+        assert_eq!(server.send(get(&path)).status(), StatusCode::OK);
+        drop(second);
+    }
+
+    #[test]
+    fn strip_exif() {
+        let soi_app0 = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+        let app1 = b"\xff\xe1\x00\x0cExif\x00\x00GPS!";
+        let sos = b"\xff\xda\x00\x08\x01\x01\x00\x00\x3f\x00\x12\x34\xff\xd9";
+        let jpeg = [&soi_app0[..], app1, sos].concat();
+        let stripped = [&soi_app0[..], sos].concat();
+
+        let mut server = TestServer::new(&["--strip-exif"]);
+        let raw = server.upload("a.jpg", &jpeg);
+        let body = multipart_body(&[
+            ("file", Some("b.jpg"), &jpeg),
+            ("file", Some("c.txt"), b"Exif"),
+        ]);
+        let multipart = server.send(multipart_upload(vec![body]));
+        for path in &[url_path(&raw, 0), url_path(&multipart, 0)] {
+            let response = server.send(get(path));
+            assert_eq!(response.body(), &stripped, "{}", path);
+        }
+        assert_eq!(
+            json(&raw)["part"][0]["sha256"],
+            Sha256::digest(&stripped).as_str()
+        );
+        assert_eq!(
+            json(&multipart)["part"][0]["sha256"],
+            Sha256::digest(&stripped).as_str()
+        );
+        // other files are kept as is.
+        let response = server.send(get(&url_path(&multipart, 1)));
+        assert_eq!(response.body().as_slice(), b"Exif");
+
+        let mut server = TestServer::new(&[]);
+        let response = server.upload("a.jpg", &jpeg);
+        let response = server.send(get(&url_path(&response, 0)));
+        assert_eq!(response.body(), &jpeg);
+    }
+
+    #[test]
+    fn partial_name() {
+        let partial = partial_path(Path::new("data/0/a.txt"));
+        assert_eq!(partial.parent(), Some(Path::new("data/0")));
+        let name = partial.file_name().unwrap().to_str().unwrap();
+        assert!(is_partial_name(name), "{}", name);
+        assert!(!is_partial_name(".a.txt.partial"));
+        assert!(!is_partial_name("a.txt"));
+    }
+
+    #[test]
+    fn longest_filename() {
+        let mut server = TestServer::new(&[]);
+        let filename = format!("{}.txt", "a".repeat(251));
+        let response = server.upload(&filename, b"raw");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(url_path(&response, 0).ends_with(&filename));
+
+        let body = multipart_body(&[("file", Some(&filename), b"multipart")]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(get(&url_path(&response, 0)));
+        assert_eq!(response.body().as_slice(), b"multipart");
+    }
+
+    #[test]
+    fn sha256_header() {
+        let mut server = TestServer::new(&[]);
+        let response = server.upload("a.txt", b"hello");
+        let sha256 = json(&response)["part"][0]["sha256"].clone();
+        assert_eq!(sha256, Sha256::digest(b"hello").as_str());
+        let response = server.send(get(&url_path(&response, 0)));
+        assert_eq!(response.headers()["x-tp-sha256"], sha256.as_str().unwrap());
+
+        let response = server.send(get("/0/unknown.txt"));
+        assert!(!response.headers().contains_key("x-tp-sha256"));
+    }
+
+    #[test]
+    fn checksums() {
+        const MD5: &str = "5d41402abc4b2a76b9719d911017c592";
+        const SHA1: &str = "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d";
+        const SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let mut server = TestServer::new(&["--checksums", "md5,sha1,sha256"]);
+        // split across the chunks of the multipart body.
+        let body = multipart_body(&[("file", Some("a.txt"), b"hello")]);
+        let (first, second) = body.split_at(body.len() - 20);
+        let responses = vec![
+            server.upload("a.txt", b"hello"),
+            server.send(multipart_upload(vec![first.to_vec(), second.to_vec()])),
+            server.send(urlencoded_upload(b"a.txt=hello")),
+        ];
+        for response in &responses {
+            let part = &json(response)["part"][0];
+            assert_eq!(part["md5"], MD5);
+            assert_eq!(part["sha1"], SHA1);
+            assert_eq!(part["sha256"], SHA256);
+
+            let response = server.send(get(&url_path(response, 0)));
+            assert_eq!(response.headers()["x-tp-md5"], MD5);
+            assert_eq!(response.headers()["x-tp-sha1"], SHA1);
+            assert_eq!(response.headers()["x-tp-sha256"], SHA256);
+        }
+
+        let mut req = raw_upload("a.txt", b"hello");
+        req.headers_mut()
+            .insert(hyper::header::ACCEPT, "application/xml".parse().unwrap());
+        let body = String::from_utf8(server.send(req).body().clone()).unwrap();
+        assert!(body.contains(&format!(
+            "<sha256>{}</sha256><md5>{}</md5><sha1>{}</sha1>",
+            SHA256, MD5, SHA1
+        )));
+
+        let mut server = TestServer::new(&["--checksums", "sha1"]);
+        let response = server.upload("a.txt", b"hello");
+        let part = &json(&response)["part"][0];
+        assert!(part.get("md5").is_none());
+        assert_eq!(part["sha1"], SHA1);
+        let response = server.send(get(&url_path(&response, 0)));
+        assert!(!response.headers().contains_key("x-tp-md5"));
+        assert_eq!(response.headers()["x-tp-sha1"], SHA1);
+
+        let mut server = TestServer::new(&[]);
+        let part = json(&server.upload("a.txt", b"hello"))["part"][0].clone();
+        assert!(part.get("md5").is_none());
+        assert!(part.get("sha1").is_none());
+        assert!(Opt::from_iter_safe(&["transfer", "--checksums", "crc32"]).is_err());
+    }
+
+    #[test]
+    fn digest_header() {
+        let mut server = TestServer::new(&["--root-behavior", "list"]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        // sha-256 of "hello".
+        let expected = "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        let download = |server: &mut TestServer, want_digest: Option<&str>| {
+            let mut req = get(&path);
+            if let Some(want_digest) = want_digest {
+                req.headers_mut()
+                    .insert("want-digest", want_digest.parse().unwrap());
+            }
+            server.send(req)
+        };
+
+        for want_digest in &[None, Some("sha-256"), Some("md5;q=0.5, SHA-256")] {
+            let response = download(&mut server, *want_digest);
+            assert_eq!(response.headers()["digest"], expected, "{:?}", want_digest);
+        }
+        for want_digest in &["md5", "sha-256;q=0"] {
+            let response = download(&mut server, Some(want_digest));
+            assert!(
+                response.headers().get("digest").is_none(),
+                "{}",
+                want_digest
+            );
+        }
+
+        // a range has the digest of the whole file.
+        let mut req = get(&path);
+        req.headers_mut()
+            .insert(hyper::header::RANGE, "bytes=0-1".parse().unwrap());
+        let response = server.send(req);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["digest"], expected);
+
+        // the cache is neither listed nor left behind.
+        let response = server.send(get("/"));
+        assert_eq!(json(&response).as_array().unwrap().len(), 1);
+        let response = server.send(Request::delete(path.as_str()).body(Body::empty()).unwrap());
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn digest_header_of_unknown_file() {
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        // e.g. a file copied into the data directory by hand.
+        let mut filepath = server.data_dir().to_path_buf();
+        filepath.push(path.trim_start_matches('/'));
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::write(&filepath, b"world").unwrap();
+
+        let response = server.send(get(&path));
+        assert_eq!(response.body().as_slice(), b"world");
+        // sha-256 of "world".
+        assert_eq!(
+            response.headers()["digest"],
+            "sha-256=SG6kYiTRu0+2gPNPfJrZao8k7Ii+c+qOWmxlJg6cuKc="
+        );
+    }
+
+    #[test]
+    fn multipart_header_in_single_byte_chunks() {
+        let mut server = TestServer::new(&[]);
+        let name = format!("field-{}", "n".repeat(200));
+        let filename = format!("{}.txt", "\u{3042}\u{1f600}".repeat(20));
+        let body = multipart_body(&[(&name, Some(&filename), b"hello")]);
+        let chunks = body.iter().map(|data| vec![*data]).collect();
+        let response = server.send(multipart_upload(chunks));
+        assert_eq!(response.status(), StatusCode::OK);
+        let ret = json(&response);
+        assert_eq!(ret["part"][0]["name"], name.as_str());
+        assert_eq!(ret["part"][0]["file_name"], filename.as_str());
+        let stored = stored_files(server.data_dir());
+        assert!(stored[0].ends_with(&filename));
+        assert_eq!(std::fs::read(&stored[0]).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn user_key() {
+        let upload = |key: &str, data: &[u8]| {
+            let mut req = raw_upload("a.txt", data);
+            req.headers_mut().insert("x-tp-key", key.parse().unwrap());
+            req
+        };
+        let download = |server: &mut TestServer| server.send(get("/key/a.txt"));
+
+        // ignored without --allow-user-keys.
+        let mut server = TestServer::new(&[]);
+        let response = server.send(upload("key", b"first"));
+        assert!(!url_path(&response, 0).starts_with("/key/"));
+
+        let mut server = TestServer::new(&["--allow-user-keys"]);
+        let response = server.send(upload("key", b"first"));
+        assert_eq!(url_path(&response, 0), "/key/a.txt");
+        assert_eq!(download(&mut server).body().as_slice(), b"first");
+
+        let response = server.send(upload("key", b"second"));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(download(&mut server).body().as_slice(), b"first");
+
+        let body = multipart_body(&[("file", Some("a.txt"), b"second")]);
+        let mut req = multipart_upload(vec![body]);
+        req.headers_mut().insert("x-tp-key", "key".parse().unwrap());
+        let response = server.send(req);
+        assert_eq!(json(&response)["part"][0]["error"], "key already exists");
+        assert_eq!(download(&mut server).body().as_slice(), b"first");
+
+        for key in &["..", ".", "a\\b"] {
+            let response = server.send(upload(key, b"second"));
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", key);
+        }
+
+        let mut server = TestServer::new(&["--allow-user-keys", "--allow-key-overwrite"]);
+        server.send(upload("key", b"first"));
+        let response = server.send(upload("key", b"second"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(download(&mut server).body().as_slice(), b"second");
+    }
+
+    #[test]
+    fn max_multipart_parses() {
+        let mut server = TestServer::new(&["--max-multipart-parses", "1", "--retry-after", "3"]);
+        // an upload in progress.
+        let permit = Semaphore::try_acquire(&server.multipart_semaphore).unwrap();
+        let body = multipart_body(&[("file", Some("a.txt"), b"hello")]);
+        let response = server.send(multipart_upload(vec![body.clone()]));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "3");
+        // raw uploads aren't limited.
+        assert_eq!(server.upload("b.txt", b"hello").status(), StatusCode::OK);
+
+        drop(permit);
+        let response = server.send(multipart_upload(vec![body.clone()]));
+        assert_eq!(response.status(), StatusCode::OK);
+        // the finished upload released its slot.
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn counting_body() {
+        let counting_body = || {
+            let body = Body::wrap_stream(stream::iter_ok::<_, std::io::Error>(vec![
+                b"hello".to_vec(),
+                b"world!".to_vec(),
+            ]));
+            let bytes = Arc::new(Mutex::new(None));
+            let on_end = {
+                let bytes = bytes.clone();
+                move |data| *bytes.lock().unwrap() = Some(data)
+            };
+            (CountingBody::new(body, on_end), bytes)
+        };
+
+        let (body, bytes) = counting_body();
+        let body = body.concat2().wait().unwrap();
+        assert_eq!(body.as_ref(), b"helloworld!");
+        assert_eq!(*bytes.lock().unwrap(), Some(11));
+
+        // dropped by a disconnected client.
+        let (body, bytes) = counting_body();
+        let mut chunks = body.wait();
+        chunks.next().unwrap().unwrap();
+        assert_eq!(*bytes.lock().unwrap(), None);
+        drop(chunks);
+        assert_eq!(*bytes.lock().unwrap(), Some(5));
+    }
+
+    #[test]
+    fn preserve_paths_round_trip() {
+        let mut server = TestServer::new(&["--preserve-paths"]);
+        let body = multipart_body(&[
+            ("file", Some("photos/2019/img.jpg"), b"first"),
+            ("file", Some("photos/2020/img.jpg"), b"second"),
+        ]);
+        let response = server.send(multipart_upload(vec![body]));
+        let first = url_path(&response, 0);
+        let second = url_path(&response, 1);
+        assert!(first.ends_with("/photos/2019/img.jpg"), "{}", first);
+
+        let response = server.send(get(&first));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_slice(), b"first");
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "image/jpeg"
+        );
+        assert_eq!(server.send(get(&second)).body().as_slice(), b"second");
+
+        // a traversal out of the upload, also percent-encoded.
+        let id = first.trim_start_matches('/').split('/').next().unwrap();
+        for path in &[
+            format!("/{}/photos/../../{}/photos/2019/img.jpg", id, id),
+            format!("/{}/photos/%2e%2e/photos/2019/img.jpg", id),
+            format!("/{}/photos%2f2019/img.jpg", id),
+            format!("/{}/photos//2019/img.jpg", id),
+        ] {
+            let response = server.send(get(path));
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert_eq!(
+            server.send(get(&format!("/{}/photos/", id))).status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let delete = |server: &mut TestServer, path: &str| {
+            server
+                .send(Request::delete(path).body(Body::empty()).unwrap())
+                .status()
+        };
+        assert_eq!(delete(&mut server, &first), StatusCode::NO_CONTENT);
+        assert_eq!(server.send(get(&first)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(server.send(get(&second)).body().as_slice(), b"second");
+        assert_eq!(delete(&mut server, &second), StatusCode::NO_CONTENT);
+        // the emptied directories are removed up to the data directory.
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn url_is_percent_encoded() {
+        let mut server = TestServer::new(&["--preserve-paths"]);
+        let filenames = [
+            "a b.txt",
+            "100%.txt",
+            "\u{3042}\u{1f600}.txt",
+            "q?a=1#frag.txt",
+            "dir name/a+b;c.txt",
+        ];
+        let parts = filenames
+            .iter()
+            .map(|data| ("file", Some(*data), &b"hello"[..]))
+            .collect::<Vec<_>>();
+        let response = server.send(multipart_upload(vec![multipart_body(&parts)]));
+        for (index, filename) in filenames.iter().enumerate() {
+            let path = url_path(&response, index);
+            assert!(
+                path.bytes()
+                    .all(|c| c.is_ascii_graphic() && c != b'?' && c != b'#'),
+                "{}",
+                path
+            );
+            let uri = path.parse::<hyper::Uri>().unwrap();
+            assert_eq!(uri.path(), path);
+            assert!(uri.query().is_none(), "{}", path);
+
+            let response = server.send(get(&path));
+            assert_eq!(response.status(), StatusCode::OK, "{}", filename);
+            assert_eq!(response.body().as_slice(), b"hello");
+        }
+        assert!(url_path(&response, 4).ends_with("/dir%20name/a%2Bb%3Bc.txt"));
+    }
+
+    #[test]
+    fn active_content_is_not_served_inline() {
+        let mut server = TestServer::new(&[]);
+        for filename in &["a.html", "a.svg", "a.js", "a.xml"] {
+            let path = url_path(&server.upload(filename, b"<script>alert(1)</script>"), 0);
+            let response = server.send(get(&path));
+            let headers = response.headers();
+            assert_eq!(
+                headers[hyper::header::CONTENT_DISPOSITION],
+                format!("attachment; filename*=UTF-8''{}", filename).as_str()
+            );
+            assert_eq!(headers[hyper::header::CONTENT_SECURITY_POLICY], "sandbox");
+            assert_eq!(headers[hyper::header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        }
+
+        let body = multipart_body(&[("file", Some("\u{3042} b.html"), b"hello")]);
+        let response = server.send(multipart_upload(vec![body]));
+        let response = server.send(get(&url_path(&response, 0)));
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_DISPOSITION],
+            "attachment; filename*=UTF-8''%E3%81%82%20b.html"
+        );
+
+        let path = url_path(&server.upload("a.png", b"hello"), 0);
+        let response = server.send(get(&path));
+        assert!(response
+            .headers()
+            .get(hyper::header::CONTENT_DISPOSITION)
+            .is_none());
+        assert!(response
+            .headers()
+            .get(hyper::header::CONTENT_SECURITY_POLICY)
+            .is_none());
+    }
+
+    #[test]
+    fn range_request() {
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"0123456789"), 0);
+        let download = |server: &mut TestServer, range: &str| {
+            let mut req = get(&path);
+            req.headers_mut()
+                .insert(hyper::header::RANGE, range.parse().unwrap());
+            server.send(req)
+        };
+
+        let response = server.send(get(&path));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[hyper::header::ACCEPT_RANGES], "bytes");
+
+        for (range, content_range, body) in &[
+            ("bytes=2-5", "bytes 2-5/10", &b"2345"[..]),
+            ("bytes=7-", "bytes 7-9/10", b"789"),
+            ("bytes=-2", "bytes 8-9/10", b"89"),
+            ("bytes=5-100", "bytes 5-9/10", b"56789"),
+        ] {
+            let response = download(&mut server, range);
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            let headers = response.headers();
+            assert_eq!(headers[hyper::header::CONTENT_RANGE], *content_range);
+            assert_eq!(
+                headers[hyper::header::CONTENT_LENGTH],
+                body.len().to_string().as_str()
+            );
+            assert_eq!(response.body().as_slice(), *body);
+        }
+
+        let response = download(&mut server, "bytes=10-");
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_RANGE],
+            "bytes */10"
+        );
+        assert!(response.body().is_empty());
+
+        let response = download(&mut server, "bytes=0-1,4-5");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_slice(), b"0123456789");
+    }
+
+    #[test]
+    fn head_request() {
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        let head = |path: &str| {
+            Request::head(path)
+                .header(hyper::header::HOST, "example.com")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let get_response = server.send(get(&path));
+        let response = server.send(head(&path));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.body().is_empty());
+        for name in &[
+            hyper::header::CONTENT_LENGTH,
+            hyper::header::CONTENT_TYPE,
+            hyper::header::LAST_MODIFIED,
+            hyper::header::ACCEPT_RANGES,
+        ] {
+            assert_eq!(
+                response.headers()[name],
+                get_response.headers()[name],
+                "{}",
+                name
+            );
+        }
+        assert_eq!(response.headers()[hyper::header::CONTENT_LENGTH], "5");
+        let last_modified = response.headers()[hyper::header::LAST_MODIFIED]
+            .to_str()
+            .unwrap();
+        assert!(last_modified.ends_with(" GMT"), "{}", last_modified);
+
+        let missing = format!("{}.missing", path);
+        assert_eq!(server.send(head(&missing)).status(), StatusCode::NOT_FOUND);
+    }
+
+    fn delete(path: &str) -> Request<Body> {
+        Request::delete(path).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn tombstones() {
+        let head = |path: &str| Request::head(path).body(Body::empty()).unwrap();
+        let mut server = TestServer::new(&["--track-tombstones", "--allow-user-keys"]);
+        let upload = |server: &mut TestServer, expires: &str| {
+            let mut req = post(&format!("/upload?expires={}", expires), "a.txt", b"hello");
+            req.headers_mut().insert("x-tp-key", "key".parse().unwrap());
+            server.send(req).status()
+        };
+        assert_eq!(upload(&mut server, "1h"), StatusCode::OK);
+        assert_eq!(server.send(head("/key/a.txt")).status(), StatusCode::OK);
+        assert_eq!(
+            server.send(delete("/key/a.txt")).status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(server.send(head("/key/a.txt")).status(), StatusCode::GONE);
+        assert_eq!(server.send(get("/key/a.txt")).status(), StatusCode::GONE);
+        // never existed.
+        assert_eq!(
+            server.send(head("/key/b.txt")).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            server.send(head("/a/a.txt")).status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // stored again.
+        assert_eq!(upload(&mut server, "100ms"), StatusCode::OK);
+        assert_eq!(server.send(head("/key/a.txt")).status(), StatusCode::OK);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(server.send(head("/key/a.txt")).status(), StatusCode::GONE);
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        assert_eq!(server.send(head("/key/a.txt")).status(), StatusCode::GONE);
+        assert!(stored_files(server.data_dir()).is_empty());
+
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        server.send(delete(&path));
+        assert_eq!(server.send(head(&path)).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn delete_file() {
+        let mut server = TestServer::new(&["--allow-user-keys"]);
+        let path = url_path(&server.upload("a.png", b"hello"), 0);
+
+        let response = server.send(delete(&path));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.body().is_empty());
+        assert_eq!(server.send(get(&path)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(server.send(delete(&path)).status(), StatusCode::NOT_FOUND);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+
+        for path in &["/../a.png", "/a/..", "/a/%2e%2e"] {
+            assert_eq!(
+                server.send(delete(path)).status(),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                path
+            );
+        }
+
+        // the directory shared by X-TP-Key is kept for the other file.
+        let upload = |filename: &str| {
+            let mut req = raw_upload(filename, b"hello");
+            req.headers_mut().insert("x-tp-key", "key".parse().unwrap());
+            req
+        };
+        server.send(upload("a.txt"));
+        server.send(upload("b.txt"));
+        assert_eq!(
+            server.send(delete("/key/a.txt")).status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(server.send(get("/key/b.txt")).status(), StatusCode::OK);
+        assert_eq!(
+            server.send(delete("/key/b.txt")).status(),
+            StatusCode::NO_CONTENT
+        );
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn upload_path_traversal() {
+        for preserve_paths in &[false, true] {
+            let args: &[&str] = if *preserve_paths {
+                &["--preserve-paths"]
+            } else {
+                &[]
+            };
+            let mut server = TestServer::new(args);
+            for filename in &["../../etc/passwd", "..", "a/../../b"] {
+                let response = server.upload(filename, b"hello");
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", filename);
+            }
+            let body = multipart_body(&[
+                ("file", Some("../a.txt"), b"hello"),
+                ("file", Some("a\0.txt"), b"hello"),
+                ("file", Some("/etc/passwd"), b"hello"),
+            ]);
+            let response = server.send(multipart_upload(vec![body]));
+            let ret = json(&response);
+            assert_eq!(ret["part"][1]["file_name"], "../a.txt");
+            assert_eq!(ret["part"][1]["error"], "invalid filename");
+            assert_eq!(ret["part"][2]["file_name"], "a\u{0}.txt");
+            assert_eq!(ret["part"][2]["error"], "invalid filename");
+
+            // a leading slash is relative to the upload.
+            let expected = if *preserve_paths {
+                "/etc/passwd"
+            } else {
+                "/passwd"
+            };
+            assert!(url_path(&response, 0).ends_with(expected));
+            let stored = stored_files(server.data_dir());
+            assert_eq!(stored.len(), 1);
+            assert!(stored[0].ends_with(expected.trim_start_matches('/')));
+        }
+    }
+
+    fn urlencoded_upload(body: &[u8]) -> Request<Body> {
+        Request::post("/upload")
+            .header(hyper::header::HOST, "example.com")
+            .header(
+                hyper::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(Body::from(body.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn urlencoded_fields() {
+        let mut server = TestServer::new(&[]);
+        let response = server.send(urlencoded_upload(
+            b"a.txt=hello+world%21&b%20c.txt=%E3%81%82",
+        ));
+        assert_eq!(response.status(), StatusCode::OK);
+        let ret = json(&response);
+        assert_eq!(ret["part"][0]["name"], "a.txt");
+        assert_eq!(ret["part"][1]["name"], "b c.txt");
+        let response_a = server.send(get(&url_path(&response, 0)));
+        assert_eq!(response_a.body().as_slice(), b"hello world!");
+        let response_b = server.send(get(&url_path(&response, 1)));
+        assert_eq!(response_b.body().as_slice(), "\u{3042}".as_bytes());
+    }
+
+    #[test]
+    fn urlencoded_keyless_fields() {
+        // e.g. `curl --data-urlencode @file`.
+        let mut server = TestServer::new(&[]);
+        let response = server.send(urlencoded_upload(b"first&&second%0A"));
+        let ret = json(&response);
+        assert_eq!(ret["part"].as_array().unwrap().len(), 2);
+        assert!(url_path(&response, 0).ends_with("/a-1"));
+        assert!(url_path(&response, 1).ends_with("/a-2"));
+        let response = server.send(get(&url_path(&response, 1)));
+        assert_eq!(response.body().as_slice(), b"second\n");
+    }
+
+    #[test]
+    fn urlencoded_invalid_fields() {
+        let mut server = TestServer::new(&[]);
+        let response = server.send(urlencoded_upload(b"a.txt=%zz&%ff=b&c.txt=ok"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let ret = json(&response);
+        assert!(url_path(&response, 0).ends_with("/c.txt"));
+        assert_eq!(ret["part"][1]["error"], "invalid field value");
+        assert_eq!(ret["part"][2]["error"], "invalid field name");
+        assert_eq!(stored_files(server.data_dir()).len(), 1);
+    }
+
+    fn with_header(mut req: Request<Body>, name: &'static str, value: &str) -> Request<Body> {
+        req.headers_mut().insert(name, value.parse().unwrap());
+        req
+    }
+
+    #[test]
+    fn auth_token_uploads() {
+        let mut server = TestServer::new(&["--auth-token", "secret"]);
+        let response = server.upload("a.txt", b"hello");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[hyper::header::WWW_AUTHENTICATE],
+            "Bearer"
+        );
+        for (name, value) in &[
+            ("authorization", "Bearer secre"),
+            ("authorization", "Bearer secrets"),
+            ("authorization", "Basic secret"),
+            ("x-tp-token", "Secret"),
+        ] {
+            let response = server.send(with_header(raw_upload("a.txt", b"hello"), name, value));
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", value);
+        }
+        assert!(stored_files(server.data_dir()).is_empty());
+
+        for (name, value) in &[
+            ("authorization", "Bearer secret"),
+            ("authorization", "bearer secret"),
+            ("x-tp-token", "secret"),
+        ] {
+            let response = server.send(with_header(raw_upload("a.txt", b"hello"), name, value));
+            assert_eq!(response.status(), StatusCode::OK, "{}", value);
+        }
+        assert_eq!(stored_files(server.data_dir()).len(), 3);
+    }
+
+    #[test]
+    fn auth_token_deletions_and_list() {
+        let mut server = TestServer::new(&["--auth-token", "secret", "--root-behavior", "list"]);
+        let response = server.send(with_header(
+            raw_upload("a.txt", b"hello"),
+            "x-tp-token",
+            "secret",
+        ));
+        let path = url_path(&response, 0);
+        // downloads are public without --auth-downloads.
+        assert_eq!(server.send(get(&path)).status(), StatusCode::OK);
+
+        assert_eq!(server.send(get("/")).status(), StatusCode::UNAUTHORIZED);
+        let response = server.send(with_header(get("/"), "x-tp-token", "secret"));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(
+            server.send(delete(&path)).status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(stored_files(server.data_dir()).len(), 1);
+        let response = server.send(with_header(delete(&path), "x-tp-token", "secret"));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn auth_downloads() {
+        let mut server = TestServer::new(&["--auth-token", "secret", "--auth-downloads"]);
+        let response = server.send(with_header(
+            raw_upload("a.txt", b"hello"),
+            "authorization",
+            "Bearer secret",
+        ));
+        let path = url_path(&response, 0);
+        assert_eq!(server.send(get(&path)).status(), StatusCode::UNAUTHORIZED);
+        let response = server.send(with_header(get(&path), "authorization", "Bearer secret"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_slice(), b"hello");
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn request_id_from_client() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-request-id", value.parse().unwrap());
+            headers
+        };
+        assert_eq!(request_id(&headers("abc-123")), "abc-123");
+        let longest = "a".repeat(MAX_REQUEST_ID_LENGTH);
+        assert_eq!(request_id(&headers(&longest)), longest);
+
+        // generated instead of being written to the logs as is.
+        let invalid = &[
+            "".to_owned(),
+            "a b".to_owned(),
+            "a\tb".to_owned(),
+            "a".repeat(MAX_REQUEST_ID_LENGTH + 1),
+        ];
+        for value in invalid {
+            let generated = request_id(&headers(value));
+            assert!(uuid::Uuid::parse_str(&generated).is_ok(), "{:?}", value);
+        }
+        let generated = request_id(&HeaderMap::new());
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+        assert_ne!(generated, request_id(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn require_filename() {
+        let mut server = TestServer::new(&["--require-filename"]);
+        let mut req = raw_upload("a.txt", b"hello");
+        req.headers_mut().remove("x-tp-filename");
+        let response = server.send(req);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = multipart_body(&[
+            ("file", Some("a.txt"), b"hello"),
+            ("comment", None, b"world"),
+        ]);
+        let response = server.send(multipart_upload(vec![body.clone()]));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json(&response)["error"], "missing filename");
+        assert!(stored_files(server.data_dir()).is_empty());
+
+        // a form field without --require-filename.
+        let mut server = TestServer::new(&[]);
+        let response = server.send(multipart_upload(vec![body]));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json(&response)["field"][0]["value"], "world");
+        assert_eq!(stored_files(server.data_dir()).len(), 1);
+    }
+
+    #[test]
+    fn options_asterisk() {
+        let mut server = TestServer::new(&[]);
+        let req = Request::options("*")
+            .header(hyper::header::HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = server.send(req);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[hyper::header::ALLOW],
+            "GET, HEAD, POST, DELETE, OPTIONS"
+        );
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn cors_preflight() {
+        let mut server = TestServer::new(&["--cors-origin", "https://app.example.com"]);
+        let req = Request::options("/upload")
+            .header(hyper::header::ORIGIN, "https://app.example.com")
+            .header(hyper::header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(
+                hyper::header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "x-tp-filename, idempotency-key",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let response = server.send(req);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[hyper::header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, HEAD, POST, DELETE"
+        );
+        assert_eq!(
+            headers[hyper::header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "x-tp-filename, idempotency-key"
+        );
+        assert!(headers.contains_key(hyper::header::ACCESS_CONTROL_MAX_AGE));
+
+        let mut headers = HeaderMap::new();
+        add_cors_headers(&mut headers, "https://app.example.com");
+        assert_eq!(
+            headers[hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+    }
+
+    #[test]
+    fn max_age() {
+        let mut server = TestServer::new(&["--max-age", "500ms", "--root-behavior", "list"]);
+        let path = url_path(&server.upload("a.txt", b"hello"), 0);
+        assert_eq!(server.send(get(&path)).status(), StatusCode::OK);
+        assert_eq!(json(&server.send(get("/"))).as_array().unwrap().len(), 1);
+
+        std::thread::sleep(Duration::from_millis(600));
+        // hidden before the periodic removal.
+        assert_eq!(server.send(get(&path)).status(), StatusCode::NOT_FOUND);
+        assert!(json(&server.send(get("/"))).as_array().unwrap().is_empty());
+
+        let fresh = url_path(&server.upload("b.txt", b"hello"), 0);
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        let files = stored_files(server.data_dir());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(fresh.trim_start_matches('/')));
+    }
+
+    #[test]
+    fn upload_max_age() {
+        let upload = |server: &mut TestServer, filename: &str, max_age: &str| {
+            let mut req =
+                multipart_upload(vec![multipart_body(&[("file", Some(filename), b"hello")])]);
+            req.headers_mut()
+                .insert("x-tp-max-age", max_age.parse().unwrap());
+            server.send(req)
+        };
+        let mut server = TestServer::new(&["--max-age", "1s", "--preserve-paths"]);
+        let short = url_path(&upload(&mut server, "a/b/short.txt", "300ms"), 0);
+        // clamped to --max-age.
+        let long = url_path(&upload(&mut server, "long.txt", "1h"), 0);
+        let response = upload(&mut server, "c.txt", "soon");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // downloads don't extend the life of an upload.
+        for _ in 0..2 {
+            assert_eq!(server.send(get(&short)).status(), StatusCode::OK);
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(server.send(get(&short)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(server.send(get(&long)).status(), StatusCode::OK);
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        let files = stored_files(server.data_dir());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(long.trim_start_matches('/')));
+
+        std::thread::sleep(Duration::from_millis(800));
+        assert_eq!(server.send(get(&long)).status(), StatusCode::NOT_FOUND);
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+
+        // the header alone expires an upload without --max-age.
+        let mut server = TestServer::new(&[]);
+        let path = url_path(&upload(&mut server, "a.txt", "100ms"), 0);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(server.send(get(&path)).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn upload_expires_in() {
+        let mut server = TestServer::new(&["--max-age", "3d"]);
+        let mut expires = |header: Option<&str>, query: &str| {
+            let mut req = post(&format!("/upload{}", query), "a.txt", b"hello");
+            if let Some(header) = header {
+                req.headers_mut()
+                    .insert("x-tp-expires-in", header.parse().unwrap());
+            }
+            let response = server.send(req);
+            if response.status() != StatusCode::OK {
+                return Err(response.status());
+            }
+            let path = url_path(&response, 0);
+            let filepath = server.data_dir().join(path.trim_start_matches('/'));
+            let metadata = read_metadata(server.data_dir(), &filepath).unwrap();
+            Ok(metadata.expires.unwrap() - metadata.uploaded)
+        };
+        let hour = 3_600_000;
+        assert_eq!(expires(Some("2h"), ""), Ok(2 * hour));
+        assert_eq!(expires(None, "?expires=2h"), Ok(2 * hour));
+        assert_eq!(expires(None, "?expires=30m"), Ok(hour / 2));
+        // clamped to --max-age.
+        assert_eq!(expires(Some("7d"), ""), Ok(72 * hour));
+        assert_eq!(expires(None, "?expires=7d"), Ok(72 * hour));
+        // the header wins over the query.
+        assert_eq!(expires(Some("1h"), "?expires=2h"), Ok(hour));
+        assert_eq!(expires(Some("soon"), ""), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(expires(None, "?expires=-1h"), Err(StatusCode::BAD_REQUEST));
+
+        let mut server = TestServer::new(&["--root-behavior", "list"]);
+        let response = server.send(post("/upload?expires=100ms", "a.txt", b"hello"));
+        let path = url_path(&response, 0);
+        assert_eq!(server.send(get(&path)).status(), StatusCode::OK);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(server.send(get(&path)).status(), StatusCode::NOT_FOUND);
+        assert!(json(&server.send(get("/"))).as_array().unwrap().is_empty());
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn expiry_waits_for_downloads() {
+        let mut server = TestServer::new(&["--max-age", "100ms"]);
+        let data = (0..DOWNLOAD_CHUNK_SIZE * 2)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let path = url_path(&server.upload("a.bin", &data), 0);
+        let req = Request::get(path.as_str())
+            .header(hyper::header::RANGE, "bytes=10-")
+            .body(Body::empty())
+            .unwrap();
+        let response = server.respond(SocketAddr::from(CLIENT_ADDR), req);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+
+        std::thread::sleep(Duration::from_millis(200));
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        assert_eq!(stored_files(server.data_dir()).len(), 1);
+        let body = server.runtime.block_on(response.into_body().concat2());
+        assert_eq!(body.unwrap().as_ref(), &data[10..]);
+
+        remove_expired_uploads(&server.opt, &server.download_semaphore);
+        assert!(server.data_dir().read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn shutdown_after_idle() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        let started = Instant::now();
+        // a request in the middle postpones the shutdown.
+        let thread = {
+            let last_activity = last_activity.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(500));
+                *last_activity.lock().unwrap() = Instant::now();
+            })
+        };
+        runtime
+            .block_on(idle_timeout(last_activity, Duration::from_secs(1)))
+            .unwrap();
+        thread.join().unwrap();
+        assert!(Duration::from_millis(1500) <= started.elapsed());
+    }
+
+    /// Serves the requests on an ephemeral loopback port for the tests that need a real
+    /// connection, storing the files in a temporary directory.
+    struct LiveServer {
+        _dir: TestDir,
+        addr: SocketAddr,
+        shutdown: Option<futures::sync::oneshot::Sender<()>>,
+        runtime: Option<tokio::runtime::Runtime>,
+    }
+
+    impl LiveServer {
+        fn new(args: &[&str]) -> Self {
+            let dir = TestDir::new();
+            let data_dir = dir.0.to_str().unwrap().to_owned();
+            let opt = Opt::from_iter_safe(
+                ["transfer", "--port", "0", "--data-dir", &data_dir]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+            let (shutdown_sender, shutdown_receiver) = futures::sync::oneshot::channel::<()>();
+            let download_semaphore = new_download_semaphore(&opt);
+            let (addr, server) = bind_server(
+                Arc::new(opt),
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                None,
+                download_semaphore,
+                Arc::new(Mutex::new(Instant::now())),
+                Arc::new(AtomicUsize::new(0)),
+                shutdown_receiver.map_err(|_| ()),
+            )
+            .unwrap();
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.spawn(server.map_err(|e| warn!("server error: {}", e)));
+            Self {
+                _dir: dir,
+                addr,
+                shutdown: Some(shutdown_sender),
+                runtime: Some(runtime),
+            }
+        }
+
+        fn connect(&self) -> std::net::TcpStream {
+            let stream = std::net::TcpStream::connect(self.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            stream
+        }
+
+        /// Sends `request` on a new connection and returns the response.
+        fn request(&self, request: &[u8]) -> (String, Vec<u8>) {
+            let mut stream = self.connect();
+            stream.write_all(request).unwrap();
+            read_response(&mut stream)
+        }
+    }
+
+    impl Drop for LiveServer {
+        fn drop(&mut self) {
+            if let Some(shutdown) = self.shutdown.take() {
+                shutdown.send(()).ok();
+            }
+            if let Some(runtime) = self.runtime.take() {
+                runtime.shutdown_now().wait().ok();
+            }
+        }
+    }
+
+    /// Reads a response and returns its head and its body framed by Content-Length, the chunked
+    /// transfer coding or the end of the connection.
+    fn read_response(stream: &mut std::net::TcpStream) -> (String, Vec<u8>) {
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let mut body = Vec::new();
+        if let Some(len) = response_header(&head, "content-length") {
+            body.resize(len.parse().unwrap(), 0);
+            reader.read_exact(&mut body).unwrap();
+        } else if response_header(&head, "transfer-encoding")
+            .as_ref()
+            .map(String::as_str)
+            == Some("chunked")
+        {
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else {
+            reader.read_to_end(&mut body).unwrap();
+        }
+        (head, body)
+    }
+
+    fn response_header(head: &str, name: &str) -> Option<String> {
+        head.lines().skip(1).find_map(|line| {
+            let mut field = line.splitn(2, ':');
+            match (field.next(), field.next()) {
+                (Some(key), Some(value)) if key.eq_ignore_ascii_case(name) => {
+                    Some(value.trim().to_owned())
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the path of the URL of the first part of the upload result in `body`.
+    fn body_url_path(body: &[u8]) -> String {
+        let url = serde_json::from_slice::<serde_json::Value>(body).unwrap()["part"][0]["url"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        url.trim_start_matches("http://example.com").to_owned()
+    }
+
+    /// Encodes `data` with the chunked transfer coding in chunks of `size` bytes.
+    fn chunked_body(data: &[u8], size: usize) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in data.chunks(size) {
+            body.extend(format!("{:x}\r\n", chunk.len()).bytes());
+            body.extend_from_slice(chunk);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"0\r\n\r\n");
+        body
+    }
+
+    #[test]
+    fn chunked_uploads() {
+        let server = LiveServer::new(&[]);
+        let data = (0..=DOWNLOAD_CHUNK_SIZE * 2)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let raw = (
+            "Content-Type: application/octet-stream\r\nX-TP-Filename: a.bin\r\n".to_owned(),
+            data.clone(),
+        );
+        let multipart = (
+            format!(
+                "Content-Type: multipart/form-data; boundary={}\r\n",
+                BOUNDARY
+            ),
+            multipart_body(&[("file", Some("a.bin"), &data)]),
+        );
+        for (headers, body) in vec![raw, multipart] {
+            let mut request = format!(
+                "POST /upload HTTP/1.1\r\nHost: example.com\r\n{}\
+                 Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+                headers
+            )
+            .into_bytes();
+            request.extend(chunked_body(&body, 1000));
+            let (head, body) = server.request(&request);
+            assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+            let url = body_url_path(&body);
+
+            let (head, body) = server.request(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+                    url
+                )
+                .as_bytes(),
+            );
+            assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+            assert_eq!(body, data);
+        }
+    }
+
+    #[test]
+    fn connection_persistence() {
+        let server = LiveServer::new(&[]);
+        let (_, body) = server.request(
+            b"POST /upload HTTP/1.1\r\nHost: example.com\r\n\
+              Content-Type: application/octet-stream\r\nX-TP-Filename: a.txt\r\n\
+              Content-Length: 5\r\nConnection: close\r\n\r\nhello",
+        );
+        let url = body_url_path(&body);
+        let request = |version: &str, connection: Option<&str>| {
+            let connection = connection
+                .map(|value| format!("Connection: {}\r\n", value))
+                .unwrap_or_default();
+            format!(
+                "GET {} {}\r\nHost: example.com\r\n{}\r\n",
+                url, version, connection
+            )
+        };
+        let closed = |stream: &mut std::net::TcpStream| stream.read(&mut [0; 1]).unwrap() == 0;
+
+        for (version, connection) in &[
+            ("HTTP/1.0", None),
+            ("HTTP/1.0", Some("close")),
+            ("HTTP/1.1", Some("close")),
+        ] {
+            let mut stream = server.connect();
+            stream
+                .write_all(request(version, *connection).as_bytes())
+                .unwrap();
+            let (head, body) = read_response(&mut stream);
+            assert!(head.contains(" 200 "), "{}", head);
+            assert_eq!(body, b"hello");
+            assert!(closed(&mut stream), "{} {:?}", version, connection);
+        }
+
+        for (version, connection) in &[("HTTP/1.0", Some("keep-alive")), ("HTTP/1.1", None)] {
+            let mut stream = server.connect();
+            for _ in 0..2 {
+                stream
+                    .write_all(request(version, *connection).as_bytes())
+                    .unwrap();
+                let (head, body) = read_response(&mut stream);
+                assert!(head.contains(" 200 "), "{}", head);
+                assert_eq!(body, b"hello");
+            }
+            stream
+                .write_all(request(version, Some("close")).as_bytes())
+                .unwrap();
+            read_response(&mut stream);
+            assert!(closed(&mut stream), "{} {:?}", version, connection);
+        }
+    }
+
+    #[test]
+    fn slow_large_upload() {
+        let server = LiveServer::new(&[]);
+        let data = (0..4 * 1024 * 1024)
+            .map(|_| rand::random::<u8>())
+            .collect::<Vec<_>>();
+        let body = multipart_body(&[("file", Some("a.bin"), &data)]);
+        let mut stream = server.connect();
+        write!(
+            stream,
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\n\
+             Content-Type: multipart/form-data; boundary={}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            BOUNDARY,
+            body.len()
+        )
+        .unwrap();
+        let pieces = body.chunks(body.len() / 32 + 1).collect::<Vec<_>>();
+        for (index, piece) in pieces.iter().enumerate() {
+            stream.write_all(piece).unwrap();
+            std::thread::sleep(Duration::from_millis(30));
+            if index == pieces.len() / 2 {
+                // nothing is written before the result of the whole upload.
+                stream
+                    .set_read_timeout(Some(Duration::from_millis(100)))
+                    .unwrap();
+                assert!(stream.peek(&mut [0; 1]).is_err());
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+            }
+        }
+        let (head, body) = read_response(&mut stream);
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+
+        let (head, body) = server.request(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+                body_url_path(&body)
+            )
+            .as_bytes(),
+        );
+        assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+        assert!(body == data);
+    }
+}
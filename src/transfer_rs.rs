@@ -14,4 +14,27 @@
  * limitations under the License.
  */
 
+pub mod access_log;
+pub mod checksum;
+pub mod cidr;
+pub mod content_type;
+pub mod digest;
+pub mod exif;
+pub mod filename;
+pub mod forwarded;
+pub mod http_date;
+pub mod id;
+pub mod idempotency;
+pub mod logger;
+pub mod md5;
+pub mod metadata;
+pub mod percent_encoding;
 pub mod prelude;
+pub mod range;
+pub mod semaphore;
+pub mod sha1;
+pub mod sha256;
+pub mod size;
+pub mod tar;
+pub mod thumbnail;
+pub mod tls;
@@ -0,0 +1,220 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::transfer_rs::http_date::{civil_from_days, MONTHS};
+
+/// A handled request to write to the access log.
+pub struct AccessLogEntry {
+    /// Identifies the request among the other log lines.
+    pub request_id: String,
+    pub client: IpAddr,
+    pub time: SystemTime,
+    pub method: String,
+    pub uri: String,
+    /// The protocol of the request, e.g. "HTTP/1.1".
+    pub version: String,
+    pub status: u16,
+    /// The bytes of the response body sent to the client.
+    pub bytes: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessLogEntry {
+    /// Returns the request line, e.g. "GET /index.html HTTP/1.1".
+    pub fn request_line(&self) -> String {
+        format!("{} {} {}", self.method, self.uri, self.version)
+    }
+
+    pub fn to_text(&self) -> String {
+        format!(
+            "{} \"{}\" {} {}",
+            self.client,
+            self.request_line(),
+            self.status,
+            format_bytes(self.bytes)
+        )
+    }
+
+    /// Formats the entry in the Apache combined log format.
+    pub fn to_combined(&self) -> String {
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+            self.client,
+            format_clf_time(self.time),
+            escape(&self.request_line()),
+            self.status,
+            format_bytes(self.bytes),
+            self.referer
+                .as_ref()
+                .map(|data| escape(data))
+                .unwrap_or_else(|| "-".to_owned()),
+            self.user_agent
+                .as_ref()
+                .map(|data| escape(data))
+                .unwrap_or_else(|| "-".to_owned()),
+        )
+    }
+
+    /// Formats the entry as a JSON object in a line.
+    pub fn to_json(&self) -> String {
+        json!({
+            "time": humantime::format_rfc3339_seconds(self.time).to_string(),
+            "request_id": self.request_id,
+            "client": self.client.to_string(),
+            "method": self.method,
+            "uri": self.uri,
+            "version": self.version,
+            "status": self.status,
+            "bytes": self.bytes,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+}
+
+/// Formats `bytes` like "%b" of Apache, i.e. "-" for no bytes.
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0 => "-".to_owned(),
+        _ => bytes.to_string(),
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if c.is_control() => ret.push_str(&format!("\\x{:02x}", c as u32)),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Formats `time` in UTC like "10/Oct/2000:13:55:36 +0000".
+fn format_clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|data| data.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            request_id: "req-1".to_owned(),
+            client: IpAddr::from([192, 0, 2, 1]),
+            // 2000-10-10T13:55:36Z
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: "GET".to_owned(),
+            uri: "/abc/a.txt".to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            status: 200,
+            bytes: 2326,
+            referer: Some("http://example.com/start.html".to_owned()),
+            user_agent: Some("Mozilla/4.08 [en] (Win98; I ;Nav)".to_owned()),
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            entry().to_text(),
+            r#"192.0.2.1 "GET /abc/a.txt HTTP/1.1" 200 2326"#
+        );
+    }
+
+    #[test]
+    fn combined() {
+        assert_eq!(
+            entry().to_combined(),
+            r#"192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] "GET /abc/a.txt HTTP/1.1" 200 2326 "http://example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#
+        );
+    }
+
+    #[test]
+    fn combined_without_optional_fields() {
+        let entry = AccessLogEntry {
+            client: "2001:db8::1".parse().unwrap(),
+            status: 204,
+            bytes: 0,
+            referer: None,
+            user_agent: None,
+            ..entry()
+        };
+        assert_eq!(
+            entry.to_combined(),
+            r#"2001:db8::1 - - [10/Oct/2000:13:55:36 +0000] "GET /abc/a.txt HTTP/1.1" 204 - "-" "-""#
+        );
+    }
+
+    #[test]
+    fn combined_escapes_quotes_and_controls() {
+        let entry = AccessLogEntry {
+            uri: "/a\"b".to_owned(),
+            user_agent: Some("x\\y\nz".to_owned()),
+            ..entry()
+        };
+        let line = entry.to_combined();
+        assert!(line.contains(r#""GET /a\"b HTTP/1.1""#), "{}", line);
+        assert!(line.ends_with(r#""x\\y\x0az""#), "{}", line);
+    }
+
+    #[test]
+    fn json() {
+        let line = serde_json::from_str::<serde_json::Value>(&entry().to_json()).unwrap();
+        assert_eq!(line["time"], "2000-10-10T13:55:36Z");
+        assert_eq!(line["request_id"], "req-1");
+        assert_eq!(line["client"], "192.0.2.1");
+        assert_eq!(line["method"], "GET");
+        assert_eq!(line["uri"], "/abc/a.txt");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 2326);
+        assert_eq!(line["referer"], "http://example.com/start.html");
+
+        let entry = AccessLogEntry {
+            referer: None,
+            ..entry()
+        };
+        let line = serde_json::from_str::<serde_json::Value>(&entry.to_json()).unwrap();
+        assert!(line["referer"].is_null());
+    }
+}
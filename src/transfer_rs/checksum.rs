@@ -0,0 +1,138 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+
+use failure::format_err;
+
+use crate::transfer_rs::md5::Md5;
+use crate::transfer_rs::prelude::*;
+use crate::transfer_rs::sha1::Sha1;
+use crate::transfer_rs::sha256::Sha256;
+
+/// A digest algorithm of `--checksums`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl FromStr for Algorithm {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s {
+            "md5" => Ok(Algorithm::Md5),
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            _ => Err(format_err!("unknown checksum algorithm: {}", s)),
+        }
+    }
+}
+
+/// Computes the SHA-256 digest, and the other digests of `algorithms`, in a single pass over data
+/// fed in pieces.
+pub struct Hasher {
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    sha256: Sha256,
+}
+
+/// The lowercase hex digests of a file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Checksums {
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub sha256: String,
+}
+
+impl Hasher {
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        Self {
+            md5: if algorithms.contains(&Algorithm::Md5) {
+                Some(Md5::new())
+            } else {
+                None
+            },
+            sha1: if algorithms.contains(&Algorithm::Sha1) {
+                Some(Sha1::new())
+            } else {
+                None
+            },
+            sha256: Sha256::new(),
+        }
+    }
+
+    /// Returns the digests of `data`.
+    pub fn digest(algorithms: &[Algorithm], data: &[u8]) -> Checksums {
+        let mut hasher = Self::new(algorithms);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+        if let Some(sha1) = &mut self.sha1 {
+            sha1.update(data);
+        }
+        self.sha256.update(data);
+    }
+
+    pub fn finish(self) -> Checksums {
+        Checksums {
+            md5: self.md5.map(Md5::finish),
+            sha1: self.sha1.map(Sha1::finish),
+            sha256: self.sha256.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_algorithms() {
+        assert_eq!("md5".parse::<Algorithm>().unwrap(), Algorithm::Md5);
+        assert_eq!("sha1".parse::<Algorithm>().unwrap(), Algorithm::Sha1);
+        assert_eq!("sha256".parse::<Algorithm>().unwrap(), Algorithm::Sha256);
+        assert!("crc32".parse::<Algorithm>().is_err());
+        assert!("MD5".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn digest_only_the_algorithms() {
+        let checksums = Hasher::digest(&[Algorithm::Md5, Algorithm::Sha1], b"abc");
+        assert_eq!(
+            checksums,
+            Checksums {
+                md5: Some("900150983cd24fb0d6963f7d28e17f72".to_owned()),
+                sha1: Some("a9993e364706816aba3e25717850c26c9cd0d89d".to_owned()),
+                sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_owned(),
+            }
+        );
+
+        // SHA-256 is always computed.
+        let checksums = Hasher::digest(&[], b"abc");
+        assert_eq!(checksums.md5, None);
+        assert_eq!(checksums.sha1, None);
+        assert_eq!(checksums.sha256, Sha256::digest(b"abc"));
+    }
+}
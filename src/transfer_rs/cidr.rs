@@ -0,0 +1,139 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use failure::format_err;
+
+use crate::transfer_rs::prelude::*;
+
+/// An address range such as `10.0.0.0/8` or `fd00::/8`. A bare address is treated as a single host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, to_canonical(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::max_value()
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::max_value()
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut value = s.splitn(2, '/');
+        let addr = to_canonical(value.next().unwrap_or_default().parse::<IpAddr>()?);
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match value.next() {
+            Some(data) => data.parse::<u8>()?,
+            None => max_prefix,
+        };
+        if max_prefix < prefix {
+            return Err(format_err!("invalid prefix length: {}", s));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Converts an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to IPv4.
+fn to_canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|data| *data == 0) && segments[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new(
+                    (segments[6] >> 8) as u8,
+                    segments[6] as u8,
+                    (segments[7] >> 8) as u8,
+                    segments[7] as u8,
+                ))
+            } else {
+                addr
+            }
+        }
+        IpAddr::V4(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(cidr: &str, addr: &str) -> bool {
+        cidr.parse::<Cidr>()
+            .unwrap()
+            .contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+        assert!(contains("0.0.0.0/0", "203.0.113.1"));
+        assert!(contains("203.0.113.7", "203.0.113.7"));
+        assert!(!contains("203.0.113.7", "203.0.113.8"));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("2001:db8::1", "2001:db8::1"));
+        assert!(!contains("2001:db8::/32", "10.0.0.1"));
+    }
+
+    #[test]
+    fn contains_ipv4_mapped_ipv6() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+    }
+
+    #[test]
+    fn parse_rejects_invalid() {
+        for value in &[
+            "",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "host/8",
+        ] {
+            assert!(value.parse::<Cidr>().is_err(), "{}", value);
+        }
+    }
+}
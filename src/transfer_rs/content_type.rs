@@ -0,0 +1,146 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// (content-type, canonical extension)
+///
+/// Only types that have a single obvious extension are listed.
+const CANONICAL_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/gzip", "gz"),
+    ("application/json", "json"),
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("image/bmp", "bmp"),
+    ("image/gif", "gif"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/svg+xml", "svg"),
+    ("image/webp", "webp"),
+    ("text/css", "css"),
+    ("text/csv", "csv"),
+    ("text/html", "html"),
+    ("text/plain", "txt"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+];
+
+/// (extension, content-type) of the extensions missing in `CANONICAL_EXTENSIONS`.
+const OTHER_EXTENSIONS: &[(&str, &str)] = &[
+    ("htm", "text/html"),
+    ("jpeg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("md", "text/markdown"),
+    ("mov", "video/quicktime"),
+    ("tar", "application/x-tar"),
+    ("wav", "audio/wav"),
+    ("xml", "application/xml"),
+];
+
+/// Types that browsers run scripts of when opened from the server's origin.
+const ACTIVE_TYPES: &[&str] = &[
+    "application/xhtml+xml",
+    "application/xml",
+    "image/svg+xml",
+    "text/html",
+    "text/javascript",
+    "text/xml",
+];
+
+/// Strips parameters (e.g. `; charset=utf-8`) and normalizes the case.
+pub fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    let essence = essence(content_type);
+    CANONICAL_EXTENSIONS
+        .iter()
+        .find(|(mime, _)| *mime == essence)
+        .map(|(_, ext)| *ext)
+}
+
+/// Guesses the content-type from the extension of `filename`, `application/octet-stream` if unknown.
+pub fn for_filename(filename: &str) -> &'static str {
+    let ext = match filename.rfind('.') {
+        Some(index) => filename[index + 1..].to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+    CANONICAL_EXTENSIONS
+        .iter()
+        .find(|(_, data)| *data == ext)
+        .map(|(mime, _)| *mime)
+        .or_else(|| {
+            OTHER_EXTENSIONS
+                .iter()
+                .find(|(data, _)| *data == ext)
+                .map(|(_, mime)| *mime)
+        })
+        .unwrap_or("application/octet-stream")
+}
+
+/// Returns whether serving `content_type` inline lets an uploaded file run scripts on the server's
+/// origin, i.e. a stored XSS.
+pub fn is_active(content_type: &str) -> bool {
+    ACTIVE_TYPES.contains(&essence(content_type).as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn essence_strips_parameters() {
+        assert_eq!(essence("Text/Plain; charset=utf-8"), "text/plain");
+        assert_eq!(essence(" image/png "), "image/png");
+    }
+
+    #[test]
+    fn extension_for_known_types() {
+        assert_eq!(extension_for("image/jpeg"), Some("jpg"));
+        assert_eq!(
+            extension_for("Application/JSON; charset=utf-8"),
+            Some("json")
+        );
+        assert_eq!(extension_for("application/octet-stream"), None);
+        assert_eq!(extension_for(""), None);
+    }
+
+    #[test]
+    fn for_filename_by_extension() {
+        assert_eq!(for_filename("a.PNG"), "image/png");
+        assert_eq!(for_filename("a.jpeg"), "image/jpeg");
+        assert_eq!(for_filename("a.tar.gz"), "application/gzip");
+        assert_eq!(for_filename("a"), "application/octet-stream");
+        assert_eq!(for_filename("a.unknown"), "application/octet-stream");
+    }
+
+    #[test]
+    fn active_types() {
+        for filename in &["a.html", "a.HTM", "a.svg", "a.js", "a.xml"] {
+            assert!(is_active(for_filename(filename)), "{}", filename);
+        }
+        assert!(is_active("Text/HTML; charset=utf-8"));
+        for filename in &["a.txt", "a.png", "a.pdf", "a.json", "a.css", "a"] {
+            assert!(!is_active(for_filename(filename)), "{}", filename);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::transfer_rs::sha256::Sha256;
+
+const CACHE_SUFFIX: &str = ".sha256";
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Returns the name of the file caching the digest of `filename`, e.g. `.2c26b46b68ffc68f.sha256`.
+pub fn cache_name(filename: &str) -> String {
+    format!(
+        ".{}{}",
+        &Sha256::digest(filename.as_bytes())[..16],
+        CACHE_SUFFIX
+    )
+}
+
+/// Returns whether the `Want-Digest` header accepts SHA-256, e.g. "SHA-256, md5;q=0.3".
+///
+/// Without the header any algorithm is acceptable.
+pub fn wants_sha256(want_digest: Option<&str>) -> bool {
+    let want_digest = match want_digest {
+        Some(data) => data,
+        None => return true,
+    };
+    want_digest.split(',').any(|item| {
+        let mut params = item.split(';');
+        let algorithm = params.next().unwrap_or_default().trim();
+        let accepted = params
+            .filter_map(|param| {
+                let param = param.trim();
+                if param.starts_with("q=") || param.starts_with("Q=") {
+                    param[2..].trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .map_or(true, |q| 0.0 < q);
+        algorithm.eq_ignore_ascii_case("sha-256") && accepted
+    })
+}
+
+/// Returns the value of the `Digest` header for the lowercase hex SHA-256 digest, i.e.
+/// `sha-256=<base64>`.
+pub fn header_value(sha256: &str) -> Option<String> {
+    if sha256.len() != 64 {
+        return None;
+    }
+    let bytes = (0..64)
+        .step_by(2)
+        .map(|index| u8::from_str_radix(sha256.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("sha-256={}", base64_encode(&bytes)))
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut ret = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |acc, (index, byte)| {
+            acc | u32::from(*byte) << (16 - 8 * index)
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                ret.push(BASE64[(value >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        // RFC 4648 test vectors.
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(&[0xfb, 0xff, 0xfe]), "+//+");
+    }
+
+    #[test]
+    fn header_value_of_digest() {
+        // sha-256 of "hello".
+        assert_eq!(
+            header_value("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"),
+            Some("sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=".to_owned())
+        );
+        assert_eq!(header_value(""), None);
+        assert_eq!(header_value(&"z".repeat(64)), None);
+        assert_eq!(header_value(&"\u{3042}".repeat(21)), None);
+    }
+
+    #[test]
+    fn want_digest() {
+        assert!(wants_sha256(None));
+        assert!(wants_sha256(Some("sha-256")));
+        assert!(wants_sha256(Some("SHA-256;q=0.5, md5")));
+        assert!(wants_sha256(Some("md5;q=0.3, sha-256;q=1")));
+        assert!(!wants_sha256(Some("md5")));
+        assert!(!wants_sha256(Some("sha-256;q=0")));
+        assert!(!wants_sha256(Some("sha-512, sha")));
+    }
+
+    #[test]
+    fn cache_names() {
+        let name = cache_name("a.txt");
+        assert!(name.starts_with('.'));
+        assert!(name.ends_with(".sha256"));
+        assert_eq!(name.len(), cache_name(&"a".repeat(255)).len());
+        assert_ne!(name, cache_name("b.txt"));
+    }
+}
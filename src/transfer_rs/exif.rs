@@ -0,0 +1,186 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Removes EXIF and other metadata from JPEG and PNG images.
+///
+/// Returns `None` if `data` isn't a JPEG or PNG image or is malformed, in which case the data should be kept
+/// as is.
+pub fn strip_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xff, 0xd8]) {
+        strip_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png(data)
+    } else {
+        None
+    }
+}
+
+/// Drops the APP1 (EXIF, XMP), APP13 (IPTC) and COM segments.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(data.len());
+    ret.extend_from_slice(&data[..2]);
+    let mut pos = 2;
+    loop {
+        if data.len() <= pos || data[pos] != 0xff {
+            return None;
+        }
+        // skip fill bytes.
+        while pos + 1 < data.len() && data[pos + 1] == 0xff {
+            pos += 1;
+        }
+        let marker = *data.get(pos + 1)?;
+        match marker {
+            // TEM and RSTn have no length.
+            0x01 | 0xd0..=0xd7 => {
+                ret.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            0xd9 => {
+                ret.extend_from_slice(&data[pos..pos + 2]);
+                return Some(ret);
+            }
+            _ => (),
+        }
+        let length = usize::from(*data.get(pos + 2)?) << 8 | usize::from(*data.get(pos + 3)?);
+        let end = pos + 2 + length;
+        if length < 2 || data.len() < end {
+            return None;
+        }
+        match marker {
+            0xe1 | 0xed | 0xfe => (),
+            // start of scan, the entropy-coded data follows until EOI.
+            0xda => {
+                ret.extend_from_slice(&data[pos..]);
+                return Some(ret);
+            }
+            _ => ret.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+}
+
+/// Drops the eXIf, tEXt, zTXt, iTXt and tIME chunks.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(data.len());
+    ret.extend_from_slice(PNG_SIGNATURE);
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        if data.len() < pos + 12 {
+            return None;
+        }
+        let length = (u32::from(data[pos]) << 24
+            | u32::from(data[pos + 1]) << 16
+            | u32::from(data[pos + 2]) << 8
+            | u32::from(data[pos + 3])) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        let end = pos.checked_add(12)?.checked_add(length)?;
+        if data.len() < end {
+            return None;
+        }
+        match chunk_type {
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => (),
+            _ => ret.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+        if chunk_type == b"IEND" {
+            return Some(ret);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOI: &[u8] = b"\xff\xd8";
+    const APP0: &[u8] = b"\xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+    const APP1: &[u8] = b"\xff\xe1\x00\x0cExif\x00\x00GPS!";
+    const COM: &[u8] = b"\xff\xfe\x00\x05abc";
+    const SOS: &[u8] =
+        b"\xff\xda\x00\x08\x01\x01\x00\x00\x3f\x00\x12\xff\x00\x34\xff\xd0\x56\xff\xd9";
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut ret = (data.len() as u32).to_be_bytes().to_vec();
+        ret.extend_from_slice(chunk_type);
+        ret.extend_from_slice(data);
+        // the CRC isn't checked.
+        ret.extend_from_slice(&[0; 4]);
+        ret
+    }
+
+    #[test]
+    fn strip_jpeg_metadata() {
+        let data = [SOI, APP0, APP1, COM, SOS].concat();
+        assert_eq!(strip_metadata(&data), Some([SOI, APP0, SOS].concat()));
+    }
+
+    #[test]
+    fn strip_jpeg_keeps_image_without_metadata() {
+        let data = [SOI, APP0, SOS].concat();
+        assert_eq!(strip_metadata(&data), Some(data));
+    }
+
+    #[test]
+    fn strip_jpeg_rejects_malformed() {
+        // truncated segment.
+        assert_eq!(strip_metadata(&[SOI, &APP1[..8]].concat()), None);
+        // no start of scan.
+        assert_eq!(strip_metadata(&[SOI, APP0].concat()), None);
+        // a segment without a marker.
+        assert_eq!(strip_metadata(&[SOI, b"\x00\x00", SOS].concat()), None);
+    }
+
+    #[test]
+    fn strip_png_metadata() {
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        let idat = png_chunk(b"IDAT", b"pixels");
+        let iend = png_chunk(b"IEND", b"");
+        let data = [
+            PNG_SIGNATURE,
+            &ihdr,
+            &png_chunk(b"tEXt", b"Author\x00me"),
+            &png_chunk(b"eXIf", b"MM\x00\x2a"),
+            &idat,
+            &png_chunk(b"tIME", &[0; 7]),
+            &iend,
+        ]
+        .concat();
+        assert_eq!(
+            strip_metadata(&data),
+            Some([PNG_SIGNATURE, &ihdr, &idat, &iend].concat())
+        );
+    }
+
+    #[test]
+    fn strip_png_rejects_malformed() {
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        // no IEND.
+        assert_eq!(strip_metadata(&[PNG_SIGNATURE, &ihdr].concat()), None);
+        // truncated chunk.
+        assert_eq!(strip_metadata(&[PNG_SIGNATURE, &ihdr[..10]].concat()), None);
+    }
+
+    #[test]
+    fn strip_other_types() {
+        assert_eq!(strip_metadata(b""), None);
+        assert_eq!(strip_metadata(b"GIF89a"), None);
+        assert_eq!(strip_metadata(b"\xff"), None);
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// Makes a client supplied filename safe to store.
+///
+/// The filename is truncated to `max_bytes` bytes on a UTF-8 character boundary since filesystems limit
+/// names by bytes rather than characters. The extension is kept if it's short enough.
+pub fn sanitize_filename(filename: &str, max_bytes: usize) -> String {
+    if filename.len() <= max_bytes {
+        return filename.to_owned();
+    }
+    let ext = match filename.rfind('.') {
+        Some(index) if 0 < index && filename.len() - index <= max_bytes / 2 => &filename[index..],
+        _ => "",
+    };
+    let stem = &filename[..filename.len() - ext.len()];
+    let mut end = max_bytes - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+/// Inserts `suffix` (e.g. "-1") before the extension of the last path component of `filename`.
+///
+/// The stem is truncated so that the component stays within `max_bytes` bytes like
+/// `sanitize_filename`.
+pub fn insert_suffix(filename: &str, suffix: &str, max_bytes: usize) -> String {
+    let (dir, name) = match filename.rfind('/') {
+        Some(index) => filename.split_at(index + 1),
+        None => ("", filename),
+    };
+    let (stem, ext) = match name.rfind('.') {
+        Some(index) if 0 < index && name.len() - index <= max_bytes / 2 => name.split_at(index),
+        _ => (name, ""),
+    };
+    let mut end = stem
+        .len()
+        .min(max_bytes.saturating_sub(suffix.len() + ext.len()));
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}{}{}", dir, &stem[..end], suffix, ext)
+}
+
+/// Returns whether `value` can be used as a single path component as is.
+pub fn is_path_component(value: &str) -> bool {
+    !value.is_empty()
+        && value != "."
+        && value != ".."
+        && !value.contains(|c| c == '/' || c == '\\' || c == '\0')
+}
+
+/// Makes a client supplied filename that may contain directories safe to store.
+///
+/// With `preserve_paths` every directory component is kept and sanitized like a filename, otherwise
+/// only the last component is used. Returns `None` if the filename refers to a parent directory,
+/// contains a NUL byte or has no name at all.
+pub fn sanitize_path(filename: &str, max_bytes: usize, preserve_paths: bool) -> Option<String> {
+    if filename.contains('\0') {
+        return None;
+    }
+    let mut components = Vec::new();
+    for component in filename.split(|c| c == '/' || c == '\\') {
+        match component {
+            "" | "." => (),
+            ".." => return None,
+            _ => components.push(sanitize_filename(component, max_bytes)),
+        }
+    }
+    if preserve_paths {
+        if components.is_empty() {
+            return None;
+        }
+        Some(components.join("/"))
+    } else {
+        components.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_filename_keeps_short_names() {
+        assert_eq!(sanitize_filename("a.txt", 255), "a.txt");
+        assert_eq!(sanitize_filename(&"a".repeat(16), 16), "a".repeat(16));
+    }
+
+    #[test]
+    fn sanitize_filename_truncates_keeping_extension() {
+        assert_eq!(
+            sanitize_filename(&format!("{}.txt", "a".repeat(20)), 16),
+            format!("{}.txt", "a".repeat(12))
+        );
+        // the extension is too long to keep.
+        assert_eq!(
+            sanitize_filename(&format!("a.{}", "b".repeat(20)), 16),
+            format!("a.{}", "b".repeat(14))
+        );
+        // a leading dot isn't an extension.
+        assert_eq!(
+            sanitize_filename(&format!(".{}", "a".repeat(20)), 16).len(),
+            16
+        );
+    }
+
+    #[test]
+    fn sanitize_filename_truncates_on_char_boundary() {
+        let ret = sanitize_filename(&"\u{3042}".repeat(10), 16);
+        assert_eq!(ret, "\u{3042}".repeat(5));
+        let ret = sanitize_filename(&format!("{}.txt", "\u{1f600}".repeat(5)), 16);
+        assert_eq!(ret, format!("{}.txt", "\u{1f600}".repeat(3)));
+    }
+
+    #[test]
+    fn is_path_component_rejects_traversal() {
+        assert!(is_path_component("a.txt"));
+        assert!(is_path_component("..a"));
+        for value in &["", ".", "..", "a/b", "a\\b", "a\0b"] {
+            assert!(!is_path_component(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn sanitize_path_keeps_last_component() {
+        assert_eq!(sanitize_path("a.txt", 255, false), Some("a.txt".to_owned()));
+        assert_eq!(
+            sanitize_path("dir/a.txt", 255, false),
+            Some("a.txt".to_owned())
+        );
+        assert_eq!(
+            sanitize_path("C:\\dir\\a.txt", 255, false),
+            Some("a.txt".to_owned())
+        );
+        assert_eq!(sanitize_path("dir/", 255, false), Some("dir".to_owned()));
+        assert_eq!(sanitize_path("", 255, false), None);
+        assert_eq!(sanitize_path("/", 255, false), None);
+    }
+
+    #[test]
+    fn sanitize_path_preserve_paths() {
+        assert_eq!(
+            sanitize_path("/photos/./2019//img.jpg", 255, true),
+            Some("photos/2019/img.jpg".to_owned())
+        );
+        assert_eq!(
+            sanitize_path(&format!("{}/a", "d".repeat(20)), 16, true),
+            Some(format!("{}/a", "d".repeat(16)))
+        );
+        assert_eq!(sanitize_path("./", 255, true), None);
+    }
+
+    #[test]
+    fn sanitize_path_rejects_parent_and_nul() {
+        for preserve_paths in &[false, true] {
+            assert_eq!(sanitize_path("../a.txt", 255, *preserve_paths), None);
+            assert_eq!(sanitize_path("a/../../b", 255, *preserve_paths), None);
+            assert_eq!(sanitize_path("a\\..\\b", 255, *preserve_paths), None);
+            assert_eq!(sanitize_path("a\0.txt", 255, *preserve_paths), None);
+        }
+    }
+
+    #[test]
+    fn insert_suffix_before_extension() {
+        assert_eq!(insert_suffix("a.txt", "-1", 255), "a-1.txt");
+        assert_eq!(insert_suffix("a", "-1", 255), "a-1");
+        assert_eq!(insert_suffix(".bashrc", "-1", 255), ".bashrc-1");
+        assert_eq!(insert_suffix("a.tar.gz", "-2", 255), "a.tar-2.gz");
+    }
+
+    #[test]
+    fn insert_suffix_only_to_last_component() {
+        assert_eq!(insert_suffix("v1.2/a", "-1", 255), "v1.2/a-1");
+        assert_eq!(insert_suffix("v1.2/a.txt", "-1", 255), "v1.2/a-1.txt");
+    }
+
+    #[test]
+    fn insert_suffix_keeps_max_bytes() {
+        let filename = format!("{}.txt", "a".repeat(12));
+        assert_eq!(insert_suffix(&filename, "-10", 16), "aaaaaaaaa-10.txt");
+        let filename = format!("dir/{}", "\u{3042}".repeat(5));
+        let ret = insert_suffix(&filename, "-1", 16);
+        assert_eq!(ret, format!("dir/{}-1", "\u{3042}".repeat(4)));
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Parameters of the Forwarded header (RFC 7239).
+#[derive(Debug, Default, PartialEq)]
+pub struct Forwarded {
+    pub by: Option<String>,
+    pub forwarded_for: Option<String>,
+    pub host: Option<String>,
+    pub proto: Option<String>,
+}
+
+impl Forwarded {
+    /// Parses the elements of the header in order, i.e. the last one is appended by the proxy
+    /// closest to this server.
+    pub fn parse(value: &str) -> Vec<Self> {
+        split_unquoted(value, ',')
+            .into_iter()
+            .filter(|data| !data.trim().is_empty())
+            .map(Self::parse_element)
+            .collect()
+    }
+
+    fn parse_element(element: &str) -> Self {
+        let mut ret = Self::default();
+        for pair in split_unquoted(element, ';') {
+            let mut pair = pair.splitn(2, '=');
+            let key = pair.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let value = match pair.next() {
+                Some(data) => unquote(data.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "by" => ret.by = Some(value),
+                "for" => ret.forwarded_for = Some(value),
+                "host" => ret.host = Some(value),
+                "proto" => ret.proto = Some(value.to_ascii_lowercase()),
+                _ => (),
+            }
+        }
+        ret
+    }
+
+    /// Returns the address of the `for` parameter such as `192.0.2.60`, `192.0.2.60:4711` or
+    /// `[2001:db8::1]:4711`.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded_for
+            .as_ref()
+            .and_then(|data| parse_node(data))
+    }
+}
+
+fn parse_node(value: &str) -> Option<IpAddr> {
+    if value.starts_with('[') {
+        let end = value.find(']')?;
+        return value[1..end].parse().ok();
+    }
+    if let Ok(addr) = value.parse() {
+        return Some(addr);
+    }
+    let index = value.rfind(':')?;
+    value[..index].parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+fn split_unquoted(value: &str, delimiter: char) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == delimiter && !quoted => {
+                ret.push(&value[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    ret.push(&value[start..]);
+    ret
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return value.to_owned();
+    }
+    let mut ret = String::new();
+    let mut escaped = false;
+    for c in value[1..value.len() - 1].chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        ret.push(c);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_parameters() {
+        let forwarded =
+            Forwarded::parse(r#"for=192.0.2.60;proto=HTTPS;by=203.0.113.43;Host="example.com""#);
+        assert_eq!(
+            forwarded,
+            vec![Forwarded {
+                by: Some("203.0.113.43".to_owned()),
+                forwarded_for: Some("192.0.2.60".to_owned()),
+                host: Some("example.com".to_owned()),
+                proto: Some("https".to_owned()),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_quoted_delimiters() {
+        let forwarded = Forwarded::parse(r#"for="[2001:db8::1]:4711";host="a;b,c\"d""#).remove(0);
+        assert_eq!(
+            forwarded.forwarded_for,
+            Some("[2001:db8::1]:4711".to_owned())
+        );
+        assert_eq!(forwarded.host, Some(r#"a;b,c"d"#.to_owned()));
+    }
+
+    #[test]
+    fn parse_ignores_unknown_and_malformed_pairs() {
+        assert_eq!(Forwarded::parse(""), vec![]);
+        assert_eq!(Forwarded::parse("secret=1;for"), vec![Forwarded::default()]);
+    }
+
+    #[test]
+    fn parse_every_element() {
+        let forwarded =
+            Forwarded::parse("for=192.0.2.1, for=198.51.100.2;proto=https,,for=10.0.0.1");
+        let forwarded_for = forwarded
+            .iter()
+            .map(|data| data.forwarded_for.as_ref().unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(forwarded_for, ["192.0.2.1", "198.51.100.2", "10.0.0.1"]);
+        assert_eq!(forwarded[1].proto, Some("https".to_owned()));
+    }
+
+    #[test]
+    fn client_ip_of_nodes() {
+        let client_ip = |value: &str| Forwarded::parse(&format!("for={}", value))[0].client_ip();
+        assert_eq!(client_ip("192.0.2.60"), "192.0.2.60".parse().ok());
+        assert_eq!(client_ip("192.0.2.60:4711"), "192.0.2.60".parse().ok());
+        assert_eq!(client_ip(r#""[2001:db8::1]""#), "2001:db8::1".parse().ok());
+        assert_eq!(
+            client_ip(r#""[2001:db8::1]:4711""#),
+            "2001:db8::1".parse().ok()
+        );
+        assert_eq!(client_ip("unknown"), None);
+        assert_eq!(client_ip("_hidden"), None);
+    }
+}
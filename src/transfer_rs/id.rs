@@ -0,0 +1,154 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::format_err;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::transfer_rs::prelude::*;
+
+/// Generates the id of the upload directory, i.e. the first path segment of the download URL.
+pub trait IdGenerator {
+    fn generate(&self) -> String;
+}
+
+/// e.g. `936da01f-9abd-4d9d-80c7-02af85c822a8`
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn generate(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// 16 random base62 characters (about 95 bits), e.g. `3kTMd9PqW0xZb7Lc`
+pub struct ShortIdGenerator;
+
+impl IdGenerator for ShortIdGenerator {
+    fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (0..16)
+            .map(|_| BASE62[rng.gen_range(0, BASE62.len())] as char)
+            .collect()
+    }
+}
+
+const CROCKFORD_BASE32: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// ULID, i.e. 48 bits of milliseconds since the epoch followed by 80 random bits, e.g.
+/// `01ARZ3NDEKTSV4RRFFQ69G5FAV`. Ids sort by creation time.
+pub struct UlidGenerator;
+
+impl IdGenerator for UlidGenerator {
+    fn generate(&self) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|data| data.as_millis())
+            .unwrap_or(0)
+            & ((1 << 48) - 1);
+        let random = (u128::from(rand::random::<u64>()) << 16) | u128::from(rand::random::<u16>());
+        encode_ulid((timestamp << 80) | random)
+    }
+}
+
+fn encode_ulid(value: u128) -> String {
+    // 26 characters hold 130 bits, so the first one carries only the top 3 bits.
+    (0..26)
+        .map(|index| CROCKFORD_BASE32[((value >> (125 - 5 * index)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdScheme {
+    Uuid,
+    Short,
+    Ulid,
+}
+
+impl IdScheme {
+    pub fn generator(self) -> Box<dyn IdGenerator + Send + Sync> {
+        match self {
+            IdScheme::Uuid => Box::new(UuidGenerator),
+            IdScheme::Short => Box::new(ShortIdGenerator),
+            IdScheme::Ulid => Box::new(UlidGenerator),
+        }
+    }
+}
+
+impl FromStr for IdScheme {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s {
+            "uuid" => Ok(IdScheme::Uuid),
+            "short" => Ok(IdScheme::Short),
+            "ulid" => Ok(IdScheme::Ulid),
+            _ => Err(format_err!("unknown id scheme: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuid() {
+        let ret = UuidGenerator.generate();
+        assert!(Uuid::parse_str(&ret).is_ok(), "{}", ret);
+    }
+
+    #[test]
+    fn short() {
+        let ret = ShortIdGenerator.generate();
+        assert_eq!(ret.len(), 16);
+        assert!(ret.bytes().all(|data| BASE62.contains(&data)), "{}", ret);
+        assert_ne!(ret, ShortIdGenerator.generate());
+    }
+
+    #[test]
+    fn encode_ulid_bounds() {
+        assert_eq!(encode_ulid(0), "0".repeat(26));
+        assert_eq!(encode_ulid(1), format!("{}1", "0".repeat(25)));
+        assert_eq!(
+            encode_ulid(u128::max_value()),
+            format!("7{}", "Z".repeat(25))
+        );
+    }
+
+    #[test]
+    fn ulid_sorts_by_time() {
+        let first = UlidGenerator.generate();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = UlidGenerator.generate();
+        assert_eq!(first.len(), 26);
+        assert!(first.bytes().all(|data| CROCKFORD_BASE32.contains(&data)));
+        assert!(first < second, "{} {}", first, second);
+    }
+
+    #[test]
+    fn id_scheme_from_str() {
+        assert_eq!("uuid".parse::<IdScheme>().unwrap(), IdScheme::Uuid);
+        assert_eq!("short".parse::<IdScheme>().unwrap(), IdScheme::Short);
+        assert_eq!("ulid".parse::<IdScheme>().unwrap(), IdScheme::Ulid);
+        assert!("UUID".parse::<IdScheme>().is_err());
+    }
+}
@@ -0,0 +1,228 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+
+/// Remembers the responses of the requests that have the `Idempotency-Key` header so that a retried
+/// request gets the original response instead of being processed again.
+///
+/// Only successful responses are remembered, and a retry that arrives while the original request is still
+/// in progress is processed as usual. Once `capacity` entries are remembered, the oldest one is forgotten.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<IdempotencyKey, Entry>>,
+}
+
+/// The `Idempotency-Key` header scoped by the client and the request, so that a client can't replay
+/// the response of another one by guessing its key.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct IdempotencyKey {
+    pub client: IpAddr,
+    pub method: Method,
+    pub path: String,
+    pub key: String,
+}
+
+struct Entry {
+    created: Instant,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    pub fn get(&self, key: &IdempotencyKey) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().unwrap();
+        self.evict(&mut entries);
+        entries.get(key).map(|entry| {
+            let mut response = Response::new(Body::from(entry.body.clone()));
+            *response.status_mut() = entry.status;
+            *response.headers_mut() = entry.headers.clone();
+            response
+        })
+    }
+
+    pub fn insert(
+        &self,
+        key: IdempotencyKey,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) {
+        if !status.is_success() || self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        self.evict(&mut entries);
+        if self.capacity <= entries.len() && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.created)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                created: Instant::now(),
+                status,
+                headers: headers.clone(),
+                body: body.to_vec(),
+            },
+        );
+    }
+
+    fn evict(&self, entries: &mut HashMap<IdempotencyKey, Entry>) {
+        let ttl = self.ttl;
+        entries.retain(|_, entry| entry.created.elapsed() < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+
+    use super::*;
+
+    fn key(client: [u8; 4], method: Method, path: &str, key: &str) -> IdempotencyKey {
+        IdempotencyKey {
+            client: IpAddr::from(client),
+            method,
+            path: path.to_owned(),
+            key: key.to_owned(),
+        }
+    }
+
+    fn body(response: Response<Body>) -> Vec<u8> {
+        response.into_body().concat2().wait().unwrap().to_vec()
+    }
+
+    #[test]
+    fn replay() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 16);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::CONTENT_TYPE,
+            "application/json".parse().unwrap(),
+        );
+        cache.insert(
+            key([192, 0, 2, 1], Method::POST, "/upload", "a"),
+            StatusCode::CREATED,
+            &headers,
+            b"result",
+        );
+
+        let response = cache
+            .get(&key([192, 0, 2, 1], Method::POST, "/upload", "a"))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            "application/json"
+        );
+        assert_eq!(body(response), b"result");
+    }
+
+    #[test]
+    fn scoped_by_client_and_request() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 16);
+        cache.insert(
+            key([192, 0, 2, 1], Method::POST, "/upload", "a"),
+            StatusCode::OK,
+            &HeaderMap::new(),
+            b"",
+        );
+        assert!(cache
+            .get(&key([192, 0, 2, 2], Method::POST, "/upload", "a"))
+            .is_none());
+        assert!(cache
+            .get(&key([192, 0, 2, 1], Method::PUT, "/upload", "a"))
+            .is_none());
+        assert!(cache
+            .get(&key([192, 0, 2, 1], Method::POST, "/other", "a"))
+            .is_none());
+        assert!(cache
+            .get(&key([192, 0, 2, 1], Method::POST, "/upload", "b"))
+            .is_none());
+    }
+
+    #[test]
+    fn ignore_unsuccessful_response() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 16);
+        let key = key([192, 0, 2, 1], Method::POST, "/upload", "a");
+        cache.insert(
+            key.clone(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &HeaderMap::new(),
+            b"",
+        );
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let cache = IdempotencyCache::new(Duration::from_millis(10), 16);
+        let key = key([192, 0, 2, 1], Method::POST, "/upload", "a");
+        cache.insert(key.clone(), StatusCode::OK, &HeaderMap::new(), b"");
+        assert!(cache.get(&key).is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn forget_oldest_over_capacity() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 2);
+        let keys = ["a", "b", "c"]
+            .iter()
+            .map(|data| key([192, 0, 2, 1], Method::POST, "/upload", data))
+            .collect::<Vec<_>>();
+        for key in &keys {
+            cache.insert(key.clone(), StatusCode::OK, &HeaderMap::new(), b"");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(cache.get(&keys[0]).is_none());
+        assert!(cache.get(&keys[1]).is_some());
+        assert!(cache.get(&keys[2]).is_some());
+
+        // replacing a remembered key doesn't forget the others.
+        cache.insert(keys[1].clone(), StatusCode::OK, &HeaderMap::new(), b"");
+        assert!(cache.get(&keys[2]).is_some());
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 0);
+        let key = key([192, 0, 2, 1], Method::POST, "/upload", "a");
+        cache.insert(key.clone(), StatusCode::OK, &HeaderMap::new(), b"");
+        assert!(cache.get(&key).is_none());
+    }
+}
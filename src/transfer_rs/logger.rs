@@ -0,0 +1,342 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+use crate::transfer_rs::prelude::*;
+
+/// The target of the records that are access log lines, e.g.
+/// `info!(target: ACCESS_LOG_TARGET, "{}", line)`.
+///
+/// These records are written as is, without the prefix or the JSON object of the other records,
+/// and regardless of `RUST_LOG`.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+/// Initializes the logger that writes to stderr and, if `log_file` is given, to the file as well.
+///
+/// The filter is configured by `RUST_LOG` as with `env_logger::init`. With `json`, each record is
+/// written as a JSON object in a line instead.
+pub fn init(log_file: Option<PathBuf>, rotate_size: Option<u64>, json: bool) -> Fallible<()> {
+    let inner = env_logger::Builder::from_default_env().build();
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(RotatingFile::open(path, rotate_size)?)),
+        None => None,
+    };
+    // lets the access log lines through, the other records are filtered in `TeeLogger::log`.
+    log::set_max_level(std::cmp::max(inner.filter(), LevelFilter::Info));
+    log::set_boxed_logger(Box::new(TeeLogger { inner, file, json }))?;
+    Ok(())
+}
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Tags the records logged on the current thread with `request_id` until dropped.
+///
+/// Futures should enter the scope on every `poll` since a task may move between threads.
+pub struct RequestIdScope {
+    prev: Option<String>,
+}
+
+impl RequestIdScope {
+    pub fn enter(request_id: &str) -> Self {
+        let prev = REQUEST_ID.with(|data| data.replace(Some(request_id.to_owned())));
+        Self { prev }
+    }
+}
+
+impl Drop for RequestIdScope {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        REQUEST_ID.with(|data| *data.borrow_mut() = prev);
+    }
+}
+
+struct TeeLogger {
+    inner: env_logger::Logger,
+    file: Option<Mutex<RotatingFile>>,
+    json: bool,
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == ACCESS_LOG_TARGET || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let access_log = record.target() == ACCESS_LOG_TARGET;
+        if !access_log && !self.inner.matches(record) {
+            return;
+        }
+        let request_id = REQUEST_ID.with(|data| data.borrow().clone());
+        let line = if access_log {
+            let line = record.args().to_string();
+            eprintln!("{}", line);
+            line
+        } else if self.json {
+            let line = json!({
+                "time": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "request_id": request_id,
+                "message": record.args().to_string(),
+            })
+            .to_string();
+            eprintln!("{}", line);
+            line
+        } else {
+            let message = match &request_id {
+                Some(request_id) => format!("[{}] {}", request_id, record.args()),
+                None => record.args().to_string(),
+            };
+            self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+            if self.file.is_none() {
+                return;
+            }
+            format!(
+                "[{} {:<5} {}] {}",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                record.target(),
+                message
+            )
+        };
+        if let Some(file) = &self.file {
+            let line = format!("{}\n", line);
+            let mut file = match file.lock() {
+                Ok(data) => data,
+                Err(e) => e.into_inner(),
+            };
+            if let Err(e) = file.write_line(&line) {
+                eprintln!("failed to write the log file: {:?}", e);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                file.file.flush().ok();
+            }
+        }
+    }
+}
+
+/// Appends to `path` and moves it to `<path>.1` once it grows beyond `max_size` bytes.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: Option<u64>) -> Fallible<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if let Some(max_size) = self.max_size {
+            if 0 < self.size && max_size < self.size + line.len() as u64 {
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    struct TestFile(PathBuf);
+
+    impl TestFile {
+        fn new() -> Self {
+            TestFile(std::env::temp_dir().join(format!("transfer-rs-{}.log", uuid::Uuid::new_v4())))
+        }
+
+        fn rotated(&self) -> PathBuf {
+            let mut ret = self.0.clone().into_os_string();
+            ret.push(".1");
+            PathBuf::from(ret)
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+            std::fs::remove_file(self.rotated()).ok();
+        }
+    }
+
+    fn logger(path: &TestFile, rotate_size: Option<u64>, json: bool) -> TeeLogger {
+        TeeLogger {
+            inner: env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Info)
+                .build(),
+            file: Some(Mutex::new(
+                RotatingFile::open(path.0.clone(), rotate_size).unwrap(),
+            )),
+            json,
+        }
+    }
+
+    fn log(logger: &TeeLogger, level: Level, message: &str) {
+        log_to(logger, level, "transfer", message);
+    }
+
+    fn log_to(logger: &TeeLogger, level: Level, target: &str, message: &str) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .target(target)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn file_is_created_and_written() {
+        let path = TestFile::new();
+        let logger = logger(&path, None, false);
+        assert!(path.0.exists());
+
+        let _scope = RequestIdScope::enter("req-1");
+        log(&logger, Level::Info, "Hello");
+        log(&logger, Level::Debug, "filtered");
+        let content = std::fs::read_to_string(&path.0).unwrap();
+        assert!(
+            content.ends_with(" INFO  transfer] [req-1] Hello\n"),
+            "{}",
+            content
+        );
+        assert_eq!(content.lines().count(), 1);
+    }
+
+    #[test]
+    fn file_in_json() {
+        let path = TestFile::new();
+        let logger = logger(&path, None, true);
+        log(&logger, Level::Warn, "Hello");
+        let content = std::fs::read_to_string(&path.0).unwrap();
+        let line = serde_json::from_str::<serde_json::Value>(content.trim_end()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "Hello");
+    }
+
+    #[test]
+    fn access_log_is_written_as_is() {
+        for json in &[false, true] {
+            let path = TestFile::new();
+            let mut logger = logger(&path, None, *json);
+            logger.inner = env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Error)
+                .build();
+            let _scope = RequestIdScope::enter("req-1");
+            let line =
+                r#"192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 5 "-" "-""#;
+            log_to(&logger, Level::Info, ACCESS_LOG_TARGET, line);
+            log(&logger, Level::Info, "filtered");
+            assert!(logger.enabled(
+                &Metadata::builder()
+                    .level(Level::Info)
+                    .target(ACCESS_LOG_TARGET)
+                    .build()
+            ));
+            assert_eq!(
+                std::fs::read_to_string(&path.0).unwrap(),
+                format!("{}\n", line)
+            );
+        }
+    }
+
+    #[test]
+    fn file_is_rotated() {
+        let path = TestFile::new();
+        let mut file = RotatingFile::open(path.0.clone(), Some(10)).unwrap();
+        file.write_line("0123456\n").unwrap();
+        file.write_line("abcdefg\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.rotated()).unwrap(),
+            "0123456\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path.0).unwrap(), "abcdefg\n");
+
+        // a line longer than the limit is still written.
+        file.write_line("0123456789abc\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.rotated()).unwrap(),
+            "abcdefg\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path.0).unwrap(), "0123456789abc\n");
+    }
+
+    #[test]
+    fn request_id_scope_restores_previous() {
+        let current = || REQUEST_ID.with(|data| data.borrow().clone());
+        {
+            let _outer = RequestIdScope::enter("outer");
+            {
+                let _inner = RequestIdScope::enter("inner");
+                assert_eq!(current(), Some("inner".to_owned()));
+            }
+            assert_eq!(current(), Some("outer".to_owned()));
+        }
+        assert_eq!(current(), None);
+    }
+}
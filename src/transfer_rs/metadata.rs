@@ -0,0 +1,189 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::transfer_rs::checksum::Checksums;
+use crate::transfer_rs::sha256::Sha256;
+
+const FILE_SUFFIX: &str = ".json";
+const TOMBSTONE_SUFFIX: &str = ".gone";
+
+/// What is recorded about a stored file when it is uploaded.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Milliseconds since the epoch of the upload.
+    pub uploaded: u64,
+    /// Milliseconds the upload asked the file to be kept for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    /// Milliseconds since the epoch the upload asked the file to be kept until.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// The lowercase hex MD5 digest with --checksums md5.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    /// The lowercase hex SHA-1 digest with --checksums sha1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+}
+
+impl Metadata {
+    /// Returns the metadata of a file uploaded at `uploaded`.
+    pub fn new(uploaded: SystemTime) -> Self {
+        Self {
+            uploaded: millis(uploaded.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the metadata written by `to_json`, or `None` if `data` is malformed.
+    pub fn from_json(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Sets the max-age asked by the upload, clamped to `limit`.
+    pub fn set_max_age(&mut self, max_age: Duration, limit: Option<Duration>) {
+        self.max_age = Some(millis(clamp(max_age, limit)));
+    }
+
+    /// Sets the expiry `expires_in` after the upload, clamped to `limit` after the upload.
+    pub fn set_expires_in(&mut self, expires_in: Duration, limit: Option<Duration>) {
+        self.expires = Some(self.uploaded + millis(clamp(expires_in, limit)));
+    }
+
+    /// Records the digests other than SHA-256, which is cached on its own.
+    pub fn set_checksums(&mut self, checksums: &Checksums) {
+        self.md5 = checksums.md5.clone();
+        self.sha1 = checksums.sha1.clone();
+    }
+
+    /// Returns when the file expires, at the earliest of its own expiry and the end of its own
+    /// max-age and `max_age`.
+    ///
+    /// A file without any of them is kept forever.
+    pub fn expires(&self, max_age: Option<Duration>) -> Option<SystemTime> {
+        let uploaded = UNIX_EPOCH + Duration::from_millis(self.uploaded);
+        vec![
+            self.expires
+                .map(|data| UNIX_EPOCH + Duration::from_millis(data)),
+            self.max_age
+                .map(|data| uploaded + Duration::from_millis(data)),
+            max_age.map(|data| uploaded + data),
+        ]
+        .into_iter()
+        .filter_map(|data| data)
+        .min()
+    }
+}
+
+/// Returns the name of the file keeping the metadata of `filename`, e.g. `.2c26b46b68ffc68f.json`.
+pub fn file_name(filename: &str) -> String {
+    hashed_name(filename, FILE_SUFFIX)
+}
+
+/// Returns the name of the file recording that `filename` was deleted or expired, e.g.
+/// `.2c26b46b68ffc68f.gone`.
+pub fn tombstone_name(filename: &str) -> String {
+    hashed_name(filename, TOMBSTONE_SUFFIX)
+}
+
+fn hashed_name(filename: &str, suffix: &str) -> String {
+    format!(".{}{}", &Sha256::digest(filename.as_bytes())[..16], suffix)
+}
+
+fn clamp(duration: Duration, limit: Option<Duration>) -> Duration {
+    match limit {
+        Some(limit) if limit < duration => limit,
+        _ => duration,
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut metadata = Metadata::new(at(1_500));
+        assert_eq!(metadata.to_json(), r#"{"uploaded":1500}"#);
+        metadata.set_max_age(Duration::from_secs(2), None);
+        assert_eq!(
+            Metadata::from_json(metadata.to_json().as_bytes()),
+            Some(metadata)
+        );
+        assert_eq!(Metadata::from_json(b"{}"), None);
+        assert_eq!(Metadata::from_json(b"broken"), None);
+    }
+
+    #[test]
+    fn expires_after_the_shorter_max_age() {
+        let hour = Duration::from_secs(3600);
+        let mut metadata = Metadata::new(at(1_000));
+        assert_eq!(metadata.expires(None), None);
+        assert_eq!(metadata.expires(Some(hour)), Some(at(1_000) + hour));
+
+        metadata.set_max_age(hour * 2, None);
+        assert_eq!(metadata.expires(None), Some(at(1_000) + hour * 2));
+        assert_eq!(metadata.expires(Some(hour)), Some(at(1_000) + hour));
+        assert_eq!(metadata.expires(Some(hour * 3)), Some(at(1_000) + hour * 2));
+
+        metadata.set_max_age(hour * 2, Some(hour));
+        assert_eq!(metadata.max_age, Some(3_600_000));
+    }
+
+    #[test]
+    fn expires_at_the_earliest() {
+        let hour = Duration::from_secs(3600);
+        let mut metadata = Metadata::new(at(1_000));
+        metadata.set_expires_in(hour * 2, None);
+        assert_eq!(metadata.expires, Some(7_201_000));
+        assert_eq!(metadata.expires(None), Some(at(1_000) + hour * 2));
+        assert_eq!(metadata.expires(Some(hour)), Some(at(1_000) + hour));
+        assert_eq!(metadata.expires(Some(hour * 3)), Some(at(1_000) + hour * 2));
+
+        metadata.set_max_age(hour / 2, None);
+        assert_eq!(metadata.expires(None), Some(at(1_000) + hour / 2));
+
+        metadata.set_expires_in(hour * 2, Some(hour));
+        assert_eq!(metadata.expires, Some(3_601_000));
+    }
+
+    #[test]
+    fn file_names() {
+        let name = file_name("a.txt");
+        assert!(name.starts_with('.'));
+        assert!(name.ends_with(".json"));
+        assert_ne!(name, file_name("b.txt"));
+        assert_eq!(name.len(), file_name(&"a".repeat(255)).len());
+        assert_ne!(name, tombstone_name("a.txt"));
+        assert!(tombstone_name("a.txt").ends_with(".gone"));
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// Encodes `value` as a URI path segment, escaping every byte but the unreserved characters of
+/// RFC 3986, i.e. `A-Z a-z 0-9 - . _ ~`.
+pub fn percent_encode(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                ret.push(byte as char)
+            }
+            _ => ret.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    ret
+}
+
+/// Decodes the `%XX` escapes of a URI path segment.
+///
+/// Returns `None` if an escape is malformed or the decoded bytes are not UTF-8.
+pub fn percent_decode(value: &str) -> Option<String> {
+    String::from_utf8(decode(value.as_bytes(), false)?).ok()
+}
+
+/// Decodes a name or a value of an `application/x-www-form-urlencoded` body, where `+` is a space.
+///
+/// Returns `None` if an escape is malformed.
+pub fn form_urlencoded_decode(value: &[u8]) -> Option<Vec<u8>> {
+    decode(value, true)
+}
+
+fn decode(bytes: &[u8], plus_as_space: bool) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hex = bytes.get(index + 1..index + 3)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                ret.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                index += 3;
+            }
+            b'+' if plus_as_space => {
+                ret.push(b' ');
+                index += 1;
+            }
+            data => {
+                ret.push(data);
+                index += 1;
+            }
+        }
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        assert_eq!(percent_encode("a-Z_0.9~"), "a-Z_0.9~");
+        assert_eq!(percent_encode("a b/c?d#e%f"), "a%20b%2Fc%3Fd%23e%25f");
+        assert_eq!(percent_encode("\u{3042}"), "%E3%81%82");
+        assert_eq!(percent_encode(""), "");
+    }
+
+    #[test]
+    fn decode_path_segment() {
+        assert_eq!(percent_decode("a%20b%2fc"), Some("a b/c".to_owned()));
+        assert_eq!(percent_decode("a+b"), Some("a+b".to_owned()));
+        assert_eq!(percent_decode("%E3%81%82"), Some("\u{3042}".to_owned()));
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        // not UTF-8.
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        for value in &[
+            "a b",
+            "100%",
+            "\u{3042}\u{1f600}.txt",
+            "?#[]@!$&'()*+,;=",
+            "%41",
+        ] {
+            assert_eq!(
+                percent_decode(&percent_encode(value)).as_ref(),
+                Some(&value.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn decode_form_urlencoded() {
+        assert_eq!(form_urlencoded_decode(b"a+b%2B"), Some(b"a b+".to_vec()));
+        assert_eq!(form_urlencoded_decode(b"%ff"), Some(vec![0xff]));
+        assert_eq!(form_urlencoded_decode(b"%f"), None);
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// How to answer a request with the `Range` header.
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    /// Serve the whole representation as if the header was absent.
+    Full,
+    /// Serve the bytes from `start` to `end` inclusive.
+    Partial { start: u64, end: u64 },
+    /// Respond 416.
+    Unsatisfiable,
+}
+
+/// Resolves the value of a `Range` header against a representation of `len` bytes.
+///
+/// Only a single byte range is supported. A header with multiple ranges or with an invalid syntax is
+/// ignored as RFC 7233 allows.
+pub fn resolve(value: &str, len: u64) -> ByteRange {
+    let value = value.trim();
+    match value.get(.."bytes=".len()) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=") => (),
+        _ => return ByteRange::Full,
+    }
+    let spec = value["bytes=".len()..].trim();
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let index = match spec.find('-') {
+        Some(data) => data,
+        None => return ByteRange::Full,
+    };
+    let (first, last) = (spec[..index].trim(), spec[index + 1..].trim());
+    if first.is_empty() {
+        // suffix range, e.g. "bytes=-500" for the last 500 bytes.
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let start = match first.parse::<u64>() {
+        Ok(data) => data,
+        Err(_) => return ByteRange::Full,
+    };
+    let end = if last.is_empty() {
+        None
+    } else {
+        match last.parse::<u64>() {
+            Ok(data) if start <= data => Some(data),
+            _ => return ByteRange::Full,
+        }
+    };
+    if len <= start {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        start,
+        end: end.map_or(len - 1, |data| data.min(len - 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> ByteRange {
+        ByteRange::Partial { start, end }
+    }
+
+    #[test]
+    fn first_and_last() {
+        assert_eq!(resolve("bytes=0-0", 10), partial(0, 0));
+        assert_eq!(resolve("bytes=2-5", 10), partial(2, 5));
+        assert_eq!(resolve("Bytes = 2 - 5", 10), ByteRange::Full);
+        assert_eq!(resolve("BYTES=2- 5 ", 10), partial(2, 5));
+        // clamped to the last byte.
+        assert_eq!(resolve("bytes=2-100", 10), partial(2, 9));
+    }
+
+    #[test]
+    fn open_ended() {
+        assert_eq!(resolve("bytes=3-", 10), partial(3, 9));
+        assert_eq!(resolve("bytes=9-", 10), partial(9, 9));
+        assert_eq!(resolve("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(resolve("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn suffix() {
+        assert_eq!(resolve("bytes=-3", 10), partial(7, 9));
+        assert_eq!(resolve("bytes=-100", 10), partial(0, 9));
+        assert_eq!(resolve("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(resolve("bytes=-3", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignore_unsupported_or_invalid() {
+        for value in &[
+            "",
+            "bytes",
+            "bytes=",
+            "items=0-1",
+            "bytes=0-1,3-4",
+            "bytes=5-2",
+            "bytes=a-1",
+            "bytes=1-b",
+            "bytes=--1",
+            "bytes=-",
+            "bytes=18446744073709551616-",
+        ] {
+            assert_eq!(resolve(value, 10), ByteRange::Full, "{:?}", value);
+        }
+    }
+}
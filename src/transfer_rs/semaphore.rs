@@ -0,0 +1,211 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Limits how many tasks run at the same time without waiting for a free slot.
+pub struct Semaphore {
+    available: AtomicUsize,
+}
+
+/// A slot of the `Semaphore` that is released on drop.
+pub struct Permit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            available: AtomicUsize::new(permits),
+        }
+    }
+
+    /// Takes a slot, or returns `None` if all slots are in use.
+    pub fn try_acquire(semaphore: &Arc<Self>) -> Option<Permit> {
+        let mut available = semaphore.available.load(Ordering::SeqCst);
+        loop {
+            if available == 0 {
+                return None;
+            }
+            let prev =
+                semaphore
+                    .available
+                    .compare_and_swap(available, available - 1, Ordering::SeqCst);
+            if prev == available {
+                break;
+            }
+            available = prev;
+        }
+        Some(Permit {
+            semaphore: semaphore.clone(),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.semaphore.available.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Limits how many tasks run at the same time for each key, e.g. the downloads of each file.
+pub struct KeyedSemaphore<K: Eq + Hash> {
+    permits: usize,
+    acquired: Mutex<HashMap<K, usize>>,
+}
+
+/// A slot of the `KeyedSemaphore` that is released on drop.
+pub struct KeyedPermit<K: Eq + Hash> {
+    semaphore: Arc<KeyedSemaphore<K>>,
+    key: K,
+}
+
+impl<K: Clone + Eq + Hash> KeyedSemaphore<K> {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits,
+            acquired: Default::default(),
+        }
+    }
+
+    /// Takes a slot of `key`, or returns `None` if all slots of `key` are in use.
+    pub fn try_acquire(semaphore: &Arc<Self>, key: K) -> Option<KeyedPermit<K>> {
+        let mut acquired = semaphore.acquired.lock().unwrap();
+        let count = acquired.get(&key).cloned().unwrap_or(0);
+        if semaphore.permits <= count {
+            return None;
+        }
+        acquired.insert(key.clone(), count + 1);
+        Some(KeyedPermit {
+            semaphore: semaphore.clone(),
+            key,
+        })
+    }
+
+    /// Runs `f` unless a slot of `key` is in use, keeping the slots of `key` from being taken until
+    /// it returns.
+    pub fn unless_acquired<T, F: FnOnce() -> T>(&self, key: &K, f: F) -> Option<T> {
+        let acquired = self.acquired.lock().unwrap();
+        if acquired.contains_key(key) {
+            return None;
+        }
+        Some(f())
+    }
+}
+
+impl<K: Eq + Hash> Drop for KeyedPermit<K> {
+    fn drop(&mut self) {
+        let mut acquired = self.semaphore.acquired.lock().unwrap();
+        // forgets the keys without slots in use so that the map doesn't grow with every key.
+        let released = match acquired.get_mut(&self.key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if released {
+            acquired.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn semaphore_limits_permits() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let first = Semaphore::try_acquire(&semaphore);
+        let second = Semaphore::try_acquire(&semaphore);
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(Semaphore::try_acquire(&semaphore).is_none());
+
+        drop(first);
+        let third = Semaphore::try_acquire(&semaphore);
+        assert!(third.is_some());
+        assert!(Semaphore::try_acquire(&semaphore).is_none());
+        drop(second);
+        drop(third);
+        assert_eq!(semaphore.available.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn semaphore_across_threads() {
+        let semaphore = Arc::new(Semaphore::new(4));
+        let threads = (0..8)
+            .map(|_| {
+                let semaphore = semaphore.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let _permit = Semaphore::try_acquire(&semaphore);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(semaphore.available.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn keyed_semaphore_limits_each_key() {
+        let semaphore = Arc::new(KeyedSemaphore::new(2));
+        let a1 = KeyedSemaphore::try_acquire(&semaphore, "a");
+        let a2 = KeyedSemaphore::try_acquire(&semaphore, "a");
+        assert!(a1.is_some());
+        assert!(a2.is_some());
+        assert!(KeyedSemaphore::try_acquire(&semaphore, "a").is_none());
+        assert!(KeyedSemaphore::try_acquire(&semaphore, "b").is_some());
+
+        drop(a1);
+        assert!(KeyedSemaphore::try_acquire(&semaphore, "a").is_some());
+    }
+
+    #[test]
+    fn keyed_semaphore_forgets_released_keys() {
+        let semaphore = Arc::new(KeyedSemaphore::new(1));
+        let a = KeyedSemaphore::try_acquire(&semaphore, "a");
+        let b = KeyedSemaphore::try_acquire(&semaphore, "b");
+        assert_eq!(semaphore.acquired.lock().unwrap().len(), 2);
+        drop(a);
+        drop(b);
+        assert!(semaphore.acquired.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keyed_semaphore_unless_acquired() {
+        let semaphore = Arc::new(KeyedSemaphore::new(2));
+        let a = KeyedSemaphore::try_acquire(&semaphore, "a");
+        assert_eq!(semaphore.unless_acquired(&"a", || 1), None);
+        assert_eq!(semaphore.unless_acquired(&"b", || 1), Some(1));
+        drop(a);
+        assert_eq!(semaphore.unless_acquired(&"a", || 1), Some(1));
+    }
+
+    #[test]
+    fn keyed_semaphore_zero_permits() {
+        let semaphore = Arc::new(KeyedSemaphore::new(0));
+        assert!(KeyedSemaphore::try_acquire(&semaphore, "a").is_none());
+        assert!(semaphore.acquired.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use failure::format_err;
+
+use crate::transfer_rs::prelude::*;
+
+/// Parses a byte size such as `1048576`, `500M` or `2GiB`. The suffixes are powers of 1024.
+pub fn parse_size(value: &str) -> Fallible<u64> {
+    let value = value.trim();
+    let index = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| value.len());
+    let (number, suffix) = value.split_at(index);
+    let number = number
+        .parse::<u64>()
+        .map_err(|e| format_err!("invalid size: {}, {}", value, e))?;
+    let shift = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return Err(format_err!("unknown size suffix: {}", value)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format_err!("too large size: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_bytes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1048576").unwrap(), 1_048_576);
+        assert_eq!(parse_size(" 42 ").unwrap(), 42);
+        assert_eq!(parse_size("42b").unwrap(), 42);
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(parse_size("1k").unwrap(), 1024);
+        assert_eq!(parse_size("500M").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("3 MB").unwrap(), 3 * 1024 * 1024);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("1P").is_err());
+        assert!(parse_size("18446744073709551615").is_ok());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert!(parse_size("16777216T").is_err());
+    }
+}
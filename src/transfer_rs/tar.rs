@@ -0,0 +1,227 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// The end of an archive, two zero blocks.
+pub const END: [u8; BLOCK_SIZE * 2] = [0; BLOCK_SIZE * 2];
+
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+
+/// Largest size of the 11 octal digits of the size field.
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+/// Returns the header blocks of a regular file at `path` in the archive, preceded by a PAX
+/// extended header when the path or the size doesn't fit in the ustar header.
+pub fn file_header(path: &str, size: u64, mtime: u64) -> Vec<u8> {
+    let mut records = String::new();
+    let (prefix, name) = match split_path(path) {
+        Some(data) => data,
+        None => {
+            records.push_str(&pax_record("path", path));
+            // readers without PAX support get a truncated name.
+            ("", truncate(path, NAME_LEN))
+        }
+    };
+    if MAX_USTAR_SIZE < size {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+
+    let mut ret = Vec::new();
+    if !records.is_empty() {
+        ret.extend_from_slice(&header_block(
+            "",
+            &truncate(&format!("PaxHeader/{}", name), NAME_LEN),
+            records.len() as u64,
+            mtime,
+            b'x',
+        ));
+        ret.extend_from_slice(records.as_bytes());
+        ret.resize(ret.len() + padding(records.len() as u64), 0);
+    }
+    ret.extend_from_slice(&header_block(
+        prefix,
+        name,
+        std::cmp::min(size, MAX_USTAR_SIZE),
+        mtime,
+        b'0',
+    ));
+    ret
+}
+
+/// Returns the number of the zero bytes following `size` bytes of content up to the next block.
+pub fn padding(size: u64) -> usize {
+    (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE
+}
+
+/// Splits `path` into the prefix and the name fields of the ustar header if it fits.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME_LEN {
+        return Some(("", path));
+    }
+    path.match_indices('/')
+        .map(|(index, _)| (&path[..index], &path[index + 1..]))
+        .find(|(prefix, name)| prefix.len() <= PREFIX_LEN && name.len() <= NAME_LEN)
+}
+
+/// Returns the longest prefix of `value` in `len` bytes that ends at a character boundary.
+fn truncate(value: &str, len: usize) -> &str {
+    let mut end = std::cmp::min(value.len(), len);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Returns "<length> <key>=<value>\n" where the length counts the whole record.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while (rest + len.to_string().len()) != len {
+        len = rest + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value)
+}
+
+fn header_block(prefix: &str, name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK_SIZE] {
+    let mut block = [0; BLOCK_SIZE];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut block[100..108], 0o644);
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // summed with the checksum field filled with spaces.
+    block[148..156].copy_from_slice(b"        ");
+    let checksum = block.iter().map(|&data| u64::from(data)).sum::<u64>();
+    write_octal(&mut block[148..155], checksum);
+    block
+}
+
+/// Writes `value` in octal digits padded with zeros, followed by a NUL, filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+    let digits = &digits.as_bytes()[digits.len() - (field.len() - 1)..];
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(block: &[u8], range: std::ops::Range<usize>) -> &str {
+        let data = &block[range];
+        let end = data
+            .iter()
+            .position(|&data| data == 0)
+            .unwrap_or_else(|| data.len());
+        std::str::from_utf8(&data[..end]).unwrap()
+    }
+
+    fn checksum_is_valid(block: &[u8]) -> bool {
+        let expected = u64::from_str_radix(field(block, 148..155), 8).unwrap();
+        let sum = block
+            .iter()
+            .enumerate()
+            .map(|(index, &data)| match index {
+                148..=155 => u64::from(b' '),
+                _ => u64::from(data),
+            })
+            .sum::<u64>();
+        sum == expected
+    }
+
+    #[test]
+    fn short_path() {
+        let header = file_header("id/a.txt", 5, 1_500_000_000);
+        assert_eq!(header.len(), BLOCK_SIZE);
+        assert_eq!(field(&header, 0..100), "id/a.txt");
+        assert_eq!(field(&header, 100..108), "0000644");
+        assert_eq!(field(&header, 124..136), "00000000005");
+        assert_eq!(field(&header, 136..148), "13132027400");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..265], b"ustar\000");
+        assert_eq!(field(&header, 345..500), "");
+        assert!(checksum_is_valid(&header));
+    }
+
+    #[test]
+    fn split_long_path() {
+        let name = "a".repeat(100);
+        let path = format!("{}/{}", "d".repeat(150), name);
+        let header = file_header(&path, 0, 0);
+        assert_eq!(header.len(), BLOCK_SIZE);
+        assert_eq!(field(&header, 0..100), name);
+        assert_eq!(field(&header, 345..500), "d".repeat(150));
+        assert!(checksum_is_valid(&header));
+    }
+
+    #[test]
+    fn pax_path() {
+        let path = format!("id/{}", "あ".repeat(85));
+        let header = file_header(&path, 5, 0);
+        assert_eq!(header.len(), BLOCK_SIZE * 3);
+        assert_eq!(header[156], b'x');
+        assert!(checksum_is_valid(&header[..BLOCK_SIZE]));
+        let len = u64::from_str_radix(field(&header, 124..136), 8).unwrap() as usize;
+        let records = std::str::from_utf8(&header[BLOCK_SIZE..BLOCK_SIZE + len]).unwrap();
+        assert_eq!(records, format!("{} path={}\n", len, path));
+        assert!(header[BLOCK_SIZE + len..BLOCK_SIZE * 2]
+            .iter()
+            .all(|&data| data == 0));
+
+        let header = &header[BLOCK_SIZE * 2..];
+        assert_eq!(header[156], b'0');
+        assert!(path.starts_with(field(header, 0..100)));
+        assert!(checksum_is_valid(header));
+    }
+
+    #[test]
+    fn pax_size() {
+        let header = file_header("a", MAX_USTAR_SIZE + 1, 0);
+        assert_eq!(header.len(), BLOCK_SIZE * 3);
+        assert!(std::str::from_utf8(&header[BLOCK_SIZE..BLOCK_SIZE + 20])
+            .unwrap()
+            .starts_with("19 size=8589934592\n"));
+    }
+
+    #[test]
+    fn record_lengths() {
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+        let record = pax_record("path", &"a".repeat(90));
+        assert_eq!(record.len(), 99);
+        assert!(record.starts_with("99 "));
+        // the length grows a digit with itself.
+        let record = pax_record("path", &"a".repeat(91));
+        assert_eq!(record.len(), 101);
+        assert!(record.starts_with("101 "));
+    }
+
+    #[test]
+    fn paddings() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), 511);
+        assert_eq!(padding(512), 0);
+        assert_eq!(padding(513), 511);
+    }
+}
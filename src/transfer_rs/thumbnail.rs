@@ -0,0 +1,197 @@
+/*
+ * Copyright 2019 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::Cursor;
+
+use image::{ImageDecoder, ImageFormat, ImageOutputFormat};
+
+use crate::transfer_rs::sha256::Sha256;
+
+/// Images larger than this are not decoded since the pixels are held in memory.
+const MAX_SOURCE_PIXELS: u64 = 50_000_000;
+
+const CACHE_SUFFIX: &str = ".thumb";
+
+/// Parses `<width>x<height>` of a thumbnail request, each side between 1 and `max_side`.
+pub fn parse_dimensions(value: &str, max_side: u32) -> Option<(u32, u32)> {
+    let index = value.find('x')?;
+    let width = value[..index].parse::<u32>().ok()?;
+    let height = value[index + 1..].parse::<u32>().ok()?;
+    if width == 0 || height == 0 || max_side < width || max_side < height {
+        return None;
+    }
+    Some((width, height))
+}
+
+/// Returns whether thumbnails can be generated for `content_type`.
+pub fn is_supported(content_type: &str) -> bool {
+    match content_type {
+        "image/gif" | "image/jpeg" | "image/png" => true,
+        _ => false,
+    }
+}
+
+/// Scales the JPEG, PNG or GIF image down to fit in `width`x`height` keeping the aspect ratio.
+///
+/// The thumbnail is a JPEG for JPEG images and a PNG otherwise. Returns `None` if `data` isn't a
+/// supported image or is too large to decode. Smaller images are re-encoded as is.
+pub fn generate(data: &[u8], width: u32, height: u32) -> Option<Vec<u8>> {
+    let format = image::guess_format(data).ok()?;
+    let (source_width, source_height) = dimensions(data, format)?;
+    if MAX_SOURCE_PIXELS < source_width.saturating_mul(source_height) {
+        return None;
+    }
+    let mut image = image::load_from_memory_with_format(data, format).ok()?;
+    if u64::from(width) < source_width || u64::from(height) < source_height {
+        image = image.thumbnail(width, height);
+    }
+    let output_format = match format {
+        ImageFormat::JPEG => ImageOutputFormat::JPEG(85),
+        _ => ImageOutputFormat::PNG,
+    };
+    let mut ret = Vec::new();
+    image.write_to(&mut ret, output_format).ok()?;
+    Some(ret)
+}
+
+/// Returns the content-type of a thumbnail made by `generate`.
+pub fn content_type(thumbnail: &[u8]) -> &'static str {
+    if thumbnail.starts_with(&[0xff, 0xd8]) {
+        "image/jpeg"
+    } else {
+        "image/png"
+    }
+}
+
+/// Reads the dimensions from the header without decoding the pixels.
+fn dimensions(data: &[u8], format: ImageFormat) -> Option<(u64, u64)> {
+    let data = Cursor::new(data);
+    match format {
+        ImageFormat::GIF => Some(image::gif::Decoder::new(data).ok()?.dimensions()),
+        ImageFormat::JPEG => Some(image::jpeg::JPEGDecoder::new(data).ok()?.dimensions()),
+        ImageFormat::PNG => Some(image::png::PNGDecoder::new(data).ok()?.dimensions()),
+        _ => None,
+    }
+}
+
+/// Returns the name the thumbnail of `filename` is cached as.
+///
+/// The name is hidden and has a fixed length so that it fits in the filesystem limit whatever the length
+/// of `filename` is.
+pub fn cache_name(filename: &str, width: u32, height: u32) -> String {
+    format!(
+        "{}{}x{}{}",
+        cache_prefix(filename),
+        width,
+        height,
+        CACHE_SUFFIX
+    )
+}
+
+/// Returns whether `name` is a cached thumbnail of `filename`.
+pub fn is_cache_of(name: &str, filename: &str) -> bool {
+    name.starts_with(&cache_prefix(filename)) && name.ends_with(CACHE_SUFFIX)
+}
+
+fn cache_prefix(filename: &str) -> String {
+    format!(".{}.", &Sha256::digest(filename.as_bytes())[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView};
+
+    use super::*;
+
+    fn encode(image: &DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut ret = Vec::new();
+        image.write_to(&mut ret, format).unwrap();
+        ret
+    }
+
+    #[test]
+    fn parse_dimensions_accepts_sizes_up_to_the_cap() {
+        assert_eq!(parse_dimensions("64x32", 1024), Some((64, 32)));
+        assert_eq!(parse_dimensions("1024x1", 1024), Some((1024, 1)));
+    }
+
+    #[test]
+    fn parse_dimensions_rejects_invalid_sizes() {
+        for value in &[
+            "",
+            "64",
+            "x64",
+            "64x",
+            "0x64",
+            "64x0",
+            "-1x64",
+            "64x64x64",
+            "1025x64",
+            "64x1025",
+            "99999999999x1",
+        ] {
+            assert_eq!(parse_dimensions(value, 1024), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn generate_png_keeps_aspect_ratio() {
+        let source = encode(&DynamicImage::new_rgb8(200, 100), ImageOutputFormat::PNG);
+        let data = generate(&source, 50, 50).unwrap();
+        assert_eq!(content_type(&data), "image/png");
+        let thumbnail = image::load_from_memory(&data).unwrap();
+        assert_eq!(thumbnail.dimensions(), (50, 25));
+    }
+
+    #[test]
+    fn generate_jpeg_returns_jpeg() {
+        let source = encode(&DynamicImage::new_rgb8(64, 64), ImageOutputFormat::JPEG(90));
+        let data = generate(&source, 16, 16).unwrap();
+        assert_eq!(content_type(&data), "image/jpeg");
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::JPEG);
+    }
+
+    #[test]
+    fn generate_does_not_enlarge() {
+        let source = encode(&DynamicImage::new_rgb8(8, 4), ImageOutputFormat::PNG);
+        let data = generate(&source, 64, 64).unwrap();
+        let thumbnail = image::load_from_memory(&data).unwrap();
+        assert_eq!(thumbnail.dimensions(), (8, 4));
+    }
+
+    #[test]
+    fn generate_rejects_non_images() {
+        assert_eq!(generate(b"hello", 16, 16), None);
+        assert_eq!(generate(b"\x89PNG\r\n\x1a\nbroken", 16, 16), None);
+    }
+
+    #[test]
+    fn cache_name_has_fixed_length() {
+        let long = "a".repeat(255);
+        assert_eq!(
+            cache_name("a.png", 16, 16).len(),
+            cache_name(&long, 16, 16).len()
+        );
+    }
+
+    #[test]
+    fn is_cache_of_matches_only_its_file() {
+        let name = cache_name("a.png", 16, 16);
+        assert!(is_cache_of(&name, "a.png"));
+        assert!(!is_cache_of(&name, "b.png"));
+        assert!(!is_cache_of("a.png", "a.png"));
+    }
+}